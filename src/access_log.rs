@@ -0,0 +1,260 @@
+//! Structured access log, one json line per request.
+use crate::config::Config;
+use crate::models::PlexContext;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Written instead of tokens.
+const REDACTED: &str = "REDACTED";
+
+/// What the handlers of a request did, filled while the request runs.
+#[derive(Debug, Clone, Default)]
+struct Trail {
+    transforms: Vec<String>,
+    upstream_status: Option<u16>,
+    cache_hit: bool,
+}
+
+tokio::task_local! {
+    static TRAIL: Arc<Mutex<Trail>>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    /// unix time in milliseconds
+    pub timestamp: u64,
+    pub method: String,
+    pub path: String,
+    /// query with tokens redacted
+    pub query: Option<String>,
+    pub status: u16,
+    pub duration_ms: f64,
+    pub username: Option<String>,
+    pub product: Option<String>,
+    pub platform: Option<String>,
+    pub device_name: Option<String>,
+    pub client_identifier: Option<String>,
+    /// transforms applied to the response, in order
+    pub transforms: Vec<String>,
+    /// status of the last request to plex
+    pub upstream_status: Option<u16>,
+    /// response served from the response cache
+    pub cache_hit: bool,
+}
+
+impl AccessLogEntry {
+    pub fn new(
+        method: String,
+        path: String,
+        query: Option<&str>,
+        context: &PlexContext,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            method,
+            path,
+            query: query.map(redact_query),
+            status: 0,
+            duration_ms: 0.0,
+            username: context.username.clone(),
+            product: context.product.clone(),
+            platform: context.platform.as_ref().map(|p| p.to_string()),
+            device_name: context.device_name.clone(),
+            client_identifier: context.client_identifier.clone(),
+            transforms: vec![],
+            upstream_status: None,
+            cache_hit: false,
+        }
+    }
+}
+
+/// Replaces the values of token params, ex `X-Plex-Token`.
+pub fn redact_query(query: &str) -> String {
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.to_lowercase().contains("token") {
+            true => serializer.append_pair(&key, REDACTED),
+            false => serializer.append_pair(&key, &value),
+        };
+    }
+    serializer.finish()
+}
+
+pub fn enabled() -> bool {
+    WRITER.is_some()
+}
+
+/// Run a request, collecting what its handlers record for the access log.
+pub async fn scope<F: Future>(mut entry: AccessLogEntry, f: F) -> AccessLogEntry {
+    let trail = Arc::new(Mutex::new(Trail::default()));
+    TRAIL.scope(trail.clone(), f).await;
+    let trail = trail.lock().unwrap().clone();
+    entry.transforms = trail.transforms;
+    entry.upstream_status = trail.upstream_status;
+    entry.cache_hit = trail.cache_hit;
+    entry
+}
+
+fn with_trail(f: impl FnOnce(&mut Trail)) {
+    // outside of a request scope, ex background tasks, there is nothing to record
+    let _ = TRAIL.try_with(|trail| f(&mut trail.lock().unwrap()));
+}
+
+pub fn record_transform(name: &str) {
+    with_trail(|trail| trail.transforms.push(name.to_string()));
+}
+
+pub fn record_upstream(status: u16) {
+    with_trail(|trail| trail.upstream_status = Some(status));
+}
+
+pub fn record_cache_hit() {
+    with_trail(|trail| trail.cache_hit = true);
+}
+
+/// Queue the entry, it is written on a separate thread.
+pub fn write(entry: &AccessLogEntry) {
+    let Some(writer) = WRITER.as_ref() else {
+        return;
+    };
+    match serde_json::to_string(entry) {
+        Ok(line) => {
+            let _ = writer.lock().unwrap().send(line);
+        }
+        Err(error) => tracing::error!(error = %error, "Failed to serialize access log entry"),
+    }
+}
+
+/// Set up at startup from `access_log`, changes need a restart.
+static WRITER: Lazy<Option<Mutex<Sender<String>>>> = Lazy::new(|| {
+    let config = Config::current();
+    let target = config.access_log.clone()?;
+    let mut output: Box<dyn Write + Send> = match target.as_str() {
+        "-" => Box::new(std::io::stdout()),
+        path => match RotatingFile::open(
+            PathBuf::from(path),
+            config.access_log_max_size * 1024 * 1024,
+            config.access_log_max_files,
+        ) {
+            Ok(file) => Box::new(file),
+            Err(error) => {
+                tracing::error!(path, error = %error, "Failed to open access log");
+                return None;
+            }
+        },
+    };
+
+    let (sender, receiver) = mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        for line in receiver {
+            // one write per line, so rotating never splits it
+            let line = format!("{}\n", line);
+            if let Err(error) = output.write_all(line.as_bytes()).and_then(|_| output.flush()) {
+                tracing::error!(error = %error, "Failed to write access log");
+            }
+        }
+    });
+    Some(Mutex::new(sender))
+});
+
+/// File that is moved to `<path>.1` when it grows over `max_size`,
+/// older ones shift up to `<path>.<max_files>`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: u32) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_query() {
+        assert_eq!(
+            redact_query("X-Plex-Token=secret&X-Plex-Product=Plex%20Web"),
+            "X-Plex-Token=REDACTED&X-Plex-Product=Plex+Web"
+        );
+        assert_eq!(redact_query("count=10"), "count=10");
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("replex-access-log-{}", std::process::id()));
+        let path = dir.join("access.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for _ in 0..4 {
+            file.write_all(b"12345678\n").unwrap();
+        }
+        assert!(path.exists());
+        assert!(file.rotated(1).exists());
+        assert!(file.rotated(2).exists());
+        assert!(!file.rotated(3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
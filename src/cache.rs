@@ -13,13 +13,16 @@ use salvo::http::StatusCode;
 use salvo::Handler;
 use salvo::{cache::CacheIssuer, Depot, Request};
 use serde::de::DeserializeOwned;
-use serde::{Serialize};
+use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
 use std::error::Error as StdError;
 use std::hash::Hash;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::{
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 // use bincode::{config, Decode, Encode};
 
@@ -40,10 +43,25 @@ pub(crate) static GLOBAL_CACHE: Lazy<CacheManager> = Lazy::new(|| {
         MokaCache::builder()
             .max_capacity(100000)
             .expire_after(expiry)
+            .support_invalidation_closures()
             .build(),
     )
 });
 
+/// Second tier behind the memory caches, so entries survive restarts.
+/// Enabled by setting `cache_dir`, set up at startup so changes need a restart.
+pub(crate) static DISK_CACHE: Lazy<Option<DiskCache>> = Lazy::new(|| {
+    let config = Config::current();
+    let dir = config.cache_dir.clone()?;
+    match DiskCache::new(PathBuf::from(&dir), config.cache_dir_max_size * 1024 * 1024) {
+        Ok(cache) => Some(cache),
+        Err(error) => {
+            tracing::error!(dir = %dir, error = %error, "Failed to open disk cache, continuing without");
+            None
+        }
+    }
+});
+
 /// An enum to represent the expiration of a value.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Expiration {
@@ -55,17 +73,20 @@ pub enum Expiration {
     Month,
     /// Expires after a day
     Day,
+    /// Expires at the unix time in seconds, for entries loaded from disk
+    At(u64),
 }
 
 impl Expiration {
     /// Returns the duration of this expiration.
     pub fn as_duration(&self) -> Option<Duration> {
-        let config: Config = Config::figment().extract().unwrap();
+        let config = Config::current();
         match self {
             Expiration::Never => None,
             Expiration::Global => Some(Duration::from_secs(config.cache_ttl)),
             Expiration::Month => Some(Duration::from_secs(30 * 24 * 60 * 60)),
             Expiration::Day => Some(Duration::from_secs(60 * 60 * 24)),
+            Expiration::At(at) => Some(Duration::from_secs(at.saturating_sub(unix_now()))),
         }
     }
 }
@@ -91,12 +112,94 @@ impl Expiry<CacheKey, (Expiration, Arc<Vec<u8>>)> for CacheExpiry {
     }
 }
 
+/// Hit and miss counters of a cache.
+#[derive(Debug, Default)]
+pub struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheCounters {
+    pub const fn new() -> Self {
+        Self {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, hit: bool) {
+        match hit {
+            true => self.hit(),
+            false => self.miss(),
+        }
+    }
+
+    /// Statistics of the memory cache with these counters.
+    pub async fn stats<K, V>(&self, cache: &MokaCache<K, V>) -> CacheStats
+    where
+        K: Hash + Eq + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+    {
+        // counts are updated lazily
+        cache.run_pending_tasks().await;
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        CacheStats {
+            entries: cache.entry_count(),
+            weighted_size: cache.weighted_size(),
+            hits,
+            misses,
+            hit_ratio: match hits + misses {
+                0 => 0.0,
+                total => hits as f64 / total as f64,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub weighted_size: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: f64,
+}
+
+/// Whether the key matches the pattern. Patterns match the start of
+/// the key and `*` matches any characters, ex `*:hero_art`.
+pub fn key_matches(pattern: &str, key: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(first) = parts.next() else {
+        return true;
+    };
+    let Some(mut rest) = key.strip_prefix(first) else {
+        return false;
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
 #[derive(Clone)]
 pub struct CacheManager {
     /// The instance of `moka::future::Cache`
     // pub store: Arc<Cache<String, Arc<Vec<u8>>>>,
     // pub inner: S,
     pub inner: GlobalCacheType,
+    pub counters: Arc<CacheCounters>,
 }
 
 impl CacheManager {
@@ -105,26 +208,62 @@ impl CacheManager {
     pub fn new(cache: GlobalCacheType) -> Self {
         Self {
             inner: cache, // store: Arc::new(store),
+            counters: Arc::new(CacheCounters::new()),
         }
     }
-    /// Clears out the entire cache.
+
+    pub async fn stats(&self) -> CacheStats {
+        self.counters.stats(&self.inner).await
+    }
+    /// Clears out the entire cache, the disk tier included.
     pub async fn clear(&self) -> anyhow::Result<()> {
         self.inner.invalidate_all();
+        if let Some(disk) = DISK_CACHE.as_ref() {
+            disk.invalidate_prefixes(vec![String::new()]).await?;
+        }
         //self.inner.sync();
         Ok(())
     }
 
+    /// Invalidate all entries with a key matching one of the prefixes, see `key_matches`.
+    pub fn invalidate_prefixes(&self, prefixes: Vec<String>) -> anyhow::Result<()> {
+        invalidate_disk_prefixes(prefixes.clone());
+        self.inner.invalidate_entries_if(move |key, _| {
+            prefixes.iter().any(|p| key_matches(p, key))
+        })?;
+        Ok(())
+    }
+
     pub async fn get<T>(&self, cache_key: &str) -> Option<T>
     where
         T: DeserializeOwned,
     {
-        match self.inner.get(cache_key).await {
-            Some(d) => {
-                let result: T = bincode::deserialize(&d.1).unwrap();
-                Some(result)
-            }
-            None => None,
-        }
+        let result = match self.inner.get(cache_key).await {
+            // entries of an older layout read as a miss, and are replaced
+            Some(d) => match bincode::deserialize(&d.1) {
+                Ok(result) => Some(result),
+                Err(error) => {
+                    tracing::warn!(key = %cache_key, error = %error, "Failed to read cache entry");
+                    self.inner.invalidate(cache_key).await;
+                    None
+                }
+            },
+            None => self.get_from_disk(cache_key).await,
+        };
+        self.counters.record(result.is_some());
+        result
+    }
+
+    async fn get_from_disk<T>(&self, cache_key: &str) -> Option<T>
+    where
+        T: DeserializeOwned,
+    {
+        let (expires, data) = DISK_CACHE.as_ref()?.get(cache_key).await?;
+        let result: T = bincode::deserialize(&data).ok()?;
+        self.inner
+            .insert(cache_key.to_string(), (expires, Arc::new(data)))
+            .await;
+        Some(result)
     }
 
     pub async fn insert<V>(
@@ -136,7 +275,13 @@ impl CacheManager {
     where
         V: Serialize,
     {
-        let value = (expires, Arc::new(bincode::serialize(&v)?));
+        let data = bincode::serialize(&v)?;
+        if let Some(disk) = DISK_CACHE.as_ref() {
+            if let Err(error) = disk.insert(cache_key.clone(), data.clone(), expires).await {
+                tracing::warn!(key = %cache_key, error = %error, "Failed to write disk cache");
+            }
+        }
+        let value = (expires, Arc::new(data));
         self.inner.insert(cache_key, value).await;
         //self.inner.sync();
         Ok(())
@@ -144,22 +289,236 @@ impl CacheManager {
 
     pub async fn delete(&self, cache_key: &str) -> anyhow::Result<()> {
         self.inner.invalidate(cache_key).await;
+        if let Some(disk) = DISK_CACHE.as_ref() {
+            disk.delete(cache_key).await?;
+        }
         //self.inner.sync();
         Ok(())
     }
 }
 
+/// Invalidate disk entries in the background, it reads every file.
+pub(crate) fn invalidate_disk_prefixes(prefixes: Vec<String>) {
+    if DISK_CACHE.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Some(disk) = DISK_CACHE.as_ref() {
+            if let Err(error) = disk.invalidate_prefixes(prefixes).await {
+                tracing::error!(error = %error, "Failed to invalidate disk cache");
+            }
+        }
+    });
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    /// first, so the key can be read without the value
+    key: String,
+    /// unix time in seconds, None never expires
+    expires_at: Option<u64>,
+    value: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct DiskEntryKey {
+    key: String,
+}
+
+/// A file per key in a directory. When the directory grows over `max_size`
+/// the least recently written files are removed.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    size: AtomicU64,
+    pruning: AtomicBool,
+    writes: AtomicU64,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, max_size: u64) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let size = entry_files(&dir)?.iter().map(|(_, meta)| meta.len()).sum();
+        Ok(Self {
+            dir,
+            max_size,
+            size: AtomicU64::new(size),
+            pruning: AtomicBool::new(false),
+            writes: AtomicU64::new(0),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}", fnv1a(key)))
+    }
+
+    /// The value and when it expires, None when missing or expired.
+    pub async fn get(&self, key: &str) -> Option<(Expiration, Vec<u8>)> {
+        let path = self.path(key);
+        let data = tokio::fs::read(&path).await.ok()?;
+        let entry: DiskEntry = match bincode::deserialize(&data) {
+            Ok(entry) => entry,
+            Err(_) => {
+                self.remove(&path).await;
+                return None;
+            }
+        };
+        // other key with the same hash
+        if entry.key != key {
+            return None;
+        }
+        match entry.expires_at {
+            Some(at) if at <= unix_now() => {
+                self.remove(&path).await;
+                None
+            }
+            Some(at) => Some((Expiration::At(at), entry.value)),
+            None => Some((Expiration::Never, entry.value)),
+        }
+    }
+
+    pub async fn insert(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires: Expiration,
+    ) -> anyhow::Result<()> {
+        let path = self.path(&key);
+        let expires_at = expires.as_duration().map(|d| unix_now() + d.as_secs());
+        let data = bincode::serialize(&DiskEntry {
+            key,
+            expires_at,
+            value,
+        })?;
+
+        // write a temp file and rename, so readers never see a partial file
+        let tmp = path.with_extension(format!(
+            "tmp{}",
+            self.writes.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&tmp, &data).await?;
+        let previous = tokio::fs::metadata(&path).await.map(|m| m.len()).ok();
+        tokio::fs::rename(&tmp, &path).await?;
+
+        self.size.fetch_add(data.len() as u64, Ordering::Relaxed);
+        if let Some(previous) = previous {
+            self.shrink(previous);
+        }
+        if self.size.load(Ordering::Relaxed) > self.max_size {
+            self.prune().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.remove(&self.path(key)).await;
+        Ok(())
+    }
+
+    /// Remove all entries with a key matching one of the prefixes, see `key_matches`.
+    pub async fn invalidate_prefixes(&self, prefixes: Vec<String>) -> anyhow::Result<()> {
+        for (path, _) in entry_files(&self.dir)? {
+            let key = std::fs::File::open(&path)
+                .ok()
+                .and_then(|file| {
+                    bincode::deserialize_from::<_, DiskEntryKey>(BufReader::new(file)).ok()
+                })
+                .map(|entry| entry.key);
+            // unreadable files are removed as well
+            if key.map_or(true, |key| prefixes.iter().any(|p| key_matches(p, &key))) {
+                self.remove(&path).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the oldest files until the cache is at 90% of its size.
+    async fn prune(&self) -> anyhow::Result<()> {
+        if self.pruning.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let result = async {
+            let mut files = entry_files(&self.dir)?;
+            files.sort_by_key(|(_, meta)| meta.modified().ok());
+            let target = self.max_size / 10 * 9;
+            for (path, _) in files {
+                if self.size.load(Ordering::Relaxed) <= target {
+                    break;
+                }
+                self.remove(&path).await;
+            }
+            Ok(())
+        }
+        .await;
+        self.pruning.store(false, Ordering::Relaxed);
+        result
+    }
+
+    async fn remove(&self, path: &Path) {
+        if let Ok(meta) = tokio::fs::metadata(path).await {
+            if tokio::fs::remove_file(path).await.is_ok() {
+                self.shrink(meta.len());
+            }
+        }
+    }
+
+    fn shrink(&self, len: u64) {
+        let _ = self
+            .size
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |size| {
+                Some(size.saturating_sub(len))
+            });
+    }
+}
+
+/// Cache files with their metadata, skips temp files.
+fn entry_files(dir: &Path) -> std::io::Result<Vec<(PathBuf, std::fs::Metadata)>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_some() {
+            continue;
+        }
+        if let Ok(meta) = entry.metadata() {
+            if meta.is_file() {
+                files.push((path, meta));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Stable across builds, unlike `DefaultHasher`, so files survive upgrades.
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 pub struct RequestIssuer {
     use_scheme: bool,
     use_authority: bool,
     use_local_addr: bool,
     use_path: bool,
     path_strip_last_segment: bool,
-    // TODO: Also make this like headers. So that we can dump unneeded query variables like X-Plex-Client-Identifier and have cross device caching
     use_query: bool,
+    /// query params left out of the key, compared case insensitive
+    skip_query: Vec<String>,
     use_method: bool,
     use_mime: bool,
     use_headers: Vec<http::HeaderName>,
+    /// add the matching config profiles, they can change the response
+    use_profiles: bool,
+    /// add the plex account of the token, instead of the token of the device
+    use_user: bool,
 }
 impl Default for RequestIssuer {
     fn default() -> Self {
@@ -176,9 +535,12 @@ impl RequestIssuer {
             use_path: true,
             path_strip_last_segment: false,
             use_query: true,
+            skip_query: vec![],
             use_method: true,
             use_mime: false,
             use_headers: vec![],
+            use_profiles: false,
+            use_user: false,
         }
     }
 
@@ -190,15 +552,32 @@ impl RequestIssuer {
             use_path: true,
             path_strip_last_segment: false,
             use_query: true,
+            // differ per device or session but not the response,
+            // so devices of the same user and profile share entries
+            skip_query: [
+                "X-Plex-Token",
+                "X-Plex-Client-Identifier",
+                "X-Plex-Device-Name",
+                "X-Plex-Session-Identifier",
+                "X-Plex-Session-Id",
+                "X-Plex-Playback-Session-Id",
+                "X-Plex-Playback-Id",
+                "X-Plex-Http-Pipeline",
+            ]
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
             use_method: true,
             use_mime: false,
             use_headers: vec![
                 http::header::ACCEPT,
                 http::header::ACCEPT_ENCODING,
-                headers::PLEX_TOKEN,
                 headers::PLEX_LANGUAGE,
-                headers::PLEX_CLIENT_IDENTIFIER,
+                headers::PLEX_PRODUCT,
+                headers::PLEX_PLATFORM,
             ],
+            use_profiles: true,
+            use_user: true,
         }
     }
 
@@ -226,6 +605,21 @@ impl RequestIssuer {
         self.use_query = value;
         self
     }
+    /// Query params to leave out of the key.
+    pub fn skip_query(mut self, value: Vec<String>) -> Self {
+        self.skip_query = value;
+        self
+    }
+    /// Whether to use the matching config profiles when generate the key.
+    pub fn use_profiles(mut self, value: bool) -> Self {
+        self.use_profiles = value;
+        self
+    }
+    /// Whether to use the plex account of the token when generate the key.
+    pub fn use_user(mut self, value: bool) -> Self {
+        self.use_user = value;
+        self
+    }
     /// Whether to use request method when generate the key.
     pub fn use_method(mut self, value: bool) -> Self {
         self.use_method = value;
@@ -284,11 +678,21 @@ impl CacheIssuer for RequestIssuer {
                 key.push_str(req.uri().path());
             }
         }
-        // TODO: Clean up query. Not everything needs a cache change.
         if self.use_query {
             if let Some(query) = req.uri().query() {
+                // sorted, so the param order does not matter
+                let mut pairs: Vec<_> = url::form_urlencoded::parse(query.as_bytes())
+                    .filter(|(name, _)| {
+                        !self.skip_query.iter().any(|s| s.eq_ignore_ascii_case(name))
+                    })
+                    .collect();
+                pairs.sort();
                 key.push('?');
-                key.push_str(query);
+                key.push_str(
+                    &url::form_urlencoded::Serializer::new(String::new())
+                        .extend_pairs(pairs)
+                        .finish(),
+                );
             }
         }
         if self.use_method {
@@ -318,6 +722,26 @@ impl CacheIssuer for RequestIssuer {
                 }
             }
         }
+        if self.use_user {
+            let token = req
+                .header::<String>(headers::PLEX_TOKEN)
+                .or_else(|| req.query::<String>("X-Plex-Token"));
+            if let Some(token) = token {
+                key.push_str("|user::");
+                // keep users apart until the account is known
+                match crate::plex_client::known_user_id(&token).await {
+                    Some(id) => key.push_str(&id.to_string()),
+                    None => key.push_str(&crate::plex_client::hash_token(&token)),
+                }
+            }
+        }
+        if self.use_profiles {
+            let profiles = Config::current().matching_profiles(req);
+            if !profiles.is_empty() {
+                key.push_str("|profiles::");
+                key.push_str(&profiles.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(","));
+            }
+        }
         // dbg!(&key);
         Some(key)
     }
@@ -359,7 +783,10 @@ pub struct CachedEntry {
     pub req_headers: HeaderMap,
     pub req_uri: salvo::http::uri::Uri,
     pub req_method: salvo::http::method::Method,
-    pub req_local_addr: salvo::conn::addr::SocketAddr
+    pub req_local_addr: salvo::conn::addr::SocketAddr,
+    pub created_at: Instant,
+    /// set while a stale entry is refreshed, shared by the clones of the entry
+    pub revalidating: Arc<AtomicBool>,
 }
 impl CachedEntry {
     /// Create a new `CachedEntry`.
@@ -379,7 +806,9 @@ impl CachedEntry {
             req_headers,
             req_uri,
             req_method,
-            req_local_addr
+            req_local_addr,
+            created_at: Instant::now(),
+            revalidating: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -401,6 +830,116 @@ impl CachedEntry {
     }
 }
 
+/// Transformed responses, see `Cache`. Entries live for the fresh and stale time.
+pub(crate) static RESPONSE_CACHE: Lazy<ResponseStore> = Lazy::new(|| ResponseStore {
+    counters: Arc::new(CacheCounters::new()),
+    inner: MokaCache::builder()
+        .max_capacity(10000)
+        .expire_after(ResponseExpiry)
+        .build(),
+});
+
+/// Fresh and stale time of the current config, so reloads apply to new entries.
+fn response_lifetime() -> Duration {
+    let config = Config::current();
+    Duration::from_secs(config.cache_responses_ttl + config.cache_responses_stale)
+}
+
+struct ResponseExpiry;
+
+impl Expiry<String, CachedEntry> for ResponseExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        _value: &CachedEntry,
+        _current_time: Instant,
+    ) -> Option<Duration> {
+        Some(response_lifetime())
+    }
+}
+
+#[derive(Clone)]
+pub struct ResponseStore {
+    inner: MokaCache<String, CachedEntry>,
+    counters: Arc<CacheCounters>,
+}
+
+impl ResponseStore {
+    pub fn clear(&self) {
+        self.inner.invalidate_all();
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        self.counters.stats(&self.inner).await
+    }
+}
+
+#[async_trait]
+impl CacheStore for ResponseStore {
+    type Error = std::convert::Infallible;
+    type Key = String;
+
+    async fn load_entry<Q>(&self, key: &Q) -> Option<CachedEntry>
+    where
+        Self::Key: Borrow<Q>,
+        Q: Hash + Eq + Sync,
+    {
+        // entries made before a reload can outlive the new lifetime
+        let entry = self
+            .inner
+            .get(key)
+            .await
+            .filter(|entry| entry.created_at.elapsed() <= response_lifetime());
+        self.counters.record(entry.is_some());
+        entry
+    }
+
+    async fn save_entry(
+        &self,
+        key: Self::Key,
+        data: CachedEntry,
+    ) -> Result<(), Self::Error> {
+        self.inner.insert(key, data).await;
+        Ok(())
+    }
+}
+
+/// Depot key marking the request refreshing a stale entry, so it skips the cached entry.
+/// Only set by `REVALIDATE_SERVICE`, clients cannot bypass the cache.
+const REVALIDATING: &str = "replex::cache::revalidating";
+
+#[salvo::handler]
+async fn mark_revalidating(depot: &mut Depot) {
+    depot.insert(REVALIDATING, true);
+}
+
+/// Runs the refresh requests through the routes, like a client request.
+static REVALIDATE_SERVICE: Lazy<salvo::Service> = Lazy::new(|| {
+    salvo::Service::new(crate::routes::route()).hoop(mark_revalidating)
+});
+
+/// Replay the request of a stale entry in the background. The cache
+/// handler of the route stores the new response.
+fn revalidate(entry: &CachedEntry) {
+    if entry.revalidating.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let mut req = Request::new();
+    *req.uri_mut() = entry.req_uri.clone();
+    *req.method_mut() = entry.req_method.clone();
+    *req.headers_mut() = entry.req_headers.clone();
+    *req.local_addr_mut() = entry.req_local_addr.clone();
+    let revalidating = entry.revalidating.clone();
+    tokio::spawn(async move {
+        let res = REVALIDATE_SERVICE.handle(req).await;
+        if !res.status_code.map_or(true, |s| s.is_success()) {
+            tracing::warn!(status = ?res.status_code, "Failed to refresh cached response");
+            // allow another try on the next request
+            revalidating.store(false, Ordering::Relaxed);
+        }
+    });
+}
+
 #[non_exhaustive]
 pub struct Cache<S, I> {
     /// Cache store.
@@ -409,6 +948,8 @@ pub struct Cache<S, I> {
     pub issuer: I,
     /// Skipper.
     pub skipper: Box<dyn Skipper>,
+    /// Entries older than this are served stale while refreshed in the background.
+    pub stale_after: Option<fn() -> Duration>,
 }
 
 impl<S, I> Cache<S, I> {
@@ -420,8 +961,16 @@ impl<S, I> Cache<S, I> {
             store,
             issuer,
             skipper: Box::new(skipper),
+            stale_after: None,
         }
     }
+    /// Serve entries older than `fresh` while refreshing them in the background.
+    /// Read on every request, so it can follow config reloads.
+    #[inline]
+    pub fn stale_while_revalidate(mut self, fresh: fn() -> Duration) -> Self {
+        self.stale_after = Some(fresh);
+        self
+    }
     /// Sets skipper and returns new `Cache`.
     #[inline]
     pub fn skipper(mut self, skipper: impl Skipper) -> Self {
@@ -447,6 +996,7 @@ where
         if self.skipper.skipped(req, depot) {
             return;
         }
+        let revalidating = depot.get::<bool>(REVALIDATING).is_ok_and(|v| *v);
         let key = match self.issuer.issue(req, depot).await {
             Some(key) => key,
             None => {
@@ -460,14 +1010,26 @@ where
         let req_method = req.method().clone();
         let req_local_addr = req.local_addr().clone();
 
-        let cache = match self.store.load_entry(&key).await {
+        let cached = match revalidating {
+            true => None,
+            false => self.store.load_entry(&key).await,
+        };
+        let cache = match cached {
             Some(cache) => { 
                 tracing::debug!("returning response from cache");
+                crate::access_log::record_cache_hit();
+                if self
+                    .stale_after
+                    .is_some_and(|fresh| cache.created_at.elapsed() > fresh())
+                {
+                    revalidate(&cache);
+                }
                 cache
             },
             None => {
                 ctrl.call_next(req, depot, res).await;
-                if !res.body.is_stream() && !res.body.is_error() {
+                let success = res.status_code.map_or(true, |s| s.is_success());
+                if success && !res.body.is_stream() && !res.body.is_error() {
                     let headers = res.headers().clone();
                     let body = TryInto::<CachedBody>::try_into(&res.body);
                     match body {
@@ -501,6 +1063,7 @@ where
             req_uri,
             req_method,
             req_local_addr,
+            ..
         } = cache;
         if let Some(status) = status {
             res.status_code(status);
@@ -510,3 +1073,90 @@ where
         ctrl.skip_rest();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_matches() {
+        assert!(key_matches("collection:", "collection:1:token"));
+        assert!(!key_matches("collection:", "collectiontotalunwatched:1:token"));
+        assert!(key_matches("*:hero_art", "abc:hero_art"));
+        assert!(!key_matches("*:hero_art", "collection:1:token"));
+        assert!(key_matches("", "anything"));
+    }
+
+    #[tokio::test]
+    async fn test_request_issuer_plex_defaults() {
+        use salvo::test::TestClient;
+
+        use crate::plex_client::{hash_token, USER_IDS};
+
+        // devices of a user have their own token
+        USER_IDS.insert(hash_token("a"), Some(1)).await;
+        USER_IDS.insert(hash_token("a-tv"), Some(1)).await;
+        USER_IDS.insert(hash_token("b"), Some(2)).await;
+        USER_IDS.insert(hash_token("unknown"), None).await;
+
+        let issuer = RequestIssuer::with_plex_defaults();
+        let depot = Depot::new();
+        let mut key = |url: &str| {
+            let mut req = TestClient::get(url).build();
+            let depot = &depot;
+            let issuer = &issuer;
+            async move { issuer.issue(&mut req, depot).await.unwrap() }
+        };
+
+        let phone = key("http://plex/hubs/promoted?count=12&X-Plex-Token=a&X-Plex-Client-Identifier=phone").await;
+        let tablet = key("http://plex/hubs/promoted?X-Plex-Client-Identifier=tablet&X-Plex-Token=a&count=12").await;
+        let other_user = key("http://plex/hubs/promoted?count=12&X-Plex-Token=b&X-Plex-Client-Identifier=phone").await;
+        let tv = key("http://plex/hubs/promoted?count=12&X-Plex-Client-Identifier=tv&X-Plex-Token=a-tv").await;
+        let unknown = key("http://plex/hubs/promoted?count=12&X-Plex-Token=unknown").await;
+        assert_eq!(phone, tablet);
+        assert_eq!(phone, tv);
+        assert_ne!(phone, other_user);
+        assert_ne!(phone, unknown);
+        assert!(!tv.contains("a-tv"));
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("replex-disk-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = DiskCache::new(dir.clone(), 1024 * 1024).unwrap();
+
+        cache
+            .insert("collection:1:token".to_string(), vec![1, 2], Expiration::Never)
+            .await
+            .unwrap();
+        cache
+            .insert("uuid:hero_art".to_string(), vec![3], Expiration::Month)
+            .await
+            .unwrap();
+        cache
+            .insert("expired".to_string(), vec![4], Expiration::At(1))
+            .await
+            .unwrap();
+
+        // reopening reads the files written before
+        let cache = DiskCache::new(dir.clone(), 1024 * 1024).unwrap();
+        assert_eq!(
+            cache.get("collection:1:token").await,
+            Some((Expiration::Never, vec![1, 2]))
+        );
+        assert!(matches!(
+            cache.get("uuid:hero_art").await,
+            Some((Expiration::At(_), _))
+        ));
+        assert_eq!(cache.get("expired").await, None);
+
+        cache
+            .invalidate_prefixes(vec!["collection:".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(cache.get("collection:1:token").await, None);
+        assert!(cache.get("uuid:hero_art").await.is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,706 @@
+//! Typed model of what a client says it can play, from the `X-Plex-Client-Capabilities`
+//! header and the `X-Plex-Client-Profile-Extra` param, that can be rewritten and sent on to plex.
+//!
+//! Capabilities look like `protocols=hls;videoDecoders=h264{profile:high&resolution:1080};audioDecoders=aac,ac3`,
+//! the profile extra like `add-transcode-target(type=videoProfile&videoCodec=h264&audioCodec=aac)+add-limitation(...)`.
+use crate::config::{Config, ProfileMatch};
+use crate::models::PlexContext;
+use crate::utils::replace_query;
+use itertools::Itertools;
+use salvo::http::HeaderValue;
+use salvo::Request;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+pub const CAPABILITIES: &str = "X-Plex-Client-Capabilities";
+pub const PROFILE_EXTRA: &str = "X-Plex-Client-Profile-Extra";
+
+/// Ordered `key=value` params, keys are compared case insensitive.
+type Params = Vec<(String, String)>;
+
+fn get<'a>(params: &'a Params, key: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v.as_str())
+}
+
+fn set(params: &mut Params, key: &str, value: String) {
+    match params.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+        Some((_, v)) => *v = value,
+        None => params.push((key.to_string(), value)),
+    }
+}
+
+/// Comma separated values of a param.
+fn list(params: &Params, key: &str) -> Vec<String> {
+    get(params, key)
+        .map(|v| {
+            v.split(',')
+                .map(|c| c.trim().to_lowercase())
+                .filter(|c| !c.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Adds `value` to the comma separated values of `key`, when the param is set.
+/// Returns whether the param has the value now.
+fn append(params: &mut Params, key: &str, value: &str) -> bool {
+    let mut values = list(params, key);
+    if get(params, key).is_none() {
+        return false;
+    }
+    if !values.iter().any(|v| v.eq_ignore_ascii_case(value)) {
+        values.push(value.to_lowercase());
+        set(params, key, values.join(","));
+    }
+    true
+}
+
+fn parse_params(value: &str, pair: char, separator: char) -> Params {
+    value
+        .split(separator)
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.split_once(pair) {
+            Some((k, v)) => (k.trim().to_string(), v.trim().to_string()),
+            None => (p.trim().to_string(), String::new()),
+        })
+        .collect()
+}
+
+fn format_params(params: &Params, pair: char, separator: char) -> String {
+    params
+        .iter()
+        .map(|(k, v)| format!("{}{}{}", k, pair, v))
+        .join(&separator.to_string())
+}
+
+/// A decoder of the capabilities, ex `h264{profile:high&resolution:1080&level:51}`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Decoder {
+    pub codec: String,
+    pub params: Params,
+}
+
+impl Decoder {
+    pub fn new(codec: &str) -> Self {
+        Self {
+            codec: codec.to_lowercase(),
+            params: vec![],
+        }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&str> {
+        get(&self.params, key)
+    }
+
+    fn parse(value: &str) -> Self {
+        match value.split_once('{') {
+            Some((codec, params)) => Self {
+                codec: codec.trim().to_lowercase(),
+                params: parse_params(params.trim_end_matches('}'), ':', '&'),
+            },
+            None => Self::new(value.trim()),
+        }
+    }
+}
+
+impl fmt::Display for Decoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.params.is_empty() {
+            true => write!(f, "{}", self.codec),
+            false => write!(f, "{}{{{}}}", self.codec, format_params(&self.params, ':', '&')),
+        }
+    }
+}
+
+/// `X-Plex-Client-Capabilities`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ClientCapabilities {
+    pub protocols: Vec<String>,
+    pub video_decoders: Vec<Decoder>,
+    pub audio_decoders: Vec<Decoder>,
+    /// sections replex does not know, kept as is
+    pub other: Params,
+}
+
+impl ClientCapabilities {
+    pub fn parse(value: &str) -> Self {
+        let mut capabilities = Self::default();
+        for (key, value) in parse_params(value, '=', ';') {
+            let values = value.split(',').filter(|v| !v.trim().is_empty());
+            match key.as_str() {
+                "protocols" => capabilities.protocols = values.map(|v| v.trim().to_string()).collect(),
+                "videoDecoders" => capabilities.video_decoders = values.map(Decoder::parse).collect(),
+                "audioDecoders" => capabilities.audio_decoders = values.map(Decoder::parse).collect(),
+                _ => capabilities.other.push((key, value)),
+            }
+        }
+        capabilities
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Adds a decoder for the codec, when the client lists its decoders.
+    pub fn add_video_decoder(&mut self, codec: &str) {
+        Self::add_decoder(&mut self.video_decoders, codec);
+    }
+
+    pub fn add_audio_decoder(&mut self, codec: &str) {
+        Self::add_decoder(&mut self.audio_decoders, codec);
+    }
+
+    fn add_decoder(decoders: &mut Vec<Decoder>, codec: &str) {
+        if !decoders.is_empty() && !decoders.iter().any(|d| d.codec.eq_ignore_ascii_case(codec)) {
+            decoders.push(Decoder::new(codec));
+        }
+    }
+}
+
+impl fmt::Display for ClientCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sections: Params = vec![];
+        if !self.protocols.is_empty() {
+            sections.push(("protocols".to_string(), self.protocols.join(",")));
+        }
+        if !self.video_decoders.is_empty() {
+            sections.push(("videoDecoders".to_string(), self.video_decoders.iter().join(",")));
+        }
+        if !self.audio_decoders.is_empty() {
+            sections.push(("audioDecoders".to_string(), self.audio_decoders.iter().join(",")));
+        }
+        sections.extend(self.other.iter().cloned());
+        write!(f, "{}", format_params(&sections, '=', ';'))
+    }
+}
+
+/// One entry of the profile extra, ex `add-limitation(scope=videoCodec&...)`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Directive {
+    pub name: String,
+    pub params: Params,
+}
+
+impl Directive {
+    pub fn new(name: &str, params: &[(&str, &str)]) -> Self {
+        Self {
+            name: name.to_string(),
+            params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&str> {
+        get(&self.params, key)
+    }
+
+    fn parse(value: &str) -> Self {
+        match value.split_once('(') {
+            Some((name, params)) => Self {
+                name: name.trim().to_string(),
+                params: parse_params(params.trim_end_matches(')'), '=', '&'),
+            },
+            None => Self {
+                name: value.trim().to_string(),
+                params: vec![],
+            },
+        }
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.name, format_params(&self.params, '=', '&'))
+    }
+}
+
+/// `add-limitation`, ex max level 41 for h264:
+/// `scope=videoCodec&scopeName=h264&type=upperBound&name=video.level&value=41`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Limitation {
+    pub scope: String,
+    pub scope_name: String,
+    /// `upperBound`, `lowerBound`, `match`, `notMatch` ...
+    pub kind: String,
+    pub name: String,
+    pub value: String,
+    pub is_required: Option<bool>,
+}
+
+impl Limitation {
+    fn from_directive(directive: &Directive) -> Option<Self> {
+        if directive.name != "add-limitation" {
+            return None;
+        }
+        let value = |key| directive.param(key).unwrap_or_default().to_string();
+        Some(Self {
+            scope: value("scope"),
+            scope_name: value("scopeName"),
+            kind: value("type"),
+            name: value("name"),
+            value: value("value"),
+            is_required: directive.param("isRequired").map(|v| v == "true"),
+        })
+    }
+
+    fn to_directive(&self) -> Directive {
+        let mut directive = Directive::new(
+            "add-limitation",
+            &[
+                ("scope", &self.scope),
+                ("scopeName", &self.scope_name),
+                ("type", &self.kind),
+                ("name", &self.name),
+                ("value", &self.value),
+            ],
+        );
+        if let Some(required) = self.is_required {
+            directive.params.push(("isRequired".to_string(), required.to_string()));
+        }
+        directive
+    }
+
+    fn is_upper_bound(&self, name: &str) -> bool {
+        self.kind == "upperBound" && self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// `add-transcode-target`, what plex transcodes to.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TranscodeTarget {
+    /// `videoProfile`, `musicProfile` ...
+    pub kind: String,
+    pub context: Option<String>,
+    pub protocol: Option<String>,
+    pub container: Option<String>,
+    pub video_codecs: Vec<String>,
+    pub audio_codecs: Vec<String>,
+    pub subtitle_codecs: Vec<String>,
+}
+
+/// `X-Plex-Client-Profile-Extra`, directives in the order plex applies them.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ProfileExtra {
+    pub directives: Vec<Directive>,
+}
+
+impl ProfileExtra {
+    pub fn parse(value: &str) -> Self {
+        Self {
+            directives: value
+                .split('+')
+                .filter(|d| !d.trim().is_empty())
+                .map(Directive::parse)
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.directives.is_empty()
+    }
+
+    pub fn limitations(&self) -> Vec<Limitation> {
+        self.directives
+            .iter()
+            .filter_map(Limitation::from_directive)
+            .collect()
+    }
+
+    pub fn transcode_targets(&self) -> Vec<TranscodeTarget> {
+        self.directives
+            .iter()
+            .filter(|d| d.name == "add-transcode-target")
+            .map(|d| TranscodeTarget {
+                kind: d.param("type").unwrap_or_default().to_string(),
+                context: d.param("context").map(String::from),
+                protocol: d.param("protocol").map(String::from),
+                container: d.param("container").map(String::from),
+                video_codecs: list(&d.params, "videoCodec"),
+                audio_codecs: list(&d.params, "audioCodec"),
+                subtitle_codecs: list(&d.params, "subtitleCodec"),
+            })
+            .collect()
+    }
+
+    /// Values of a param over all directives, ex every `videoCodec`.
+    fn values(&self, key: &str) -> Vec<String> {
+        self.directives
+            .iter()
+            .flat_map(|d| list(&d.params, key))
+            .unique()
+            .collect()
+    }
+
+    pub fn add_limitation(&mut self, limitation: Limitation) {
+        self.directives.push(limitation.to_directive());
+    }
+
+    /// Removes the limitations `f` returns true for.
+    pub fn remove_limitations(&mut self, f: impl Fn(&Limitation) -> bool) {
+        self.directives
+            .retain(|d| !Limitation::from_directive(d).is_some_and(|l| f(&l)));
+    }
+
+    /// Adds the codec to the video transcode targets.
+    pub fn append_video_codec(&mut self, codec: &str) {
+        self.append_target_codec("videoProfile", "videoCodec", codec, true);
+    }
+
+    /// Adds the codec to the video transcode targets, and the music ones the client sent.
+    pub fn append_audio_codec(&mut self, codec: &str) {
+        self.append_target_codec("videoProfile", "audioCodec", codec, true);
+        self.append_target_codec("musicProfile", "audioCodec", codec, false);
+    }
+
+    pub fn append_subtitle_codec(&mut self, codec: &str) {
+        self.append_target_codec("videoProfile", "subtitleCodec", codec, true);
+    }
+
+    /// Adds the codec to the targets of `kind` that list codecs of `key`.
+    /// Without those, and with `fallback`, an `append-transcode-target-codec`
+    /// adds it to the target plex has for the client.
+    fn append_target_codec(&mut self, kind: &str, key: &str, codec: &str, fallback: bool) {
+        let mut appended = false;
+        for directive in self.directives.iter_mut() {
+            if (directive.name == "add-transcode-target"
+                || directive.name == "append-transcode-target-codec")
+                && directive.param("type") == Some(kind)
+            {
+                appended |= append(&mut directive.params, key, codec);
+            }
+        }
+        if !appended && fallback {
+            self.directives.push(Directive::new(
+                "append-transcode-target-codec",
+                &[("type", kind), ("context", "streaming"), (key, &codec.to_lowercase())],
+            ));
+        }
+    }
+
+    /// Lets plex direct play the codecs in the containers, empty lists are left out.
+    pub fn add_direct_play_profile(
+        &mut self,
+        containers: &[String],
+        video_codecs: &[String],
+        audio_codecs: &[String],
+        subtitle_codecs: &[String],
+    ) {
+        let mut directive = Directive::new("add-direct-play-profile", &[("type", "videoProfile")]);
+        for (key, values) in [
+            ("container", containers),
+            ("videoCodec", video_codecs),
+            ("audioCodec", audio_codecs),
+            ("subtitleCodec", subtitle_codecs),
+        ] {
+            if !values.is_empty() {
+                directive.params.push((key.to_string(), values.join(",")));
+            }
+        }
+        self.directives.push(directive);
+    }
+}
+
+impl fmt::Display for ProfileExtra {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.directives.iter().join("+"))
+    }
+}
+
+macro_rules! string_serde {
+    ($type:ty) => {
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.to_string())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                Ok(Self::parse(&String::deserialize(deserializer)?))
+            }
+        }
+    };
+}
+
+string_serde!(ClientCapabilities);
+string_serde!(ProfileExtra);
+
+/// Everything a client says it can play.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ClientProfile {
+    pub capabilities: ClientCapabilities,
+    pub extra: ProfileExtra,
+}
+
+impl ClientProfile {
+    /// Nothing known about the client.
+    pub fn is_empty(&self) -> bool {
+        self.capabilities.is_empty() && self.extra.is_empty()
+    }
+
+    fn codecs(&self, decoders: &[Decoder], key: &str) -> Option<Vec<String>> {
+        let codecs: Vec<String> = decoders
+            .iter()
+            .map(|d| d.codec.clone())
+            .chain(self.extra.values(key))
+            .filter(|c| c != "*")
+            .unique()
+            .collect();
+        (!codecs.is_empty()).then_some(codecs)
+    }
+
+    /// None when the client does not tell.
+    pub fn video_codecs(&self) -> Option<Vec<String>> {
+        self.codecs(&self.capabilities.video_decoders, "videoCodec")
+    }
+
+    pub fn audio_codecs(&self) -> Option<Vec<String>> {
+        self.codecs(&self.capabilities.audio_decoders, "audioCodec")
+    }
+
+    pub fn subtitle_codecs(&self) -> Option<Vec<String>> {
+        let codecs = self.extra.values("subtitleCodec");
+        (!codecs.is_empty()).then_some(codecs)
+    }
+
+    pub fn containers(&self) -> Option<Vec<String>> {
+        let containers = self.extra.values("container");
+        (!containers.is_empty()).then_some(containers)
+    }
+
+    /// Lines, from the decoders and `video.height` limitations.
+    pub fn max_resolution(&self) -> Option<i64> {
+        let decoders = self
+            .capabilities
+            .video_decoders
+            .iter()
+            .filter_map(|d| d.param("resolution")?.parse().ok());
+        let limitations = self
+            .extra
+            .limitations()
+            .into_iter()
+            .filter(|l| l.is_upper_bound("video.height"))
+            .filter_map(|l| l.value.parse().ok());
+        decoders.chain(limitations).max()
+    }
+
+    /// kbps, from `video.bitrate` limitations.
+    pub fn max_bitrate(&self) -> Option<i64> {
+        self.extra
+            .limitations()
+            .into_iter()
+            .filter(|l| l.is_upper_bound("video.bitrate"))
+            .filter_map(|l| l.value.parse().ok())
+            .min()
+    }
+
+    /// Some(false) when the bit depth is limited to 8, none when not known.
+    pub fn supports_hdr(&self) -> Option<bool> {
+        self.extra
+            .limitations()
+            .iter()
+            .any(|l| l.is_upper_bound("video.bitDepth") && l.value == "8")
+            .then_some(false)
+    }
+
+    pub fn supports_dolby_vision(&self) -> Option<bool> {
+        let lower = format!("{}+{}", self.capabilities, self.extra).to_lowercase();
+        lower.contains("dovi").then_some(true)
+    }
+}
+
+/// Codecs and containers a client plays but does not report, so plex
+/// does not transcode for it. Set in the config file as `[[client_overrides]]`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+pub struct ClientOverride {
+    pub name: Option<String>,
+    #[serde(default, rename = "match")]
+    pub matches: ProfileMatch,
+    #[serde(default)]
+    pub video_codecs: Vec<String>,
+    #[serde(default)]
+    pub audio_codecs: Vec<String>,
+    #[serde(default)]
+    pub containers: Vec<String>,
+    #[serde(default)]
+    pub subtitle_codecs: Vec<String>,
+}
+
+impl ClientOverride {
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("unnamed")
+    }
+
+    fn is_empty(&self) -> bool {
+        self.video_codecs.is_empty()
+            && self.audio_codecs.is_empty()
+            && self.containers.is_empty()
+            && self.subtitle_codecs.is_empty()
+    }
+
+    /// Adds the codecs to the decoders and transcode targets, and a direct play
+    /// profile with everything the client plays in the containers.
+    pub fn apply(&self, profile: &mut ClientProfile) {
+        if self.is_empty() {
+            return;
+        }
+        for codec in self.video_codecs.iter() {
+            profile.capabilities.add_video_decoder(codec);
+            profile.extra.append_video_codec(codec);
+        }
+        for codec in self.audio_codecs.iter() {
+            profile.capabilities.add_audio_decoder(codec);
+            profile.extra.append_audio_codec(codec);
+        }
+        for codec in self.subtitle_codecs.iter() {
+            profile.extra.append_subtitle_codec(codec);
+        }
+        let containers: Vec<String> = profile
+            .containers()
+            .unwrap_or_default()
+            .into_iter()
+            .chain(self.containers.iter().map(|c| c.to_lowercase()))
+            .unique()
+            .collect();
+        profile.extra.add_direct_play_profile(
+            &containers,
+            &profile.video_codecs().unwrap_or_default(),
+            &profile.audio_codecs().unwrap_or_default(),
+            &profile.subtitle_codecs().unwrap_or_default(),
+        );
+    }
+}
+
+/// Applies the matching `client_overrides` to a decision or start request,
+/// rewriting its profile extra param and capabilities header.
+pub async fn apply_overrides(req: &mut Request) {
+    let config = Config::dynamic(req);
+    if config.client_overrides.is_empty() {
+        return;
+    }
+    let context: PlexContext = req.extract().await.unwrap_or_default();
+    let overrides = config
+        .client_overrides
+        .iter()
+        .filter(|o| o.matches.matches(&context))
+        .collect_vec();
+    if overrides.is_empty() {
+        return;
+    }
+
+    let mut profile = context.client_profile();
+    for client_override in overrides {
+        tracing::debug!(client_override = client_override.name(), "Applying client override");
+        client_override.apply(&mut profile);
+    }
+
+    let mut queries = req.queries().clone();
+    queries.remove(PROFILE_EXTRA);
+    queries.insert(PROFILE_EXTRA.to_string(), profile.extra.to_string());
+    if !profile.capabilities.is_empty() {
+        let capabilities = profile.capabilities.to_string();
+        if queries.contains_key(CAPABILITIES) {
+            queries.remove(CAPABILITIES);
+            queries.insert(CAPABILITIES.to_string(), capabilities.clone());
+        }
+        if let Ok(value) = HeaderValue::from_str(&capabilities) {
+            req.headers_mut().insert(CAPABILITIES, value);
+        }
+    }
+    replace_query(queries, req);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::providers::{Format, Toml};
+    use figment::Figment;
+
+    const CAPABILITIES: &str = "protocols=http-live-streaming,http-mp4-streaming;videoDecoders=h264{profile:high&resolution:1080&level:51},hevc{profile:main10&resolution:2160};audioDecoders=aac,ac3{bitrate:800000&channels:6}";
+    const EXTRA: &str = "add-transcode-target(type=videoProfile&context=streaming&protocol=hls&container=mpegts&videoCodec=h264&audioCodec=aac,mp3)+add-limitation(scope=videoCodec&scopeName=h264&type=upperBound&name=video.bitrate&value=8000)+add-limitation(scope=videoCodec&scopeName=hevc&type=upperBound&name=video.bitDepth&value=8)";
+
+    #[test]
+    fn test_parse_capabilities() {
+        let capabilities = ClientCapabilities::parse(CAPABILITIES);
+        assert_eq!(capabilities.protocols, vec!["http-live-streaming", "http-mp4-streaming"]);
+        assert_eq!(capabilities.video_decoders[1].codec, "hevc");
+        assert_eq!(capabilities.video_decoders[0].param("level"), Some("51"));
+        assert_eq!(capabilities.audio_decoders[1].param("channels"), Some("6"));
+        assert_eq!(capabilities.to_string(), CAPABILITIES);
+    }
+
+    #[test]
+    fn test_parse_profile_extra() {
+        let extra = ProfileExtra::parse(EXTRA);
+        let targets = extra.transcode_targets();
+        assert_eq!(targets[0].container.as_deref(), Some("mpegts"));
+        assert_eq!(targets[0].audio_codecs, vec!["aac", "mp3"]);
+        assert_eq!(extra.limitations()[0].scope_name, "h264");
+        assert_eq!(extra.to_string(), EXTRA);
+
+        let profile = ClientProfile {
+            capabilities: ClientCapabilities::parse(CAPABILITIES),
+            extra,
+        };
+        assert_eq!(profile.video_codecs(), Some(vec!["h264".to_string(), "hevc".to_string()]));
+        assert_eq!(profile.max_resolution(), Some(2160));
+        assert_eq!(profile.max_bitrate(), Some(8000));
+        assert_eq!(profile.supports_hdr(), Some(false));
+    }
+
+    #[test]
+    fn test_rewrite_profile_extra() {
+        let mut extra = ProfileExtra::parse(EXTRA);
+        extra.remove_limitations(|l| l.name == "video.bitrate");
+        extra.append_audio_codec("truehd");
+        extra.append_audio_codec("aac");
+        extra.add_limitation(Limitation {
+            scope: "videoAudioCodec".to_string(),
+            scope_name: "truehd".to_string(),
+            kind: "upperBound".to_string(),
+            name: "audio.channels".to_string(),
+            value: "8".to_string(),
+            is_required: None,
+        });
+        assert_eq!(extra.transcode_targets()[0].audio_codecs, vec!["aac", "mp3", "truehd"]);
+        assert_eq!(extra.limitations().len(), 2);
+        assert!(extra.to_string().ends_with("add-limitation(scope=videoAudioCodec&scopeName=truehd&type=upperBound&name=audio.channels&value=8)"));
+    }
+
+    #[test]
+    fn test_client_override() {
+        let client_override: ClientOverride = Figment::new()
+            .merge(Toml::string(
+                r#"
+                name = "shield"
+                match.devices = ["SHIELD Android TV"]
+                audio_codecs = ["truehd", "aac"]
+                containers = ["mkv"]
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        let mut profile = ClientProfile {
+            capabilities: ClientCapabilities::parse(CAPABILITIES),
+            extra: ProfileExtra::parse("add-transcode-target(type=videoProfile&videoCodec=h264&audioCodec=aac)"),
+        };
+        client_override.apply(&mut profile);
+
+        assert_eq!(profile.capabilities.audio_decoders[2].codec, "truehd");
+        assert_eq!(profile.extra.transcode_targets()[0].audio_codecs, vec!["aac", "truehd"]);
+        assert_eq!(
+            profile.extra.directives.last().unwrap().to_string(),
+            "add-direct-play-profile(type=videoProfile&container=mkv&videoCodec=h264,hevc&audioCodec=aac,ac3,truehd)"
+        );
+
+        // without targets the codec is appended to the one plex has for the client
+        let mut extra = ProfileExtra::default();
+        extra.append_audio_codec("eac3");
+        assert_eq!(extra.to_string(), "append-transcode-target-codec(type=videoProfile&context=streaming&audioCodec=eac3)");
+    }
+}
@@ -1,23 +1,62 @@
-use crate::models::deserialize_comma_seperated_string;
-use figment::{providers::Env, Figment};
-use serde::{Deserialize, Deserializer};
+use crate::models::{PlexContext, Platform, Style};
+use crate::transform::hub_style::DeviceType;
+use crate::transform::interleave::{DedupeMode, InterleaveGroup, InterleaveStrategy};
+use crate::transform::pipeline::TRANSFORMS;
+use figment::{
+    providers::{Env, Format, Serialized, Toml, Yaml},
+    value::{Dict, Map, Value},
+    Error, Figment, Metadata, Profile, Provider,
+};
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::notify::NotifyEventKind;
+use crate::policy::PlaybackPolicy;
+use crate::client_profile::ClientOverride;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 // use serde::Deserialize;
 
+/// Env var pointing to an optional TOML or YAML config file.
+pub const CONFIG_PATH_ENV: &str = "REPLEX_CONFIG";
+
+/// Sections a config file can use to group settings. Their keys are
+/// lifted to the top level, so `[hubs] interleave = true` equals `interleave = true`.
+pub const CONFIG_SECTIONS: [&str; 3] = ["hubs", "playback", "cache"];
+
+/// How often the config file is checked for changes.
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
 fn default_as_false() -> bool {
     false
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Config {
-    #[serde(deserialize_with = "deserialize_host")]
+    #[serde(default, deserialize_with = "deserialize_host")]
     pub host: Option<String>,
     pub token: Option<String>,
+    /// shared secret plex sends in the `secret` param of the webhook url
+    pub webhook_secret: Option<String>,
     pub port: Option<u64>,
     #[serde(
         default = "default_as_true",
         deserialize_with = "figment::util::bool_from_str_or_int"
     )]
     pub interleave: bool,
+    #[serde(default)]
+    pub interleave_strategy: InterleaveStrategy,
+    #[serde(default)]
+    pub interleave_group: InterleaveGroup,
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    pub interleave_patterns: Option<Vec<String>>,
+    #[serde(default)]
+    pub dedupe: DedupeMode,
+    /// hubs matching earlier patterns keep duplicates, others by position
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    pub dedupe_priority: Option<Vec<String>>,
     #[serde(
         default = "default_as_true",
         deserialize_with = "figment::util::bool_from_str_or_int"
@@ -30,6 +69,27 @@ pub struct Config {
     pub exclude_watched: bool,
     #[serde(default = "default_cache_ttl")]
     pub cache_ttl: u64,
+    #[serde(
+        default = "default_as_false",
+        deserialize_with = "figment::util::bool_from_str_or_int"
+    )]
+    pub cache_responses: bool,
+    /// seconds a cached response is fresh
+    #[serde(default = "default_cache_responses_ttl")]
+    pub cache_responses_ttl: u64,
+    /// seconds after that a stale response is served while it refreshes
+    #[serde(default = "default_cache_responses_stale")]
+    pub cache_responses_stale: u64,
+    #[serde(
+        default = "default_as_false",
+        deserialize_with = "figment::util::bool_from_str_or_int"
+    )]
+    pub cache_warm: bool,
+    /// directory of the disk cache, disabled when not set
+    pub cache_dir: Option<String>,
+    /// size cap of the disk cache in megabytes
+    #[serde(default = "default_cache_dir_max_size")]
+    pub cache_dir_max_size: u64,
     #[serde(
         default = "default_as_true",
         deserialize_with = "figment::util::bool_from_str_or_int"
@@ -41,7 +101,7 @@ pub struct Config {
         deserialize_with = "figment::util::bool_from_str_or_int"
     )]
     pub cache_rows_refresh: bool,
-    #[serde(default, deserialize_with = "deserialize_comma_seperated_string")]
+    #[serde(default, deserialize_with = "deserialize_string_list")]
     pub hero_rows: Option<Vec<String>>,
     #[serde(
         default = "default_as_false",
@@ -50,6 +110,24 @@ pub struct Config {
     pub ssl_enable: bool,
     pub ssl_domain: Option<String>,
     pub newrelic_api_key: Option<String>,
+    /// region of the New Relic account, picks the endpoint for `newrelic_api_key`
+    #[serde(default)]
+    pub newrelic_region: NewRelicRegion,
+    /// OTLP http traces endpoint, ex `http://collector:4318/v1/traces`
+    pub otlp_endpoint: Option<String>,
+    #[serde(default)]
+    pub otlp_headers: HashMap<String, String>,
+    /// share of traces exported, between 0 and 1
+    #[serde(default = "default_otlp_sample_ratio")]
+    pub otlp_sample_ratio: f64,
+    /// file the json access log is written to, `-` for stdout
+    pub access_log: Option<String>,
+    /// megabytes after that the access log is rotated
+    #[serde(default = "default_access_log_max_size")]
+    pub access_log_max_size: u64,
+    /// rotated access log files kept next to the current one
+    #[serde(default = "default_access_log_max_files")]
+    pub access_log_max_files: u32,
     #[serde(
         default = "default_as_false",
         deserialize_with = "figment::util::bool_from_str_or_int"
@@ -59,6 +137,11 @@ pub struct Config {
         default = "default_as_false",
         deserialize_with = "figment::util::bool_from_str_or_int"
     )]
+    pub enable_metrics: bool,
+    #[serde(
+        default = "default_as_false",
+        deserialize_with = "figment::util::bool_from_str_or_int"
+    )]
     pub disable_continue_watching: bool,
     #[serde(
         default = "default_as_false",
@@ -96,22 +179,133 @@ pub struct Config {
         deserialize_with = "figment::util::bool_from_str_or_int"
     )]
     pub auto_select_version: bool,
-    #[serde(default, deserialize_with = "deserialize_comma_seperated_string")]
+    #[serde(default, deserialize_with = "deserialize_string_list")]
     pub video_transcode_fallback_for: Option<Vec<String>>,
-    #[serde(default, deserialize_with = "deserialize_comma_seperated_string")]
+    #[serde(default, deserialize_with = "deserialize_string_list")]
     pub force_direct_play_for: Option<Vec<String>>,
-    pub test_script: Option<String>,
+    /// proxies whose `X-Real-Ip` and `X-Forwarded-For` headers are believed, addresses or networks
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub trusted_proxies: Option<Vec<String>>,
+    #[serde(default)]
+    pub scripts: ScriptsConfig,
+    #[serde(default)]
+    pub pipelines: PipelinesConfig,
+    pub hub_children_limit: Option<i32>,
+    #[serde(default)]
+    pub virtual_hubs: Vec<VirtualHub>,
+    #[serde(default)]
+    pub hub_order: HubOrderConfig,
+    #[serde(default)]
+    pub notifications: Vec<NotificationTarget>,
+    #[serde(default)]
+    pub policies: Vec<PlaybackPolicy>,
+    #[serde(default)]
+    pub client_overrides: Vec<ClientOverride>,
     #[serde(
         default = "default_as_false",
         deserialize_with = "figment::util::bool_from_str_or_int"
     )]
     pub ntf_watchlist_force: bool,
+    #[serde(default)]
+    pub profiles: Vec<ConfigProfile>,
+}
+
+/// Settings overlay applied to requests matching all the given criteria.
+/// Later profiles win over earlier ones when multiple match.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+pub struct ConfigProfile {
+    pub name: Option<String>,
+    #[serde(default, rename = "match")]
+    pub matches: ProfileMatch,
+    #[serde(default)]
+    pub settings: Dict,
+}
+
+/// Every set criterion has to match, a criterion matches if any of its values does.
+/// Values are compared case insensitive.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+pub struct ProfileMatch {
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub usernames: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub tokens: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub products: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub platforms: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub devices: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub models: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub device_types: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub client_identifiers: Option<Vec<String>>,
+}
+
+fn matches_any(values: &Option<Vec<String>>, value: Option<String>) -> bool {
+    match values {
+        None => true,
+        Some(values) => match value {
+            Some(value) => values.iter().any(|v| v.eq_ignore_ascii_case(&value)),
+            None => false,
+        },
+    }
+}
+
+impl ProfileMatch {
+    pub fn matches(&self, context: &PlexContext) -> bool {
+        let device_type = context
+            .product
+            .clone()
+            .map(|p| format!("{:?}", DeviceType::from_product(p)));
+
+        matches_any(&self.usernames, context.username.clone())
+            && matches_any(&self.tokens, context.token.clone())
+            && matches_any(&self.products, context.product.clone())
+            && matches_any(
+                &self.platforms,
+                context.platform.clone().map(|p| p.to_string()),
+            )
+            && (matches_any(&self.devices, context.device.clone())
+                || matches_any(&self.devices, context.device_name.clone()))
+            && matches_any(&self.models, context.model.clone())
+            && matches_any(&self.device_types, device_type)
+            && matches_any(
+                &self.client_identifiers,
+                context.client_identifier.clone(),
+            )
+    }
 }
 
 fn default_cache_ttl() -> u64 {
     30 * 60 // 30 minutes
 }
 
+fn default_otlp_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_access_log_max_size() -> u64 {
+    100
+}
+
+fn default_access_log_max_files() -> u32 {
+    5
+}
+
+fn default_cache_responses_ttl() -> u64 {
+    5 * 60 // 5 minutes
+}
+
+fn default_cache_responses_stale() -> u64 {
+    60 * 60 // 1 hour
+}
+
+fn default_cache_dir_max_size() -> u64 {
+    1024
+}
+
 pub(crate) fn deserialize_host<'de, D>(
     deserializer: D,
 ) -> Result<Option<String>, D::Error>
@@ -129,6 +323,70 @@ where
     }
 }
 
+/// Accepts both a comma seperated string (env vars) and a list (config files).
+pub(crate) fn deserialize_string_list<'de, D>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    match Option::<StringOrList>::deserialize(deserializer)? {
+        Some(StringOrList::String(s)) => {
+            Ok(Some(s.split(',').map(|s| s.trim().to_owned()).collect()))
+        }
+        Some(StringOrList::List(l)) => Ok(Some(l)),
+        None => Ok(None),
+    }
+}
+
+/// Transform names, unknown names are an error.
+fn deserialize_pipeline<'de, D>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(names) = deserialize_string_list(deserializer)? else {
+        return Ok(None);
+    };
+    let names: Vec<String> = names.into_iter().filter(|n| !n.is_empty()).collect();
+    for name in &names {
+        if !TRANSFORMS.contains(&name.as_str()) {
+            return Err(serde::de::Error::custom(format!(
+                "unknown transform `{}`, expected one of {}",
+                name,
+                TRANSFORMS.join(", ")
+            )));
+        }
+    }
+    Ok(Some(names))
+}
+
+/// Regex patterns, invalid patterns are an error.
+fn deserialize_patterns<'de, D>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(patterns) = deserialize_string_list(deserializer)? else {
+        return Ok(None);
+    };
+    let patterns: Vec<String> =
+        patterns.into_iter().filter(|p| !p.is_empty()).collect();
+    for pattern in &patterns {
+        regex::Regex::new(pattern).map_err(serde::de::Error::custom)?;
+    }
+    Ok(Some(patterns))
+}
+
 fn default_as_true() -> bool {
     true
 }
@@ -137,28 +395,587 @@ fn deserialize_hosr() -> bool {
     true
 }
 
+static CONFIG: Lazy<ArcSwap<Snapshot>> = Lazy::new(|| {
+    let config = Config::load().expect("Invalid configuration");
+    ArcSwap::from_pointee(Snapshot::new(config).expect("Invalid configuration profile"))
+});
+
+/// A loaded config with the configs of its profiles. Profiles are applied
+/// once per combination of matching profiles, not on every request.
+pub struct Snapshot {
+    config: Arc<Config>,
+    /// by the indexes of the matching profiles
+    profiled: Mutex<HashMap<Vec<usize>, Arc<Config>>>,
+}
+
+impl Snapshot {
+    /// Applies every profile up front, so invalid profiles fail the load.
+    pub fn new(config: Config) -> Result<Snapshot, Error> {
+        let snapshot = Snapshot {
+            config: Arc::new(config),
+            profiled: Mutex::new(HashMap::new()),
+        };
+        let mut profiled = HashMap::new();
+        for i in 0..snapshot.config.profiles.len() {
+            profiled.insert(vec![i], Arc::new(snapshot.apply(&[i])?));
+        }
+        *snapshot.profiled.lock().unwrap() = profiled;
+        Ok(snapshot)
+    }
+
+    /// Config with the profiles matching the client applied.
+    pub fn for_context(&self, context: &PlexContext) -> Arc<Config> {
+        let matching: Vec<usize> = self
+            .config
+            .profiles
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.matches.matches(context))
+            .map(|(i, _)| i)
+            .collect();
+        if matching.is_empty() {
+            return self.config.clone();
+        }
+        if let Some(config) = self.profiled.lock().unwrap().get(&matching) {
+            return config.clone();
+        }
+
+        let config = match self.apply(&matching) {
+            Ok(config) => Arc::new(config),
+            Err(error) => {
+                tracing::error!(error = %error, "Invalid config profile, ignoring profiles");
+                self.config.clone()
+            }
+        };
+        self.profiled
+            .lock()
+            .unwrap()
+            .insert(matching, config.clone());
+        config
+    }
+
+    fn apply(&self, profiles: &[usize]) -> Result<Config, Error> {
+        let mut figment = Figment::from(Serialized::defaults(&*self.config));
+        for i in profiles {
+            let profile = &self.config.profiles[*i];
+            tracing::trace!(profile = ?profile.name, "Applying config profile");
+            figment = figment.merge(Serialized::defaults(flatten_sections(
+                profile.settings.clone(),
+            )));
+        }
+        figment.extract()
+    }
+}
+
 impl Config {
-    // Note the `nested` option on both `file` providers. This makes each
-    // top-level dictionary act as a profile.
+    /// Config file (if any) first, env vars are merged on top so they always win.
     pub fn figment() -> Figment {
-        Figment::new().merge(Env::prefixed("REPLEX_"))
+        let mut figment = Figment::new();
+        if let Ok(path) = std::env::var(CONFIG_PATH_ENV) {
+            figment = figment.merge(ConfigFile::new(path));
+        }
+        // nested keys use a double underscore, ex REPLEX_SCRIPTS__HUBS_PROMOTED
+        figment.merge(Env::prefixed("REPLEX_").split("__"))
+    }
+
+    /// Read the config from its sources. Use `current` for the shared snapshot.
+    pub fn load() -> Result<Config, Error> {
+        Config::figment().extract()
+    }
+
+    /// The active config snapshot.
+    pub fn current() -> Arc<Config> {
+        CONFIG.load().config.clone()
+    }
+
+    /// Reload the config from its sources and swap the active snapshot.
+    /// On errors the current snapshot is kept.
+    pub fn reload() -> Result<Arc<Config>, Error> {
+        Config::reload_from(Config::figment())
+    }
+
+    fn reload_from(figment: Figment) -> Result<Arc<Config>, Error> {
+        let snapshot = Snapshot::new(figment.extract()?)?;
+        let config = snapshot.config.clone();
+        CONFIG.store(Arc::new(snapshot));
+        // responses were transformed with the old config
+        crate::cache::RESPONSE_CACHE.clear();
+        Ok(config)
     }
 
-    pub fn dynamic(req: &salvo::Request) -> Figment {
-        let host = req.headers().get("HOST").unwrap().to_str().unwrap();
-        let mut config = Config::figment();
-        if host.contains("replex.stream") {
+    /// Indexes of the profiles matching the request.
+    pub fn matching_profiles(&self, req: &salvo::Request) -> Vec<usize> {
+        let context = profile_context(req);
+        self.profiles
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.matches.matches(&context))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn dynamic(req: &salvo::Request) -> Arc<Config> {
+        let mut config = CONFIG.load().for_context(&profile_context(req));
+        let host = req
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default();
+        if host.contains("replex.stream") && config.host.is_none() {
             use data_encoding::BASE32;
             let val: Vec<&str> = host.split(".replex.stream").collect();
             let owned_val = val[0].to_ascii_uppercase().to_owned();
             let mut output = vec![0; BASE32.decode_len(owned_val.len()).unwrap()];
             let len = BASE32.decode_mut(owned_val.as_bytes(), &mut output).unwrap();
-            config = config.join(("host", std::str::from_utf8(&output[0 .. len]).unwrap()));
+            let mut with_host = (*config).clone();
+            with_host.host = Some(std::str::from_utf8(&output[0 .. len]).unwrap().to_string());
+            config = Arc::new(with_host);
         }
         config
         // Figment::new().merge(Env::prefixed("REPLEX_"))
     }
+
+    /// Config for the given client, with the matching profiles applied.
+    pub fn for_context(context: &PlexContext) -> Arc<Config> {
+        CONFIG.load().for_context(context)
+    }
+
+    /// Reload the config on SIGHUP and when the config file changes.
+    pub fn watch() {
+        #[cfg(unix)]
+        tokio::spawn(async {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(s) => s,
+                Err(error) => {
+                    tracing::warn!(error = %error, "Cannot listen for SIGHUP");
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                tracing::info!("Received SIGHUP, reloading config");
+                Config::reload_or_log();
+            }
+        });
+
+        if let Ok(path) = std::env::var(CONFIG_PATH_ENV) {
+            tokio::spawn(async move {
+                let modified = |path: &str| {
+                    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+                };
+                let mut last_modified = modified(&path);
+                let mut interval =
+                    tokio::time::interval(CONFIG_WATCH_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let current = modified(&path);
+                    if current != last_modified {
+                        last_modified = current;
+                        tracing::info!(path = %path, "Config file changed, reloading config");
+                        Config::reload_or_log();
+                    }
+                }
+            });
+        }
+    }
+
+    fn reload_or_log() {
+        if let Err(errors) = Config::reload() {
+            for error in errors {
+                tracing::error!(error = %error, "Invalid configuration, keeping the current one");
+            }
+        }
+    }
     // pub fn default() -> Self {
     //     Config { include_watched: false}
     // }
 }
+
+/// Data center region of a New Relic account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NewRelicRegion {
+    #[default]
+    Us,
+    Eu,
+}
+
+impl NewRelicRegion {
+    /// OTLP http traces endpoint of the region.
+    pub fn otlp_endpoint(self) -> &'static str {
+        match self {
+            Self::Us => "https://otlp.nr-data.net:4318/v1/traces",
+            Self::Eu => "https://otlp.eu01.nr-data.net:4318/v1/traces",
+        }
+    }
+}
+
+/// Rhai scripts to run per endpoint, see `transform::scripting`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+pub struct ScriptsConfig {
+    pub hubs_promoted: Option<String>,
+    pub hubs_sections: Option<String>,
+    pub collection_children: Option<String>,
+    pub metadata: Option<String>,
+}
+
+/// Hub identifier prefix of virtual hubs, followed by the hub id.
+pub const VIRTUAL_HUB_PREFIX: &str = "replex.virtual.";
+
+/// A hub that plex does not have, loaded from a plex library path.
+/// Injected by `VirtualHubTransform` and paged through `/replex/<style>/virtual/<id>`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct VirtualHub {
+    pub id: String,
+    pub title: String,
+    /// ex `/library/sections/1/all?type=1&unwatched=1&sort=addedAt:desc`
+    pub path: String,
+    /// show on the home screen
+    #[serde(default = "default_as_false")]
+    pub home: bool,
+    /// show on the recommended tab of these sections
+    #[serde(default)]
+    pub sections: Vec<i64>,
+    pub style: Option<Style>,
+    #[serde(default = "default_virtual_hub_limit")]
+    pub limit: i32,
+    /// index in the hub list, defaults to the end
+    pub position: Option<usize>,
+}
+
+fn default_virtual_hub_limit() -> i32 {
+    20
+}
+
+/// HTTP endpoint replex posts playback events to, see `notify`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct NotificationTarget {
+    pub url: String,
+    /// events to send, all when not set
+    pub events: Option<Vec<NotifyEventKind>>,
+    /// extra request headers, ex an authorization header
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// attempts after the first one failed
+    #[serde(default = "default_notification_retries")]
+    pub retries: u32,
+}
+
+fn default_notification_retries() -> u32 {
+    3
+}
+
+/// Hub patterns for `HubOrderTransform`. Patterns are case insensitive
+/// regexes matched against the hub identifier and title.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+pub struct HubOrderConfig {
+    /// hubs moved to the top, in pattern order
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    pub pin: Option<Vec<String>>,
+    /// hubs moved after the pinned ones, in pattern order
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    pub order: Option<Vec<String>>,
+    /// hubs removed
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    pub hide: Option<Vec<String>>,
+}
+
+/// Transform names per route, see `transform::pipeline`.
+/// Routes that are not set use the default pipeline.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+pub struct PipelinesConfig {
+    #[serde(default, deserialize_with = "deserialize_pipeline")]
+    pub hubs_promoted: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_pipeline")]
+    pub hubs_sections: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_pipeline")]
+    pub collection_children: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_pipeline")]
+    pub default: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_pipeline")]
+    pub metadata: Option<Vec<String>>,
+}
+
+/// The fields profiles can match on. Extracting the full `PlexContext` is async,
+/// so we read them straight from the headers and query.
+fn profile_context(req: &salvo::Request) -> PlexContext {
+    let value = |name: &str| -> Option<String> {
+        req.header::<String>(name).or_else(|| req.query::<String>(name))
+    };
+    PlexContext {
+        username: value("X-Plex-Username"),
+        token: value("X-Plex-Token"),
+        product: value("X-Plex-Product"),
+        platform: value("X-Plex-Platform")
+            .map(|p| p.parse().unwrap_or(Platform::Generic)),
+        device: value("X-Plex-Device"),
+        device_name: value("X-Plex-Device-Name"),
+        model: value("X-Plex-Model"),
+        client_identifier: value("X-Plex-Client-Identifier"),
+        ..PlexContext::default()
+    }
+}
+
+/// A TOML or YAML config file, picked by extension. Missing files are an error
+/// as the path was explicitly given.
+pub struct ConfigFile {
+    path: PathBuf,
+}
+
+impl ConfigFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn is_yaml(&self) -> bool {
+        matches!(
+            self.path.extension().and_then(|e| e.to_str()),
+            Some("yaml") | Some("yml")
+        )
+    }
+}
+
+impl Provider for ConfigFile {
+    fn metadata(&self) -> Metadata {
+        match self.is_yaml() {
+            true => Yaml::file(&self.path).metadata(),
+            false => Toml::file(&self.path).metadata(),
+        }
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, Error> {
+        if !self.path.is_file() {
+            return Err(Error::from(format!(
+                "config file {} does not exist",
+                self.path.display()
+            )));
+        }
+
+        let data = match self.is_yaml() {
+            true => Yaml::file(&self.path).data()?,
+            false => Toml::file(&self.path).data()?,
+        };
+
+        Ok(data
+            .into_iter()
+            .map(|(profile, dict)| (profile, flatten_sections(dict)))
+            .collect())
+    }
+}
+
+/// Lift the keys of the known config sections to the top level.
+/// Keys set outside a section take precedence.
+pub fn flatten_sections(mut dict: Dict) -> Dict {
+    for section in CONFIG_SECTIONS {
+        if let Some(Value::Dict(_, values)) = dict.remove(section) {
+            for (key, value) in values {
+                dict.entry(key).or_insert(value);
+            }
+        }
+    }
+    dict
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file_sections() {
+        let dir = std::env::temp_dir().join(format!("replex-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("replex.toml");
+        std::fs::write(
+            &path,
+            r#"
+            host = "http://plex:32400/"
+
+            [hubs]
+            interleave = false
+            hero_rows = ["movies.recent", "tv.inprogress"]
+
+            [cache]
+            cache_ttl = 60
+            "#,
+        )
+        .unwrap();
+
+        let config: Config =
+            Figment::from(ConfigFile::new(&path)).extract().unwrap();
+        assert_eq!(config.host, Some("http://plex:32400".to_string()));
+        assert!(!config.interleave);
+        assert_eq!(config.cache_ttl, 60);
+        assert_eq!(
+            config.hero_rows,
+            Some(vec!["movies.recent".to_string(), "tv.inprogress".to_string()])
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("replex-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("replex.toml");
+        let figment = || Config::figment().merge(ConfigFile::new(&path));
+        let write = |cache_ttl: u64, disable_related: bool| {
+            let config = format!(
+                r#"
+                cache_ttl = {cache_ttl}
+
+                [[profiles]]
+                match.products = ["Replex Reload Test"]
+                settings.disable_related = {disable_related}
+                "#
+            );
+            std::fs::write(&path, config).unwrap();
+        };
+        let client = PlexContext {
+            product: Some("Replex Reload Test".to_string()),
+            ..PlexContext::default()
+        };
+
+        write(4321, true);
+        Config::reload_from(figment()).unwrap();
+        assert_eq!(Config::current().cache_ttl, 4321);
+        let profiled = Config::for_context(&client);
+        assert!(profiled.disable_related);
+
+        write(1234, false);
+        Config::reload_from(figment()).unwrap();
+        assert_eq!(Config::current().cache_ttl, 1234);
+        assert!(!Config::for_context(&client).disable_related);
+        assert!(profiled.disable_related);
+
+        // invalid files keep the current snapshot
+        std::fs::write(&path, "cache_ttl = \"soon\"").unwrap();
+        assert!(Config::reload_from(figment()).is_err());
+        assert_eq!(Config::current().cache_ttl, 1234);
+
+        Config::reload().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config: Config = Figment::from(Toml::string(
+            r#"
+            exclude_watched = true
+
+            [[profiles]]
+            name = "kids"
+            match.usernames = ["kid"]
+            settings.exclude_watched = false
+            settings.hubs.hero_rows = ["movies.recent"]
+
+            [[profiles]]
+            match.platforms = ["Android"]
+            match.device_types = ["tv"]
+            settings.disable_user_state = true
+            "#,
+        ))
+        .extract()
+        .unwrap();
+        let config = Snapshot::new(config).unwrap();
+
+        let kid = PlexContext {
+            username: Some("Kid".to_string()),
+            platform: Some(Platform::Android),
+            product: Some("Plex for Android (Mobile)".to_string()),
+            ..PlexContext::default()
+        };
+        let kid_config = config.for_context(&kid);
+        assert!(!kid_config.exclude_watched);
+        assert!(!kid_config.disable_user_state);
+        assert_eq!(kid_config.hero_rows, Some(vec!["movies.recent".to_string()]));
+
+        let tv = PlexContext {
+            platform: Some(Platform::Android),
+            product: Some("Plex for Android (TV)".to_string()),
+            ..PlexContext::default()
+        };
+        let tv_config = config.for_context(&tv);
+        assert!(tv_config.exclude_watched);
+        assert!(tv_config.disable_user_state);
+
+        // profiles are applied once per snapshot
+        assert!(Arc::ptr_eq(&config.for_context(&kid), &kid_config));
+        assert!(Arc::ptr_eq(&config.for_context(&PlexContext::default()), &config.config));
+    }
+
+    #[test]
+    fn test_profile_match_model() {
+        let config: Config = Figment::from(Toml::string(
+            r#"
+            [[profiles]]
+            name = "shield"
+            match.models = ["darcy"]
+            settings.disable_user_state = true
+            "#,
+        ))
+        .extract()
+        .unwrap();
+
+        let req = salvo::test::TestClient::get("http://localhost/hubs")
+            .add_header("X-Plex-Model", "darcy", true)
+            .build();
+        assert_eq!(config.matching_profiles(&req), vec![0]);
+
+        let req = salvo::test::TestClient::get("http://localhost/hubs?X-Plex-Model=sif").build();
+        assert!(config.matching_profiles(&req).is_empty());
+    }
+
+    #[test]
+    fn test_pipelines() {
+        let config: Config = Figment::from(Toml::string(
+            r#"
+            [pipelines]
+            hubs_promoted = "hub_restriction, hub_children_limit, hub_key"
+            "#,
+        ))
+        .extract()
+        .unwrap();
+        assert_eq!(
+            config.pipelines.hubs_promoted,
+            Some(vec![
+                "hub_restriction".to_string(),
+                "hub_children_limit".to_string(),
+                "hub_key".to_string()
+            ])
+        );
+        assert_eq!(config.pipelines.default, None);
+
+        let result = Figment::from(Toml::string(
+            r#"
+            [pipelines]
+            default = ["hub_restriction", "unknown"]
+            "#,
+        ))
+        .extract::<Config>();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_hub_order() {
+        let config: Config = Figment::from(Toml::string(
+            r#"
+            [hub_order]
+            pin = "home.continue"
+            hide = ["^Recently Played", "home.playlists"]
+            "#,
+        ))
+        .extract()
+        .unwrap();
+        assert_eq!(config.hub_order.pin, Some(vec!["home.continue".to_string()]));
+        assert_eq!(config.hub_order.order, None);
+
+        let result = Figment::from(Toml::string(
+            r#"
+            [hub_order]
+            hide = ["(unclosed"]
+            "#,
+        ))
+        .extract::<Config>();
+        assert!(result.is_err());
+    }
+}
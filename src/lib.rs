@@ -17,6 +17,12 @@ pub mod logging;
 pub mod cache;
 pub mod routes;
 pub mod webhooks;
+pub mod notify;
+pub mod policy;
+pub mod client_profile;
+pub mod warmer;
+pub mod metrics;
+pub mod access_log;
 //pub mod proxy;
 pub mod timeout;
 pub mod headers;
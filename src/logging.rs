@@ -1,5 +1,15 @@
 //! Logging middleware
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use crate::access_log::{self, AccessLogEntry};
+use crate::config::Config;
+use crate::models::PlexContext;
+use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
 
 use tracing::{Instrument, Level};
 
@@ -31,16 +41,49 @@ impl Handler for Logger {
             path = %req.uri(),
             span.kind = "server",
             service.name = "replex",
-            name = tracing::field::Empty,
+            otel.name = %format!("{} {}", req.method(), crate::metrics::route_label(req.uri().path())),
+            otel.kind = "server",
+            http.status_code = tracing::field::Empty,
             otel.status_code = tracing::field::Empty,
             otel.status_description = tracing::field::Empty,
         );
 
+        let request_span = span.clone();
         async move {
+            let path = req.uri().path().to_string();
+            let method = req.method().to_string();
+            let product = crate::metrics::product_label(req);
             let now = Instant::now();
-            ctrl.call_next(req, depot, res).await;
+            let entry = match access_log::enabled() {
+                true => {
+                    let context: PlexContext = req.extract().await.unwrap_or_default();
+                    let entry = AccessLogEntry::new(method.clone(), path.clone(), req.uri().query(), &context);
+                    Some(access_log::scope(entry, ctrl.call_next(req, depot, res)).await)
+                }
+                false => {
+                    ctrl.call_next(req, depot, res).await;
+                    None
+                }
+            };
             let duration = now.elapsed();
             let status = res.status_code.unwrap_or(StatusCode::OK);
+            // params are known once the request is routed
+            let params: Vec<(String, String)> = req
+                .params()
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            let route = crate::metrics::route_template(&path, &params);
+            crate::metrics::observe_request(&route, &method, &product, status, duration);
+            if let Some(mut entry) = entry {
+                entry.status = status.as_u16();
+                entry.duration_ms = duration.as_secs_f64() * 1000.0;
+                access_log::write(&entry);
+            }
+            request_span.record("http.status_code", status.as_u16());
+            if status.is_server_error() {
+                request_span.record("otel.status_code", "ERROR");
+            }
             tracing::debug!(
                 status = %status,
                 path = %req.uri().path(),
@@ -51,4 +94,47 @@ impl Handler for Logger {
         .instrument(span)
         .await
     }
+}
+
+/// Exports the spans of requests, transforms and plex calls with OTLP over http,
+/// when `otlp_endpoint` or `newrelic_api_key` is set.
+pub fn otlp_layer<S>(config: &Config) -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    let mut headers = config.otlp_headers.clone();
+    if let Some(key) = &config.newrelic_api_key {
+        headers.entry("api-key".to_string()).or_insert(key.clone());
+    }
+    let endpoint = match (&config.otlp_endpoint, &config.newrelic_api_key) {
+        (Some(endpoint), _) => endpoint.clone(),
+        (None, Some(_)) => config.newrelic_region.otlp_endpoint().to_string(),
+        (None, None) => return None,
+    };
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(endpoint)
+        .with_headers(headers)
+        .with_timeout(Duration::from_secs(3));
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+        config.otlp_sample_ratio,
+    )));
+    let trace_config = trace::config()
+        .with_sampler(sampler)
+        .with_resource(Resource::new(vec![KeyValue::new("service.name", "replex")]));
+
+    match opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace_config)
+        .install_batch(opentelemetry::runtime::Tokio)
+    {
+        Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+        Err(error) => {
+            // logging is not setup yet
+            eprintln!("Failed to setup the OTLP exporter: {}", error);
+            None
+        }
+    }
 }
\ No newline at end of file
@@ -1,58 +1,48 @@
-#[macro_use]
-extern crate tracing;
-
 use replex::config::Config;
+use replex::logging;
 use replex::routes::*;
 use salvo::prelude::*;
 use std::env;
-//use tonic::metadata::MetadataMap;
+use tracing_subscriber::filter;
 use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
 use tracing_subscriber::prelude::*;
 
 #[tokio::main]
 async fn main() {
-    let config: Config = Config::figment().extract().unwrap();
-    
+    let config = match Config::reload() {
+        Ok(config) => config,
+        Err(errors) => {
+            // logging is not setup yet, as that depends on the config
+            for error in errors {
+                eprintln!("Invalid configuration: {}", error);
+            }
+            std::process::exit(1);
+        }
+    };
+
     // set default log level
-    if let Err(i) = env::var("RUST_LOG") {
+    if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "info")
     }
 
-    let fmt_layer = tracing_subscriber::fmt::layer();
+    // the env filter only applies to the log output, so traces get
+    // the trace level spans without logging everything
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_filter(tracing_subscriber::EnvFilter::from_default_env());
     let console_layer = match config.enable_console {
         true => Some(console_subscriber::spawn()),
         false => None,
     };
-
-    // let otlp_layer = if config.newrelic_api_key.is_some() {
-    //     let mut map = MetadataMap::with_capacity(3);
-    //     map.insert(
-    //         "api-key",
-    //         config.newrelic_api_key.unwrap().parse().unwrap(),
-    //     );
-    //     let tracer = opentelemetry_otlp::new_pipeline()
-    //         .tracing()
-    //         .with_exporter(
-    //             opentelemetry_otlp::new_exporter()
-    //                 .tonic()
-    //                 .with_tls_config(Default::default())
-    //                 .with_endpoint(
-    //                     "https://otlp.eu01.nr-data.net:443/v1/traces",
-    //                 )
-    //                 .with_metadata(map)
-    //                 .with_timeout(Duration::from_secs(3)),
-    //         )
-    //         .install_batch(opentelemetry::runtime::Tokio)
-    //         .unwrap();
-    //     Some(tracing_opentelemetry::layer().with_tracer(tracer))
-    // } else {
-    //     None
-    // };
+    let otlp_layer = logging::otlp_layer(&config).map(|layer| {
+        layer.with_filter(filter::filter_fn(|meta| {
+            meta.is_span() && meta.target().starts_with("replex")
+        }))
+    });
 
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::from_default_env())
         .with(console_layer)
-        // .with(otlp_layer)
+        .with(otlp_layer)
         .with(fmt_layer)
         .init();
         
@@ -64,26 +54,20 @@ async fn main() {
         tracing::warn!("REPLEX_TOKEN not defined. Hero art might not load correctly.");
     }
 
-    // spawn our background task
-    // let mut plex_client = PlexClient::dummy();
-    // tokio::spawn(async move {
-    //     let mut interval = time::interval(Duration::from_secs(60));
-    //     loop {
-    //         interval.tick().await;
-    //         dbg!("we are being runned");
-    //     }
-    // });
+    replex::warmer::spawn();
     let version = env!("CARGO_PKG_VERSION");
     tracing::info!("Replex version {}", version);
     // dbg!(&config);
 
+    Config::watch();
+
     let router = route();
-    if config.ssl_enable && config.ssl_domain.is_some() {
+    if let (true, Some(ssl_domain)) = (config.ssl_enable, config.ssl_domain.clone()) {
         let acceptor =
             TcpListener::new(format!("0.0.0.0:{}", config.port.unwrap_or(443)))
                 .acme()
                 .cache_path("/data/acme/letsencrypt")
-                .add_domain(config.ssl_domain.unwrap())
+                .add_domain(ssl_domain)
                 .bind()
                 .await;
         Server::new(acceptor)
@@ -0,0 +1,320 @@
+use crate::cache::{CacheStats, GLOBAL_CACHE, RESPONSE_CACHE};
+use crate::models::MediaContainer;
+use crate::notify::NotifyEventKind;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use salvo::http::{Request, StatusCode};
+use std::time::Duration;
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("replex_requests_total", "Handled requests"),
+            &["route", "method", "status", "product"],
+        )
+        .unwrap(),
+    )
+});
+
+static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new("replex_request_duration_seconds", "Request duration"),
+            &["route", "product"],
+        )
+        .unwrap(),
+    )
+});
+
+static UPSTREAM_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "replex_upstream_duration_seconds",
+                "Duration of requests to plex",
+            ),
+            &["route", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+static CACHE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("replex_cache_requests_total", "Cache lookups"),
+            &["cache", "result"],
+        )
+        .unwrap(),
+    )
+});
+
+static CACHE_ENTRIES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new("replex_cache_entries", "Entries in the memory caches"),
+            &["cache"],
+        )
+        .unwrap(),
+    )
+});
+
+static PLAYBACK_DECISIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "replex_playback_decisions_total",
+                "Playback decisions of decision responses",
+            ),
+            &["decision"],
+        )
+        .unwrap(),
+    )
+});
+
+static PLAYBACK_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "replex_playback_events_total",
+                "Playback events, like transcode fallbacks",
+            ),
+            &["event"],
+        )
+        .unwrap(),
+    )
+});
+
+static MEMORY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new("replex_memory_bytes", "Memory used by the process"),
+            &["type"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Path with ids replaced, so routes do not get a series per item.
+/// Segments with a digit count as id, ex `/library/metadata/<id>/children`.
+pub fn route_label(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.chars().any(|c| c.is_ascii_digit()) {
+            true => "<id>",
+            false => segment,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Products with their own series, others are counted as `other`
+/// so clients cannot add series by sending any product.
+const KNOWN_PRODUCTS: &[&str] = &[
+    "Plex Web",
+    "Plex HTPC",
+    "Plex Media Player",
+    "Plex for Windows",
+    "Plex for Mac",
+    "Plex for Android (Mobile)",
+    "Plex for Android (TV)",
+    "Plex for iOS",
+    "Plex for Apple TV",
+    "Plex for Roku",
+    "Plex for LG",
+    "Plex for Samsung",
+    "Plex for Xbox",
+    "Plex for PlayStation",
+    "Plex for Fire TV",
+    "Plex for Vizio",
+    "Plexamp",
+];
+
+pub fn product_label(req: &Request) -> String {
+    let product = req
+        .header::<String>("X-Plex-Product")
+        .or_else(|| req.query::<String>("X-Plex-Product"))
+        .unwrap_or_default();
+    match KNOWN_PRODUCTS.iter().find(|p| p.eq_ignore_ascii_case(&product)) {
+        Some(known) => known.to_string(),
+        None if product.is_empty() => product,
+        None => "other".to_string(),
+    }
+}
+
+/// Route the request matched, the path with the matched params replaced by their
+/// name, ex `/hubs/sections/<id>`. Paths only matching the proxy fallback are `/<**rest>`.
+pub fn route_template(path: &str, params: &[(String, String)]) -> String {
+    let mut segments: Vec<String> = path.split('/').map(|s| s.to_string()).collect();
+    for (name, value) in params.iter() {
+        if value.is_empty() {
+            continue;
+        }
+        if name.starts_with('*') {
+            // the rest of the path, possibly with a literal part before it
+            let rest = value.trim_start_matches('/');
+            let joined = segments.join("/");
+            if let Some(prefix) = joined.strip_suffix(rest) {
+                segments = prefix.split('/').map(|s| s.to_string()).collect();
+                if let Some(last) = segments.last_mut() {
+                    last.push_str(&format!("<{}>", name));
+                }
+            }
+        } else if let Some(segment) = segments.iter_mut().rev().find(|s| *s == value) {
+            *segment = format!("<{}>", name);
+        }
+    }
+    segments.join("/")
+}
+
+/// Route, method and product are from before the handlers, as they can change the request.
+pub fn observe_request(
+    route: &str,
+    method: &str,
+    product: &str,
+    status: StatusCode,
+    duration: Duration,
+) {
+    REQUESTS
+        .with_label_values(&[route, method, status.as_str(), product])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[route, product])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn observe_upstream(path: &str, status: Option<StatusCode>, duration: Duration) {
+    let status = status.map(|s| s.as_str().to_string()).unwrap_or("error".to_string());
+    UPSTREAM_DURATION
+        .with_label_values(&[&route_label(path), &status])
+        .observe(duration.as_secs_f64());
+}
+
+/// Counts the decision plex made for a playback decision response.
+pub fn observe_playback_decision(container: &MediaContainer) {
+    // xml responses have video elements
+    let streams = container
+        .metadata
+        .first()
+        .or(container.video.first())
+        .and_then(|m| m.media.first())
+        .and_then(|m| m.parts.first())
+        .map(|p| p.streams.clone())
+        .unwrap_or_default();
+    let decided = |decision: &str| {
+        streams
+            .iter()
+            .any(|s| s.decision.as_deref() == Some(decision))
+    };
+    let decision = if decided("transcode") {
+        "transcode"
+    } else if decided("copy") {
+        "direct_stream"
+    } else {
+        "direct_play"
+    };
+    PLAYBACK_DECISIONS.with_label_values(&[decision]).inc();
+}
+
+pub fn observe_playback_event(event: NotifyEventKind) {
+    PLAYBACK_EVENTS
+        .with_label_values(&[&event.to_string()])
+        .inc();
+}
+
+fn set_cache_stats(cache: &str, stats: CacheStats) {
+    // the caches keep their own counters, catch up with them
+    for (result, value) in [("hit", stats.hits), ("miss", stats.misses)] {
+        let counter = CACHE_REQUESTS.with_label_values(&[cache, result]);
+        counter.inc_by(value.saturating_sub(counter.get()));
+    }
+    CACHE_ENTRIES
+        .with_label_values(&[cache])
+        .set(stats.entries as i64);
+}
+
+/// All metrics in the prometheus text format.
+pub async fn render() -> anyhow::Result<String> {
+    set_cache_stats("global", GLOBAL_CACHE.stats().await);
+    set_cache_stats("plex_client", crate::plex_client::cache_stats().await);
+    set_cache_stats("responses", RESPONSE_CACHE.stats().await);
+    if let Some(usage) = memory_stats::memory_stats() {
+        MEMORY
+            .with_label_values(&["physical"])
+            .set(usage.physical_mem as i64);
+        MEMORY
+            .with_label_values(&["virtual"])
+            .set(usage.virtual_mem as i64);
+    }
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_label() {
+        assert_eq!(route_label("/hubs/sections/1"), "/hubs/sections/<id>");
+        assert_eq!(
+            route_label("/replex/shelf/library/collections/1,2/children"),
+            "/replex/shelf/library/collections/<id>/children"
+        );
+        assert_eq!(route_label("/hubs/promoted"), "/hubs/promoted");
+    }
+
+    #[test]
+    fn test_route_template() {
+        let params = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        assert_eq!(
+            route_template("/hubs/sections/1", &params(&[("id", "1")])),
+            "/hubs/sections/<id>"
+        );
+        assert_eq!(
+            route_template(
+                "/replex/shelf/library/collections/1,2/children",
+                &params(&[("style", "shelf"), ("**rest", "library/collections/1,2/children")])
+            ),
+            "/replex/<style>/<**rest>"
+        );
+        assert_eq!(
+            route_template(
+                "/video/:/transcode/universal/start.m3u8",
+                &params(&[("colon", ":"), ("**rest", ".m3u8")])
+            ),
+            "/video/<colon>/transcode/universal/start<**rest>"
+        );
+        assert_eq!(
+            route_template("/library/parts/12/file.mkv", &params(&[("**rest", "library/parts/12/file.mkv")])),
+            "/<**rest>"
+        );
+        assert_eq!(route_template("/hubs/promoted", &params(&[])), "/hubs/promoted");
+    }
+
+    #[test]
+    fn test_product_label() {
+        let label = |product: &str| {
+            let req = salvo::test::TestClient::get("http://plex/hubs")
+                .add_header("X-Plex-Product", product, true)
+                .build();
+            product_label(&req)
+        };
+        assert_eq!(label("Plex for Android (TV)"), "Plex for Android (TV)");
+        assert_eq!(label("plex web"), "Plex Web");
+        assert_eq!(label("Some Client 1.2.3"), "other");
+    }
+}
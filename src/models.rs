@@ -8,6 +8,7 @@ use crate::config::*;
 use crate::plex_client::PlexClient;
 use crate::utils::*;
 use crate::headers;
+use crate::client_profile::{ClientCapabilities, ClientProfile, ProfileExtra};
 use anyhow::Result;
 use async_trait::async_trait;
 use serde_aux::prelude::{
@@ -130,6 +131,12 @@ pub struct PlexContext {
     pub screen_resolution_original: Option<String>,
     #[salvo(extract(rename = "X-Plex-Client-Capabilities", alias = "x-plex-client-capabilities"))]
     pub client_capabilities: Option<String>, 
+    #[serde(default)]
+    #[salvo(extract(rename = "X-Plex-Client-Capabilities", alias = "x-plex-client-capabilities"))]
+    pub capabilities: ClientCapabilities,
+    #[serde(default)]
+    #[salvo(extract(rename = "X-Plex-Client-Profile-Extra", alias = "x-plex-client-profile-extra"))]
+    pub profile_extra: ProfileExtra,
     #[salvo(extract(rename = "X-Plex-Product"))]
     pub product: Option<String>,
     #[salvo(extract(rename = "X-Plex-Version"))]
@@ -204,6 +211,16 @@ pub struct PlexContext {
     // pub style: Option<Style>,
 }
 
+impl PlexContext {
+    /// What the client says it can play, from its capabilities and profile extra.
+    pub fn client_profile(&self) -> ClientProfile {
+        ClientProfile {
+            capabilities: self.capabilities.clone(),
+            extra: self.profile_extra.clone(),
+        }
+    }
+}
+
 fn default_platform() -> Option<Platform> {
     Some(Platform::Generic)
 }
@@ -234,7 +251,7 @@ where
                 return Ok(None);
             }
             let r: Vec<i32> =
-                s.split(',').map(|s| s.parse().unwrap()).collect();
+                s.split(',').filter_map(|s| s.parse().ok()).collect();
             Ok(Some(r))
         }
         None => Ok(None),
@@ -285,13 +302,12 @@ where
                 .collect();
             let r: Vec<Resolution> = cleaned_string
                 .split(',')
-                .map(|s| {
-                    let k: Vec<i64> =
-                        s.split("x").map(|s| s.parse().unwrap()).collect();
-                    Resolution {
-                        width: k[0],
-                        height: k[1],
-                    }
+                .filter_map(|s| {
+                    let (width, height) = s.split_once('x')?;
+                    Some(Resolution {
+                        width: width.parse().ok()?,
+                        height: height.parse().ok()?,
+                    })
                 })
                 .collect();
             Ok(r)
@@ -876,7 +892,7 @@ pub struct Label {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     id: i64,
     #[yaserde(attribute)]
-    tag: String,
+    pub tag: String,
     #[yaserde(attribute)]
     filter: String,
 }
@@ -910,7 +926,7 @@ pub struct Context {
 //     }
 // }
 
-#[derive(Debug, Serialize, Deserialize, Clone, YaDeserialize, YaSerialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, YaDeserialize, YaSerialize)]
 #[cfg_attr(feature = "tests_deny_unknown_fields", serde(deny_unknown_fields))]
 #[serde(rename_all = "camelCase")]
 #[serde_as]
@@ -1278,6 +1294,12 @@ impl MetaData {
         !self.is_hub() && (self.r#type == "movie" || self.r#type == "show")
     }
 
+    /// The config of a hub injected by `VirtualHubTransform`
+    pub fn virtual_hub<'a>(&self, config: &'a Config) -> Option<&'a VirtualHub> {
+        let id = self.hub_identifier.as_ref()?.strip_prefix(VIRTUAL_HUB_PREFIX)?;
+        config.virtual_hubs.iter().find(|h| h.id == id)
+    }
+
     pub fn is_collection_hub(&self) -> bool {
         self.is_hub()
             && self.context.is_some()
@@ -1302,20 +1324,25 @@ impl MetaData {
         if !self.is_hub() {
             return Ok(false);
         }
-        let config: Config = Config::figment().extract().unwrap();
+        let config = Config::for_context(&plex_client.context);
         // dbg!(&config.hero_rows);
         if config.hero_rows.is_some() && self.hub_identifier.is_some() {
             let id = self.hub_identifier.clone().unwrap();
-            for row in config.hero_rows.unwrap() {
+            for row in config.hero_rows.clone().unwrap() {
                 if !row.is_empty() && id.contains(&row) {
                     return Ok(true);
                 }
             }
         }
+        if let Some(hub) = self.virtual_hub(&config) {
+            return Ok(hub.style == Some(Style::Hero));
+        }
         if !self.is_collection_hub() {
             return Ok(false);
         }
-        let collection_id = get_collection_id_from_hub(self);
+        let Some(collection_id) = get_collection_id_from_hub(self) else {
+            return Ok(false);
+        };
         let mut collection_details = plex_client
             .clone()
             .get_cached(
@@ -1327,11 +1354,15 @@ impl MetaData {
         Ok(collection_details
             .media_container
             .children()
-            .get(0)
-            .unwrap()
-            .has_label("REPLEXHERO".to_string()))
+            .first()
+            .is_some_and(|c| c.has_label("REPLEXHERO".to_string())))
     }
     
+    /// Key to find the same item in different hubs or libraries
+    pub fn dedupe_key(&self) -> Option<String> {
+        self.guid.clone().or_else(|| self.rating_key.clone())
+    }
+
     // view_count stays for show even when marked unwatched. 
     pub fn is_watched(&self) -> bool {
         // movie or episode
@@ -1354,18 +1385,19 @@ impl MetaData {
         &self,
         plex_client: PlexClient,
     ) -> Result<bool> {
-        let config: Config = Config::figment().extract().unwrap();
+        let config = Config::for_context(&plex_client.context);
         if !self.is_collection_hub() {
             return Ok(config.exclude_watched);
         }
-        
+        let Some(collection_id) = get_collection_id_from_hub(self) else {
+            return Ok(config.exclude_watched);
+        };
+
         let collection = plex_client
             .clone()
             .get_cached(
-                plex_client
-                    .get_collection(get_collection_id_from_hub(self) as i32),
-                format!("collection:{}", get_collection_id_from_hub(self))
-                    .to_string(),
+                plex_client.get_collection(collection_id),
+                format!("collection:{}", collection_id),
             )
             .await?;
 
@@ -1373,9 +1405,8 @@ impl MetaData {
             || collection
                 .media_container
                 .metadata
-                .get(0)
-                .unwrap()
-                .has_label("REPLEX_EXCLUDE_WATCHED".to_string()))
+                .first()
+                .is_some_and(|c| c.has_label("REPLEX_EXCLUDE_WATCHED".to_string())))
     }
 
     // TODO: Does not work when using a new instance
@@ -1664,15 +1695,14 @@ impl MediaContainer {
         !self.hub.is_empty()
     }
 
-    pub fn exclude_watched(&self) -> bool {
-        let config: Config = Config::figment().extract().unwrap();
+    pub fn exclude_watched(&self, context: &PlexContext) -> bool {
+        let config = Config::for_context(context);
 
-        return config.exclude_watched
+        config.exclude_watched
             || self
                 .metadata
-                .get(0)
-                .unwrap()
-                .has_label("REPLEX_EXCLUDE_WATCHED".to_string());
+                .first()
+                .is_some_and(|c| c.has_label("REPLEX_EXCLUDE_WATCHED".to_string()))
     }
 
     pub fn set_type(&mut self, value: String) {
@@ -0,0 +1,177 @@
+use crate::config::{Config, NotificationTarget};
+use crate::models::{MediaContainer, PlexContext};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use strum_macros::Display as EnumDisplay;
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap()
+});
+
+/// Delay before the first retry, doubled on every next one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Playback decisions replex sends to the `notifications` targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumDisplay, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum NotifyEventKind {
+    /// plex decided how to play an item, ex transcode
+    PlaybackDecision,
+    /// another version was selected because the requested one transcodes
+    VideoTranscodeFallback,
+    /// direct play failed and is retried as direct stream
+    DirectStreamFallback,
+    /// a playback policy refused playback
+    PlaybackDenied,
+}
+
+/// Normalized event, posted as json.
+#[derive(Debug, Clone, Serialize)]
+pub struct NotifyEvent {
+    pub event: NotifyEventKind,
+    /// unix time in seconds
+    pub timestamp: u64,
+    pub username: Option<String>,
+    pub product: Option<String>,
+    pub platform: Option<String>,
+    pub device_name: Option<String>,
+    pub client_identifier: Option<String>,
+    /// event specific values
+    pub details: serde_json::Value,
+}
+
+impl NotifyEvent {
+    pub fn new(
+        event: NotifyEventKind,
+        context: &PlexContext,
+        details: serde_json::Value,
+    ) -> Self {
+        Self {
+            event,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            username: context.username.clone(),
+            product: context.product.clone(),
+            platform: context.platform.as_ref().map(|p| p.to_string()),
+            device_name: context.device_name.clone(),
+            client_identifier: context.client_identifier.clone(),
+            details,
+        }
+    }
+
+    /// The decision plex sent to the client.
+    pub fn playback_decision(context: &PlexContext, container: &MediaContainer) -> Self {
+        Self::new(
+            NotifyEventKind::PlaybackDecision,
+            context,
+            serde_json::json!({
+                "path": context.path,
+                "general_decision_code": container.general_decision_code,
+                "general_decision_text": container.general_decision_text,
+                "transcode_decision_code": container.transcode_decision_code,
+                "transcode_decision_text": container.transcode_decision_text,
+            }),
+        )
+    }
+}
+
+impl NotificationTarget {
+    fn accepts(&self, event: NotifyEventKind) -> bool {
+        self.events.as_ref().map_or(true, |events| events.contains(&event))
+    }
+}
+
+/// Send the event to every target that accepts it. Runs in the background
+/// so playback requests do not wait on the targets.
+pub fn notify(event: NotifyEvent) {
+    crate::metrics::observe_playback_event(event.event);
+    let config = Config::current();
+    for target in config.notifications.iter().filter(|t| t.accepts(event.event)) {
+        let target = target.clone();
+        let event = event.clone();
+        tokio::spawn(async move {
+            if let Err(error) = send(&target, &event).await {
+                tracing::error!(
+                    url = %target.url,
+                    event = %event.event,
+                    error = %error,
+                    "Failed to send notification"
+                );
+            }
+        });
+    }
+}
+
+async fn send(target: &NotificationTarget, event: &NotifyEvent) -> anyhow::Result<()> {
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        let mut req = HTTP_CLIENT.post(&target.url).json(event);
+        for (name, value) in &target.headers {
+            req = req.header(name, value);
+        }
+        let error = match req.send().await {
+            Ok(res) if res.status().is_success() => return Ok(()),
+            Ok(res) => anyhow::anyhow!("target responded with {}", res.status()),
+            Err(error) => error.into(),
+        };
+
+        if attempt >= target.retries {
+            return Err(error);
+        }
+        attempt += 1;
+        tracing::debug!(url = %target.url, attempt, error = %error, "Retrying notification");
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playback_decision_event() {
+        let context = PlexContext {
+            path: Some("/video/:/transcode/universal/decision".to_string()),
+            product: Some("Plex Web".to_string()),
+            ..PlexContext::default()
+        };
+        let container = MediaContainer {
+            general_decision_code: Some(1000),
+            transcode_decision_code: Some(1001),
+            transcode_decision_text: Some("Direct play not available".to_string()),
+            ..MediaContainer::default()
+        };
+        let event = NotifyEvent::playback_decision(&context, &container);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "playback_decision");
+        assert_eq!(json["product"], "Plex Web");
+        assert_eq!(json["details"]["path"], "/video/:/transcode/universal/decision");
+        assert_eq!(json["details"]["general_decision_code"], 1000);
+        assert_eq!(json["details"]["transcode_decision_code"], 1001);
+        assert_eq!(json["details"]["transcode_decision_text"], "Direct play not available");
+    }
+
+    #[test]
+    fn test_target_accepts() {
+        let mut target = NotificationTarget {
+            url: "http://localhost/hook".to_string(),
+            events: None,
+            headers: Default::default(),
+            retries: 0,
+        };
+        assert!(target.accepts(NotifyEventKind::PlaybackDenied));
+
+        target.events = Some(vec![NotifyEventKind::PlaybackDecision]);
+        assert!(target.accepts(NotifyEventKind::PlaybackDecision));
+        assert!(!target.accepts(NotifyEventKind::PlaybackDenied));
+    }
+}
@@ -6,9 +6,12 @@ use crate::utils::*;
 use anyhow::Result;
 use std::collections::HashMap;
 
-use crate::cache::GLOBAL_CACHE;
-use async_recursion::async_recursion;
+use crate::cache::{
+    invalidate_disk_prefixes, key_matches, CacheCounters, CacheStats, Expiration,
+    DISK_CACHE, GLOBAL_CACHE,
+};
 use futures_util::Future;
+use tracing::Instrument;
 use futures_util::TryStreamExt;
 use http::header::ACCEPT_LANGUAGE;
 use http::header::CONNECTION;
@@ -27,22 +30,275 @@ use reqwest_retry::{
     default_on_request_failure, Retryable, RetryableStrategy,
 };
 use salvo::Error;
+use serde::{Deserialize, Serialize};
 use salvo::Request;
 // use hyper::client::HttpConnector;
 
 
 static CACHE: Lazy<Cache<String, MediaContainerWrapper<MediaContainer>>> =
     Lazy::new(|| {
-        let c: Config = Config::figment().extract().unwrap();
         Cache::builder()
             .max_capacity(10000)
-            .time_to_live(Duration::from_secs(c.cache_ttl))
+            .expire_after(CacheTtl)
+            .support_invalidation_closures()
             .eviction_listener(|key, value, cause| {
                 //println!("Evicted ({key:?},{value:?}) because {cause:?}")
             })
             .build()
     });
 
+static CACHE_COUNTERS: CacheCounters = CacheCounters::new();
+
+/// Entries live for the `cache_ttl` of the config they are cached with,
+/// so reloads apply to new entries.
+struct CacheTtl;
+
+impl moka::Expiry<String, MediaContainerWrapper<MediaContainer>> for CacheTtl {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        _value: &MediaContainerWrapper<MediaContainer>,
+        _current_time: std::time::Instant,
+    ) -> Option<Duration> {
+        Some(Duration::from_secs(Config::current().cache_ttl))
+    }
+}
+
+/// Plex account ids by (hashed) token. Every device of a user has its own token,
+/// failed lookups are kept as well so plex.tv is not asked on every request.
+pub(crate) static USER_IDS: Lazy<Cache<String, Option<i64>>> = Lazy::new(|| {
+    Cache::builder()
+        .max_capacity(10000)
+        .time_to_live(Duration::from_secs(60 * 60))
+        .build()
+});
+
+static PLEX_TV_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap_or_default()
+});
+
+/// Plex account id of the token, or none when plex.tv does not know it.
+pub(crate) async fn user_id(token: &str) -> Option<i64> {
+    USER_IDS
+        .get_with(hash_token(token), async {
+            match load_user(token).await {
+                Ok(user) => Some(user.id),
+                Err(error) => {
+                    tracing::warn!(error = %error, "Failed to load plex user of token");
+                    None
+                }
+            }
+        })
+        .await
+}
+
+/// Plex account id of the token when already loaded. Otherwise loads it
+/// in the background, so callers do not wait on plex.tv.
+pub(crate) async fn known_user_id(token: &str) -> Option<i64> {
+    if let Some(id) = USER_IDS.get(&hash_token(token)).await {
+        return id;
+    }
+    let token = token.to_string();
+    tokio::spawn(async move { user_id(&token).await });
+    None
+}
+
+async fn load_user(token: &str) -> Result<PlexUser> {
+    let res = PLEX_TV_CLIENT
+        .get("https://clients.plex.tv/api/v2/user")
+        .header(ACCEPT, "application/json")
+        .header("X-Plex-Token", token)
+        .header("X-Plex-Client-Identifier", "replex")
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(res.json().await?)
+}
+
+fn upstream_span(req: &Request) -> tracing::Span {
+    tracing::trace_span!(
+        "plex_request",
+        otel.name = %format!("plex {} {}", req.method(), crate::metrics::route_label(req.uri().path())),
+        otel.kind = "client",
+        http.method = %req.method(),
+        http.target = %req.uri().path(),
+    )
+}
+
+/// Invalidate all cached responses with a key matching one of the prefixes, see `key_matches`.
+pub(crate) fn invalidate_cache_prefixes(prefixes: Vec<String>) -> anyhow::Result<()> {
+    invalidate_disk_prefixes(prefixes.iter().map(|p| disk_cache_prefix(p)).collect());
+    CACHE.invalidate_entries_if(move |key, _| {
+        prefixes.iter().any(|p| key_matches(p, key))
+    })?;
+    Ok(())
+}
+
+pub(crate) async fn cache_stats() -> CacheStats {
+    CACHE_COUNTERS.stats(&CACHE).await
+}
+
+/// Disk cache key, separate from the `GLOBAL_CACHE` keys sharing the directory.
+/// Keys end with the token, which is hashed so it is never written to disk.
+fn disk_cache_key(cache_key: &str) -> String {
+    match cache_key.rsplit_once(':') {
+        Some((name, token)) => format!("plexclient:{}:{}", name, hash_token(token)),
+        None => disk_cache_prefix(cache_key),
+    }
+}
+
+/// Prefixes stop before the token, so they match the disk keys as they are.
+fn disk_cache_prefix(prefix: &str) -> String {
+    format!("plexclient:{}", prefix)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    data_encoding::HEXLOWER.encode(&openssl::sha::sha256(token.as_bytes()))
+}
+
+/// Where the upstream pages of a collection start in the (unwatched) children,
+/// so a window can start loading at the page holding its offset.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CollectionCursor {
+    /// children before upstream page `i`, as far as loaded
+    pub pages: Vec<i32>,
+    /// amount of children, once the last page is loaded
+    pub total: Option<i32>,
+}
+
+impl CollectionCursor {
+    /// Last known page starting at or before `offset`, with the children before it.
+    fn seek(&self, offset: i32) -> (usize, i32) {
+        match self.pages.iter().rposition(|before| *before <= offset) {
+            Some(page) => (page, self.pages[page]),
+            None => (0, 0),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct CollectionWindow {
+    pub children: Vec<MetaData>,
+    /// amount of children in the collection, when the window reached its end
+    pub total_size: Option<i32>,
+}
+
+/// Children `offset..offset + count`, loading the upstream page starting at
+/// a given item with `load_page`. Pages are read from the cursor on, and the
+/// cursor learns the starts of the pages read.
+async fn load_window<F, Fut>(
+    cursor: &mut CollectionCursor,
+    offset: i32,
+    count: i32,
+    exclude_watched: bool,
+    load_page: F,
+) -> Result<CollectionWindow>
+where
+    F: Fn(i32) -> Fut,
+    Fut: Future<Output = Result<MediaContainerWrapper<MediaContainer>>>,
+{
+    let offset = offset.max(0);
+    let end = offset + count.max(0);
+    let (mut page, mut seen) = cursor.seek(offset);
+    let mut children = vec![];
+    while seen < end {
+        let mut container = load_page(page as i32 * COLLECTION_PAGE_SIZE).await?;
+        let total_size = container.media_container.total_size;
+        let mut items = container.media_container.children();
+        let fetched = items.len() as i32;
+        if exclude_watched {
+            items.retain(|x| !x.is_watched());
+        }
+
+        let skip = (offset - seen).clamp(0, items.len() as i32);
+        seen += items.len() as i32;
+        children.extend(items.into_iter().skip(skip as usize));
+        if cursor.pages.is_empty() {
+            cursor.pages.push(0);
+        }
+
+        page += 1;
+        if fetched < COLLECTION_PAGE_SIZE
+            || total_size.is_some_and(|total| page as i32 * COLLECTION_PAGE_SIZE >= total)
+        {
+            cursor.total = Some(seen);
+            break;
+        }
+        match cursor.pages.get_mut(page) {
+            Some(before) => *before = seen,
+            None => cursor.pages.push(seen),
+        }
+    }
+    children.truncate(count.max(0) as usize);
+    Ok(CollectionWindow {
+        children,
+        total_size: cursor.total,
+    })
+}
+
+/// Failures talking to plex. The client methods return them as `anyhow::Error`,
+/// use `UpstreamError::from_error` to get them back.
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    #[error("plex is unreachable: {0}")]
+    Unreachable(String),
+    #[error("plex rejected the token")]
+    Unauthorized,
+    #[error("not found on plex")]
+    NotFound,
+    #[error("plex responded with {0}")]
+    Status(http::StatusCode),
+    #[error("invalid response from plex: {0}")]
+    InvalidResponse(String),
+}
+
+impl UpstreamError {
+    /// Error for an unsuccessful status, none when it succeeded.
+    pub fn from_status(status: http::StatusCode) -> Option<Self> {
+        match status {
+            s if s.is_success() => None,
+            http::StatusCode::UNAUTHORIZED | http::StatusCode::FORBIDDEN => {
+                Some(Self::Unauthorized)
+            }
+            http::StatusCode::NOT_FOUND => Some(Self::NotFound),
+            s => Some(Self::Status(s)),
+        }
+    }
+
+    pub fn from_error(error: &anyhow::Error) -> Option<&Self> {
+        error.downcast_ref::<Self>()
+    }
+
+    /// Status to respond with to the client.
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            Self::Unauthorized => http::StatusCode::UNAUTHORIZED,
+            Self::NotFound => http::StatusCode::NOT_FOUND,
+            _ => http::StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// Media container of a plex response, or the `UpstreamError` why there is none.
+pub(crate) async fn read_container(
+    res: reqwest::Response,
+) -> Result<MediaContainerWrapper<MediaContainer>> {
+    if let Some(error) = UpstreamError::from_status(res.status()) {
+        return Err(error.into());
+    }
+    let bytes = res
+        .bytes()
+        .await
+        .map_err(|e| UpstreamError::InvalidResponse(e.to_string()))?;
+    from_bytes(bytes).map_err(|e| UpstreamError::InvalidResponse(e.to_string()).into())
+}
+
+/// Upstream page size when loading collection children.
+pub(crate) const COLLECTION_PAGE_SIZE: i32 = 100;
+
 struct Retry401;
 impl RetryableStrategy for Retry401 {
     fn handle(
@@ -99,15 +355,70 @@ impl PlexClient {
         //headers.remove(ACCEPT); // remove accept as we always do json request
         //dbg!(&headers);
         //dbg!(&url);
+        let start = std::time::Instant::now();
         let res = self
             .http_client
             .request(req.method().clone(), url)
             .headers(headers)
             .send()
+            .instrument(upstream_span(req))
+            .await;
+        crate::metrics::observe_upstream(
+            req.uri().path(),
+            res.as_ref().ok().map(|r| r.status()),
+            start.elapsed(),
+        );
+        if let Ok(res) = &res {
+            crate::access_log::record_upstream(res.status().as_u16());
+        }
+
+        res.map_err(Error::other)
+    }
+
+    /// Get a media container, failing with an `UpstreamError` when plex does not return one.
+    pub async fn get_container(
+        &self,
+        path: String,
+    ) -> Result<MediaContainerWrapper<MediaContainer>> {
+        let res = self
+            .get(path)
             .await
-            .map_err(Error::other)?;
+            .map_err(|e| UpstreamError::Unreachable(e.to_string()))?;
+        read_container(res).await
+    }
 
-        Ok(res)
+    /// Select the audio and subtitle streams of a part for the user of the token,
+    /// a subtitle stream of `0` turns subtitles off.
+    pub async fn select_streams(
+        &self,
+        part_id: i64,
+        audio_stream_id: Option<i64>,
+        subtitle_stream_id: Option<i64>,
+    ) -> Result<()> {
+        let mut query = vec![];
+        if let Some(id) = audio_stream_id {
+            query.push(format!("audioStreamID={}", id));
+        }
+        if let Some(id) = subtitle_stream_id {
+            query.push(format!("subtitleStreamID={}", id));
+        }
+        query.push("allParts=1".to_string());
+
+        let mut req = Request::default();
+        *req.method_mut() = http::Method::PUT;
+        req.set_uri(
+            Uri::builder()
+                .path_and_query(format!("/library/parts/{}?{}", part_id, query.join("&")))
+                .build()?,
+        );
+        let res = self
+            .request(&req)
+            .await
+            .map_err(|e| UpstreamError::Unreachable(e.to_string()))?;
+        match UpstreamError::from_status(res.status()) {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
     }
 
     pub async fn proxy_request(
@@ -126,33 +437,33 @@ impl PlexClient {
         let mut headers = req.headers().clone();
         headers.remove(ACCEPT); // remove accept as we always do json request
         headers.remove(http::header::HOST);
+        let start = std::time::Instant::now();
         let res = self
             .http_client
             .request(req.method().clone(), url)
             //.execute(req)
             .headers(headers)
             .send()
-            .await
-            .map_err(Error::other)?;
+            .instrument(upstream_span(req))
+            .await;
+        crate::metrics::observe_upstream(
+            req.uri().path(),
+            res.as_ref().ok().map(|r| r.status()),
+            start.elapsed(),
+        );
+        if let Ok(res) = &res {
+            crate::access_log::record_upstream(res.status().as_u16());
+        }
         //dbg!(&res);
-        Ok(res)
+        res.map_err(Error::other)
      }
 
     pub async fn get_section_collections(
         &self,
         id: i64,
     ) -> Result<MediaContainerWrapper<MediaContainer>> {
-        let res = self
-            .get(format!("/library/sections/{}/collections", id))
+        self.get_container(format!("/library/sections/{}/collections", id))
             .await
-            .unwrap();
-
-        let container: MediaContainerWrapper<MediaContainer> =
-            from_reqwest_response(res)
-                .await
-                .expect("Cannot get MediaContainerWrapper from response");
-
-        Ok(container)
     }
 
     pub async fn get_collection_children(
@@ -176,52 +487,99 @@ impl PlexClient {
         path = format!("{}&includeGuids=1", path);
         // dbg!(&path);
 
-        let res = self.get(path).await.unwrap();
-        if !res.status().is_success() {
-            return Err(anyhow::anyhow!(format!(
-                "unexpected status code: status = {}",
-                res.status()
-            )));
-        }
-        
-        let container: MediaContainerWrapper<MediaContainer> =
-            from_reqwest_response(res).await.unwrap();
-        Ok(container)
+        self.get_container(path).await
     }
 
-    #[async_recursion]
-    pub async fn load_collection_children_recursive(
+    /// Load children `offset..offset + count` of a collection, skipping watched items when `exclude_watched` is set.
+    /// Starts at the upstream page holding the offset, as far as earlier windows got, and keeps
+    /// fetching pages until the window is filled or the collection is exhausted.
+    pub async fn load_collection_children(
         &self,
         id: i64,
         offset: i32,
-        limit: i32,
-        original_limit: i32,
-    ) -> anyhow::Result<MediaContainerWrapper<MediaContainer>> {
-        let config: Config = Config::figment().extract().unwrap();
-        let mut c = self
-            .get_collection_children(id, Some(offset), Some(limit))
-            .await?;
-        c.media_container.children_mut().retain(|x| !x.is_watched());
-        c.media_container
-            .children_mut()
-            .truncate(original_limit as usize);
+        count: i32,
+        exclude_watched: bool,
+    ) -> anyhow::Result<CollectionWindow> {
+        let token = self.context.token.clone().unwrap_or_default();
+        // ends with a hash instead of the token as this cache is written to disk,
+        // the prefix still matches the invalidations of the collection
+        let cursor_key = format!(
+            "get_collection_children:{}:cursor:{}:{}",
+            id,
+            exclude_watched,
+            hash_token(&token)
+        );
+        let mut cursor: CollectionCursor =
+            GLOBAL_CACHE.get(&cursor_key).await.unwrap_or_default();
+
+        let window = load_window(&mut cursor, offset, count, exclude_watched, |start| {
+            let client = self.clone();
+            async move {
+                // fixed pages so they are cached for the next window
+                let mut page = client
+                    .clone()
+                    .get_cached(
+                        client.get_collection_children(
+                            id,
+                            Some(start),
+                            Some(COLLECTION_PAGE_SIZE),
+                        ),
+                        format!(
+                            "get_collection_children:{}:{}:{}",
+                            id, start, COLLECTION_PAGE_SIZE
+                        ),
+                    )
+                    .await?;
+                crate::webhooks::record_collection(id, &page.media_container.children());
+                Ok(page)
+            }
+        })
+        .await?;
 
-        Ok(c)
+        if let Err(error) = GLOBAL_CACHE
+            .insert(cursor_key, cursor, Expiration::Global)
+            .await
+        {
+            tracing::warn!(collection = id, error = %error, "Failed to cache collection cursor");
+        }
+        Ok(window)
+    }
+
+    /// Amount of (unwatched when `exclude_watched` is set) items in the collection.
+    pub async fn get_collection_total_size(
+        &self,
+        collection: &MetaData,
+        section_id: Option<i64>,
+        exclude_watched: bool,
+    ) -> Option<i32> {
+        if !exclude_watched {
+            return collection.child_count.as_ref()?.parse().ok();
+        }
+
+        let section_id = collection.library_section_id.or(section_id)?;
+        let index = collection.index?;
+        let r#type = collection.subtype.clone()?;
+        let rating_key = collection.rating_key.clone()?;
+        self.clone()
+            .get_cached(
+                self.get_collection_total_size_unwatched(
+                    section_id as i32,
+                    index,
+                    r#type,
+                ),
+                format!("collectiontotalunwatched:{}", rating_key),
+            )
+            .await
+            .ok()?
+            .media_container
+            .total_size
     }
 
     pub async fn get_collection(
         &self,
         id: i32,
     ) -> Result<MediaContainerWrapper<MediaContainer>> {
-        let res = self.get(format!("/library/collections/{}", id)).await?;
-
-        if res.status() == 404 {
-            return Err(salvo::http::StatusError::not_found().into());
-        }
-
-        let container: MediaContainerWrapper<MediaContainer> =
-            from_reqwest_response(res).await.unwrap();
-        Ok(container)
+        self.get_container(format!("/library/collections/{}", id)).await
     }
 
     // theres actually a global endpoint https://plex.sjoerdarendsen.dev/library/all?show.collection=2042780&collection=2042780&X-Plex-Container-Start=0&X-Plex-Container-Size=72
@@ -248,35 +606,32 @@ impl PlexClient {
             );
         }
         // dbg!(&path);
-        let res = self.get(path).await?;
-
-        if res.status() == 404 {
-            return Err(salvo::http::StatusError::not_found().into());
-        }
+        self.get_container(path).await
+    }
 
-        let container: MediaContainerWrapper<MediaContainer> =
-            from_reqwest_response(res).await.unwrap();
-        Ok(container)
+    pub async fn get_section_size(
+        &self,
+        section_id: i64,
+    ) -> Result<MediaContainerWrapper<MediaContainer>> {
+        self.get_container(format!(
+            "/library/sections/{}/all?X-Plex-Container-Start=0&X-Plex-Container-Size=0",
+            section_id
+        ))
+        .await
     }
 
     pub async fn get_hubs(
         &self,
         id: i32,
     ) -> Result<MediaContainerWrapper<MediaContainer>> {
-        let res = self.get("/hubs".to_string()).await.unwrap();
-        let container: MediaContainerWrapper<MediaContainer> =
-            from_reqwest_response(res).await.unwrap();
-        Ok(container)
+        self.get_container("/hubs".to_string()).await
     }
 
     pub async fn get_item_by_key(
         self,
         key: String,
     ) -> Result<MediaContainerWrapper<MediaContainer>> {
-        let res = self.get(key).await.unwrap();
-        let container: MediaContainerWrapper<MediaContainer> =
-            from_reqwest_response(res).await.unwrap();
-        Ok(container)
+        self.get_container(key).await
     }
 
     pub async fn get_cached(
@@ -295,6 +650,18 @@ impl PlexClient {
         Ok(r)
     }
 
+    /// Like `get_cached`, but always loads and replaces the cached value.
+    pub async fn refresh_cached(
+        self,
+        f: impl Future<Output = Result<MediaContainerWrapper<MediaContainer>>>,
+        name: String,
+    ) -> Result<MediaContainerWrapper<MediaContainer>> {
+        let cache_key = self.generate_cache_key(name);
+        let r = f.await?;
+        self.insert_cache(cache_key, r.clone()).await;
+        Ok(r)
+    }
+
     pub async fn get_hero_art(
         self,
         uuid: String,
@@ -355,7 +722,7 @@ impl PlexClient {
         self,
         uuid: &String,
     ) -> Result<MediaContainerWrapper<MediaContainer>> {
-        let config: Config = Config::figment().extract().unwrap();
+        let config = Config::current();
         let url = format!(
             "https://metadata.provider.plex.tv/library/metadata/{}",
             uuid
@@ -383,25 +750,38 @@ impl PlexClient {
         );
         *req.headers_mut() = headers;
 
-        let res = self.http_client.execute(req).await.map_err(Error::other)?;
-
-        if res.status() != salvo::http::StatusCode::OK {
-            return Err(anyhow::anyhow!(format!(
-                "unexpected status code: status = {}",
-                res.status()
-            )));
-        }
-
-        let container: MediaContainerWrapper<MediaContainer> =
-            from_reqwest_response(res).await?;
-        Ok(container)
+        let res = self
+            .http_client
+            .execute(req)
+            .await
+            .map_err(|e| UpstreamError::Unreachable(e.to_string()))?;
+        read_container(res).await
     }
 
     async fn get_cache(
         &self,
         cache_key: &str,
     ) -> Result<Option<MediaContainerWrapper<MediaContainer>>> {
-        Ok(self.cache.get(cache_key).await)
+        if let Some(container) = self.cache.get(cache_key).await {
+            CACHE_COUNTERS.hit();
+            return Ok(Some(container));
+        }
+        let Some(disk) = DISK_CACHE.as_ref() else {
+            CACHE_COUNTERS.miss();
+            return Ok(None);
+        };
+        // json, bincode cannot read the skipped optional fields back
+        let container: Option<MediaContainerWrapper<MediaContainer>> = disk
+            .get(&disk_cache_key(cache_key))
+            .await
+            .and_then(|(_, data)| serde_json::from_slice(&data).ok());
+        if let Some(container) = &container {
+            self.cache
+                .insert(cache_key.to_string(), container.clone())
+                .await;
+        }
+        CACHE_COUNTERS.record(container.is_some());
+        Ok(container)
     }
 
     async fn insert_cache(
@@ -409,19 +789,35 @@ impl PlexClient {
         cache_key: String,
         container: MediaContainerWrapper<MediaContainer>,
     ) {
+        if let Some(disk) = DISK_CACHE.as_ref() {
+            let result = match serde_json::to_vec(&container) {
+                Ok(data) => {
+                    disk.insert(disk_cache_key(&cache_key), data, Expiration::Global)
+                        .await
+                }
+                Err(error) => Err(error.into()),
+            };
+            if let Err(error) = result {
+                tracing::warn!(key = %cache_key, error = %error, "Failed to write disk cache");
+            }
+        }
         self.cache.insert(cache_key, container).await;
     }
 
     fn generate_cache_key(&self, name: String) -> String {
-        format!("{}:{}", name, self.context.token.clone().unwrap())
+        format!("{}:{}", name, self.context.token.clone().unwrap_or_default())
     }
 
-    pub fn from_context(context: &PlexContext) -> Self {
-        let config: Config = Config::figment().extract().unwrap();
-        let token = context
-            .clone()
-            .token
-            .expect("Expected to have an token in header or query");
+    /// Client with the token and headers of the request, fails with an `UpstreamError`
+    /// without a token or when no plex host is configured.
+    pub fn from_context(context: &PlexContext) -> Result<Self> {
+        let config = Config::current();
+        if context.token.is_none() {
+            return Err(UpstreamError::Unauthorized.into());
+        }
+        let Some(host) = config.host.clone() else {
+            return Err(UpstreamError::Unreachable("no plex host configured".to_string()).into());
+        };
         let client_identifier = context.clone().client_identifier;
         let platform = context.platform.clone().unwrap_or_default();
 
@@ -455,8 +851,9 @@ impl PlexClient {
         ]);
         
         for (key, val) in headers_map {
-            if val.is_some() {
-              headers.insert(key.clone(), val.unwrap().as_str().parse().unwrap());
+            // skip values that are not valid in a header instead of failing the request
+            if let Some(value) = val.and_then(|v| v.parse().ok()) {
+              headers.insert(key, value);
             }
         }
         
@@ -468,28 +865,27 @@ impl PlexClient {
         //    header::HeaderValue::from_str(&target_host).unwrap(),
         //);
 
-        Self {
-            http_client: reqwest_middleware::ClientBuilder::new(
-                reqwest::Client::builder()
-                    //.default_headers(headers)
-                    .gzip(true)
-                    .timeout(Duration::from_secs(30))
-                    .build()
-                    .unwrap(),
-            )
-            .build(),
+        let http_client = reqwest::Client::builder()
+            //.default_headers(headers)
+            .gzip(true)
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| UpstreamError::Unreachable(e.to_string()))?;
+
+        Ok(Self {
+            http_client: reqwest_middleware::ClientBuilder::new(http_client).build(),
             default_headers: headers,
-            host: config.host.unwrap(),
+            host,
             context: context.clone(),
             //x_plex_token: token,
             //x_plex_client_identifier: client_identifier,
             //x_plex_platform: platform,
             cache: CACHE.clone(),
-        }
+        })
     }
 
     // pub fn dummy() -> Self {
-    //     let config: Config = Config::figment().extract().unwrap();
+    //     let config = Config::current();
     //     let token = "DUMMY".to_string();
     //     let client_identifier: Option<String> = None;
     //     let platform: Platform = Platform::Generic;
@@ -544,3 +940,125 @@ impl PlexClient {
 //         assert_eq!(content, "Hello World");
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_error_from_status() {
+        assert!(UpstreamError::from_status(http::StatusCode::OK).is_none());
+        let error = UpstreamError::from_status(http::StatusCode::FORBIDDEN).unwrap();
+        assert_eq!(error.status(), http::StatusCode::UNAUTHORIZED);
+        let error = UpstreamError::from_status(http::StatusCode::NOT_FOUND).unwrap();
+        assert_eq!(error.status(), http::StatusCode::NOT_FOUND);
+        let error: anyhow::Error = UpstreamError::from_status(http::StatusCode::SERVICE_UNAVAILABLE)
+            .unwrap()
+            .into();
+        assert!(matches!(
+            UpstreamError::from_error(&error),
+            Some(UpstreamError::Status(http::StatusCode::SERVICE_UNAVAILABLE))
+        ));
+    }
+
+    #[test]
+    fn test_from_context_without_token() {
+        let error = PlexClient::from_context(&PlexContext::default()).unwrap_err();
+        assert!(matches!(
+            UpstreamError::from_error(&error),
+            Some(UpstreamError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn test_disk_cache_key() {
+        let key = disk_cache_key("get_collection_children:1:secret-token");
+        assert!(!key.contains("secret-token"));
+        assert_eq!(key, disk_cache_key("get_collection_children:1:secret-token"));
+        assert!(key_matches(&disk_cache_prefix("get_collection_children:1:"), &key));
+        assert_ne!(key, disk_cache_key("get_collection_children:1:other-token"));
+    }
+
+    /// Children `0..size` where the multiples of `watched_every` are watched.
+    fn collection(size: i32, watched_every: i32) -> Vec<MetaData> {
+        (0..size)
+            .map(|i| MetaData {
+                rating_key: Some(i.to_string()),
+                view_count: (i % watched_every == 0).then_some(1),
+                ..MetaData::default()
+            })
+            .collect()
+    }
+
+    fn page(
+        items: &[MetaData],
+        start: i32,
+        loads: &std::cell::RefCell<Vec<i32>>,
+    ) -> futures_util::future::Ready<Result<MediaContainerWrapper<MediaContainer>>> {
+        loads.borrow_mut().push(start);
+        let mut container: MediaContainerWrapper<MediaContainer> = MediaContainerWrapper::default();
+        container.media_container.total_size = Some(items.len() as i32);
+        container.media_container.metadata = items
+            .iter()
+            .skip(start as usize)
+            .take(COLLECTION_PAGE_SIZE as usize)
+            .cloned()
+            .collect();
+        futures_util::future::ready(Ok(container))
+    }
+
+    fn keys(window: &CollectionWindow) -> Vec<String> {
+        window.children.iter().filter_map(|c| c.rating_key.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_load_window_fills_unwatched() {
+        // every third item is watched, so 200 unwatched over 3 pages
+        let items = collection(300, 3);
+        let loads = std::cell::RefCell::new(vec![]);
+        let mut cursor = CollectionCursor::default();
+
+        let window = load_window(&mut cursor, 60, 20, true, |start| page(&items, start, &loads))
+            .await
+            .unwrap();
+        // unwatched 60 is item 91, the window spans the first two pages
+        assert_eq!(window.children.len(), 20);
+        assert_eq!(keys(&window)[0], "91");
+        assert_eq!(keys(&window)[19], "119");
+        assert_eq!(*loads.borrow(), vec![0, 100]);
+        assert_eq!(window.total_size, None);
+
+        // the next window starts at the page holding its offset
+        loads.borrow_mut().clear();
+        let window = load_window(&mut cursor, 80, 20, true, |start| page(&items, start, &loads))
+            .await
+            .unwrap();
+        assert_eq!(keys(&window)[0], "121");
+        assert_eq!(*loads.borrow(), vec![100]);
+        assert_eq!(cursor.pages, vec![0, 66, 133]);
+    }
+
+    #[tokio::test]
+    async fn test_load_window_total_size() {
+        let items = collection(250, 2);
+        let loads = std::cell::RefCell::new(vec![]);
+        let mut cursor = CollectionCursor::default();
+
+        // past the end, only unwatched items are counted
+        let window = load_window(&mut cursor, 120, 50, true, |start| page(&items, start, &loads))
+            .await
+            .unwrap();
+        assert_eq!(window.children.len(), 5);
+        assert_eq!(window.total_size, Some(125));
+        assert_eq!(cursor.total, Some(125));
+
+        // without excluding watched items the window is cut by the page size
+        let mut cursor = CollectionCursor::default();
+        let window = load_window(&mut cursor, 230, 50, false, |start| page(&items, start, &loads))
+            .await
+            .unwrap();
+        assert_eq!(window.children.len(), 20);
+        assert_eq!(window.total_size, Some(250));
+    }
+
+}
@@ -0,0 +1,1101 @@
+//! Playback policies, evaluated on transcode decision and start requests.
+use crate::client_profile::{ClientCapabilities, ClientProfile, ProfileExtra, PROFILE_EXTRA};
+use crate::config::{deserialize_string_list, Config, ProfileMatch};
+use crate::models::*;
+use crate::notify::{notify, NotifyEvent, NotifyEventKind};
+use crate::plex_client::{read_container, PlexClient};
+use crate::utils::replace_query;
+use itertools::Itertools;
+use multimap::MultiMap;
+use salvo::Request;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use strum_macros::Display as EnumDisplay;
+
+/// Where the client connects from, see `Network::of`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumDisplay, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Network {
+    Local,
+    Remote,
+}
+
+impl Network {
+    /// From the peer address, or `X-Real-Ip` and `X-Forwarded-For` when the peer is one of the `trusted_proxies`.
+    pub fn of(context: &PlexContext, req: &Request) -> Self {
+        let peer = req
+            .remote_addr()
+            .as_ipv4()
+            .map(|a| IpAddr::V4(*a.ip()))
+            .or_else(|| req.remote_addr().as_ipv6().map(|a| IpAddr::V6(*a.ip())));
+        let trusted = Config::current().trusted_proxies.clone().unwrap_or_default();
+        let ip = client_ip(
+            peer,
+            context.real_ip.as_deref(),
+            context.forwarded_for.as_deref(),
+            &trusted,
+        );
+        Self::of_ip(ip)
+    }
+
+    /// Clients without a known address are remote, so remote policies still apply.
+    fn of_ip(ip: Option<IpAddr>) -> Self {
+        match ip {
+            Some(ip) if is_local(ip) => Self::Local,
+            _ => Self::Remote,
+        }
+    }
+}
+
+/// Client address, the headers are only believed when set by a trusted proxy.
+/// `X-Forwarded-For` is read from the right, skipping the trusted proxies.
+fn client_ip(
+    peer: Option<IpAddr>,
+    real_ip: Option<&str>,
+    forwarded_for: Option<&str>,
+    trusted: &[String],
+) -> Option<IpAddr> {
+    if !peer.is_some_and(|p| is_trusted(p, trusted)) {
+        return peer;
+    }
+    if let Some(ip) = real_ip.and_then(|ip| ip.trim().parse().ok()) {
+        return Some(ip);
+    }
+    let forwarded: Vec<IpAddr> = forwarded_for
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    // when every hop is trusted the left-most entry is still set by the client
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(**ip, trusted))
+        .copied()
+        .or(peer)
+}
+
+/// Whether the address is in one of the networks, ex `172.16.0.0/12` or `10.0.0.2`.
+fn is_trusted(ip: IpAddr, networks: &[String]) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    };
+    networks.iter().any(|network| {
+        let (address, prefix) = match network.split_once('/') {
+            Some((address, prefix)) => (address, prefix.trim().parse::<u32>().ok()),
+            None => (network.as_str(), None),
+        };
+        match (ip, address.trim().parse::<IpAddr>()) {
+            (IpAddr::V4(ip), Ok(IpAddr::V4(network))) => {
+                let prefix = prefix.unwrap_or(32).min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(network) & mask
+            }
+            (IpAddr::V6(ip), Ok(IpAddr::V6(network))) => {
+                let prefix = prefix.unwrap_or(128).min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(network) & mask
+            }
+            _ => false,
+        }
+    })
+}
+
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local(IpAddr::V4(ip)),
+            // unique local fc00::/7 and link local fe80::/10
+            None => {
+                ip.is_loopback()
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+/// Client criteria are the ones of profiles, media criteria are checked against the selected version.
+/// Every set criterion has to match.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+pub struct PolicyMatch {
+    #[serde(flatten)]
+    pub client: ProfileMatch,
+    #[serde(default)]
+    pub networks: Option<Vec<Network>>,
+    /// ex `4k`, `1080`, `720` or `sd`
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub resolutions: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub video_codecs: Option<Vec<String>>,
+    /// kbps
+    pub min_bitrate: Option<i64>,
+    /// kbps
+    pub max_bitrate: Option<i64>,
+}
+
+fn matches_any(values: &Option<Vec<String>>, value: Option<&String>) -> bool {
+    match (values, value) {
+        (None, _) => true,
+        (Some(values), Some(value)) => values.iter().any(|v| v.eq_ignore_ascii_case(value)),
+        (Some(_), None) => false,
+    }
+}
+
+impl PolicyMatch {
+    fn matches(&self, playback: &Playback) -> bool {
+        if !self.client.matches(&playback.context) {
+            return false;
+        }
+        if self
+            .networks
+            .as_ref()
+            .is_some_and(|n| !n.contains(&playback.network))
+        {
+            return false;
+        }
+        let media = playback.selected();
+        matches_any(&self.resolutions, media.and_then(|m| m.video_resolution.as_ref()))
+            && matches_any(&self.video_codecs, media.and_then(|m| m.video_codec.as_ref()))
+            && self.min_bitrate.map_or(true, |min| {
+                media.and_then(|m| m.bitrate).is_some_and(|b| b >= min)
+            })
+            && self.max_bitrate.map_or(true, |max| {
+                media.and_then(|m| m.bitrate).is_some_and(|b| b <= max)
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PolicyAction {
+    /// play the original quality, ignoring bitrate limits of the client
+    ForceDirectPlay,
+    /// kbps, the video is transcoded down when the version is above it
+    CapBitrate { max_bitrate: i64 },
+    /// version with the given resolution, or the one closest to the screen resolution
+    SelectVersion {
+        #[serde(default)]
+        resolution: Option<String>,
+    },
+    /// fall back to another version when plex would transcode the video,
+    /// `deny` refuses playback when every version transcodes
+    ForbidVideoTranscode {
+        #[serde(default)]
+        deny: bool,
+    },
+    /// `force_maximum_quality`, only when the client limits the bitrate,
+    /// plus direct play for the `force_direct_play_for` resolutions
+    #[serde(skip)]
+    ForceMaximumQuality { direct_play_for: Option<Vec<String>> },
+    /// `auto_select_version`, skips clients that do not send their screen resolution
+    #[serde(skip)]
+    AutoSelectVersion,
+    /// audio and subtitle tracks by preference, see `TrackPreferences`
+    SelectTracks(TrackPreferences),
+    Deny {
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+/// Subtitle codecs that are images, clients mostly need them burned in.
+const IMAGE_SUBTITLES: [&str; 5] =
+    ["pgs", "hdmv_pgs_subtitle", "vobsub", "dvd_subtitle", "dvb_subtitle"];
+
+/// Preferred audio and subtitle tracks. Languages are plex language codes, ex `eng`, in order of preference.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
+pub struct TrackPreferences {
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub audio_languages: Option<Vec<String>>,
+    /// 5.1 and up over stereo, commentary tracks always rank last
+    #[serde(default)]
+    pub prefer_surround: bool,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub subtitle_languages: Option<Vec<String>>,
+    /// only forced subtitles, subtitles are off when there are none
+    #[serde(default)]
+    pub forced_subtitles_only: bool,
+    /// no image subtitles like PGS, as they force a transcode to burn them in
+    #[serde(default)]
+    pub avoid_image_subtitles: bool,
+}
+
+/// Position in the preferred languages, `None` when there is no preference.
+fn language_rank(languages: &Option<Vec<String>>, stream: &Stream) -> Option<usize> {
+    let languages = languages.as_ref()?;
+    let code = stream.language_code.as_deref().unwrap_or_default();
+    Some(
+        languages
+            .iter()
+            .position(|l| l.eq_ignore_ascii_case(code))
+            .unwrap_or(languages.len()),
+    )
+}
+
+fn is_image_subtitle(stream: &Stream) -> bool {
+    stream
+        .codec
+        .as_deref()
+        .is_some_and(|c| IMAGE_SUBTITLES.iter().any(|i| i.eq_ignore_ascii_case(c)))
+}
+
+fn is_commentary(stream: &Stream) -> bool {
+    [&stream.title, &stream.display_title, &stream.extended_display_title]
+        .iter()
+        .any(|t| t.as_deref().is_some_and(|t| t.to_lowercase().contains("commentary")))
+}
+
+impl TrackPreferences {
+    fn streams(part: &MediaPart, stream_type: i64) -> impl Iterator<Item = &Stream> {
+        part.streams
+            .iter()
+            .filter(move |s| s.stream_type == Some(stream_type))
+    }
+
+    /// Best audio track, the selected one wins ties. None without audio preferences.
+    pub fn audio_stream<'a>(&self, part: &'a MediaPart) -> Option<&'a Stream> {
+        if self.audio_languages.is_none() && !self.prefer_surround {
+            return None;
+        }
+        Self::streams(part, 2).min_by_key(|s| {
+            (
+                language_rank(&self.audio_languages, s),
+                is_commentary(s),
+                self.prefer_surround && s.channels.unwrap_or(2) < 6,
+                s.selected != Some(true),
+            )
+        })
+    }
+
+    /// Audio and subtitle stream ids to select, `None` where the preferred one is selected already.
+    /// A subtitle id of `0` turns subtitles off.
+    pub fn changes(&self, part: &MediaPart) -> (Option<i64>, Option<i64>) {
+        let audio = self
+            .audio_stream(part)
+            .filter(|s| s.selected != Some(true))
+            .map(|s| s.id);
+        let subtitle = self
+            .subtitle_stream(part)
+            .filter(|s| s.map_or(true, |s| s.selected != Some(true)))
+            .map(|s| s.map_or(0, |s| s.id))
+            // plex reports subtitles that are off as none selected
+            .filter(|id| *id != 0 || Self::streams(part, 3).any(|s| s.selected == Some(true)));
+        (audio, subtitle)
+    }
+
+    /// Subtitle track to select, `Some(None)` turns them off and `None` keeps what the client has.
+    pub fn subtitle_stream<'a>(&self, part: &'a MediaPart) -> Option<Option<&'a Stream>> {
+        let selected = Self::streams(part, 3).find(|s| s.selected == Some(true));
+        let allowed = |s: &&Stream| {
+            !(self.avoid_image_subtitles && is_image_subtitle(s))
+                && !(self.forced_subtitles_only && s.forced != Some(true))
+        };
+        let preferred = self.subtitle_languages.is_some() || self.forced_subtitles_only;
+        if !preferred && selected.map_or(true, |s| allowed(&s)) {
+            return None;
+        }
+
+        let candidates = Self::streams(part, 3).filter(allowed).filter(|s| {
+            // only the language of the selected track, when choosing for it
+            preferred || s.language_code == selected.and_then(|s| s.language_code.clone())
+        });
+        let best = candidates.min_by_key(|s| {
+            (
+                language_rank(&self.subtitle_languages, s),
+                s.selected != Some(true),
+            )
+        });
+        // none of the languages the user reads
+        let unread = |s: &Stream| {
+            self.subtitle_languages
+                .as_ref()
+                .is_some_and(|l| language_rank(&self.subtitle_languages, s) == Some(l.len()))
+        };
+        Some(best.filter(|s| !unread(s)))
+    }
+}
+
+/// Matching policies apply in order, a deny stops the evaluation.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PlaybackPolicy {
+    pub name: Option<String>,
+    #[serde(default, rename = "match")]
+    pub matches: PolicyMatch,
+    #[serde(flatten)]
+    pub action: PolicyAction,
+}
+
+impl PlaybackPolicy {
+    fn new(name: &str, matches: PolicyMatch, action: PolicyAction) -> Self {
+        Self {
+            name: Some(name.to_string()),
+            matches,
+            action,
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("unnamed")
+    }
+}
+
+impl Config {
+    /// Policies of the playback settings, followed by the configured `policies`.
+    pub fn playback_policies(&self) -> Vec<PlaybackPolicy> {
+        let resolutions = |r: &Option<Vec<String>>| PolicyMatch {
+            resolutions: r.clone(),
+            ..Default::default()
+        };
+        let mut policies = vec![];
+        if self.auto_select_version {
+            policies.push(PlaybackPolicy::new(
+                "auto_select_version",
+                PolicyMatch::default(),
+                PolicyAction::AutoSelectVersion,
+            ));
+        }
+        if self.force_maximum_quality || self.disable_transcode {
+            policies.push(PlaybackPolicy::new(
+                "force_maximum_quality",
+                PolicyMatch::default(),
+                PolicyAction::ForceMaximumQuality {
+                    direct_play_for: self.force_direct_play_for.clone(),
+                },
+            ));
+        }
+        if self.video_transcode_fallback_for.as_ref().is_some_and(|r| !r.is_empty()) {
+            policies.push(PlaybackPolicy::new(
+                "video_transcode_fallback_for",
+                resolutions(&self.video_transcode_fallback_for),
+                PolicyAction::ForbidVideoTranscode { deny: false },
+            ));
+        }
+        policies.extend(self.policies.iter().cloned());
+        policies
+    }
+}
+
+pub enum Verdict {
+    Allow,
+    Deny(String),
+}
+
+/// A playback request while policies rewrite it.
+struct Playback {
+    context: PlexContext,
+    network: Network,
+    item: Option<MetaData>,
+    queries: MultiMap<String, String>,
+}
+
+impl Playback {
+    /// Requested version, the first when not set.
+    fn media_index(&self) -> usize {
+        self.queries
+            .get("mediaIndex")
+            .and_then(|i| i.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// The client picked a version itself.
+    fn has_media_index(&self) -> bool {
+        self.queries.get("mediaIndex").is_some_and(|i| i != "-1")
+    }
+
+    fn media(&self) -> &[Media] {
+        self.item.as_ref().map_or(&[], |i| i.media.as_slice())
+    }
+
+    fn selected(&self) -> Option<&Media> {
+        self.media().get(self.media_index())
+    }
+
+    fn requested_bitrate(&self) -> Option<i64> {
+        self.queries
+            .get("videoBitrate")
+            .or_else(|| self.queries.get("maxVideoBitrate"))
+            .and_then(|b| b.parse().ok())
+    }
+
+    fn set_query(&mut self, key: &str, value: impl ToString) {
+        self.queries.remove(key);
+        self.queries.insert(key.to_string(), value.to_string());
+    }
+
+    fn select(&mut self, index: usize) {
+        if let Some(media) = self.media().get(index) {
+            tracing::debug!("Selected {}", media);
+        }
+        self.set_query("mediaIndex", index);
+        // directPlay is meant for the first media item
+        if self.requested_bitrate().is_none() {
+            self.set_query("directPlay", 1);
+        }
+        self.set_query("subtitles", "auto");
+    }
+
+    fn select_version(&mut self, resolution: Option<&str>) {
+        if self.has_media_index() || self.media().len() <= 1 {
+            return;
+        }
+        let index = match resolution {
+            Some(resolution) => self.media().iter().position(|m| {
+                m.video_resolution
+                    .as_ref()
+                    .is_some_and(|r| r.eq_ignore_ascii_case(resolution))
+            }),
+            None => self.scorer().best(self.media()),
+        };
+        if let Some(index) = index {
+            self.select(index);
+        }
+    }
+
+    /// Client profile with the profile extra of the (rewritten) query.
+    fn client_profile(&self) -> ClientProfile {
+        ClientProfile {
+            capabilities: self.context.capabilities.clone(),
+            extra: self
+                .queries
+                .get(PROFILE_EXTRA)
+                .map(|e| ProfileExtra::parse(e))
+                .unwrap_or_default(),
+        }
+    }
+
+    fn scorer(&self) -> VersionScorer {
+        VersionScorer::new(&self.context, &self.client_profile(), self.requested_bitrate())
+    }
+
+    fn force_direct_play(&mut self) {
+        if self.requested_bitrate().is_some() {
+            self.remove_bitrate_limits();
+        }
+        self.set_query("directStream", 1);
+        self.set_query("directPlay", 1);
+        self.queries.remove("videoResolution");
+        self.remove_client_limitations();
+    }
+
+    /// Like `force_direct_play`, but leaves clients that do not limit the bitrate alone.
+    /// Versions in `direct_play_for` resolutions are played at their own resolution.
+    fn force_maximum_quality(&mut self, direct_play_for: Option<&[String]>) {
+        if self.requested_bitrate().is_none() {
+            return;
+        }
+        self.remove_bitrate_limits();
+        self.set_query("directStream", 1);
+        self.set_query("directPlay", 1);
+        self.remove_client_limitations();
+
+        let resolution = self.selected().and_then(|m| m.video_resolution.clone());
+        if resolution.is_some_and(|r| {
+            direct_play_for.is_some_and(|d| d.iter().any(|d| d.eq_ignore_ascii_case(&r)))
+        }) {
+            self.queries.remove("videoResolution");
+        }
+    }
+
+    fn auto_select_version(&mut self) {
+        if self.context.screen_resolution.is_empty() {
+            tracing::debug!("Skipping auto select as no screen resolution has been specified");
+            return;
+        }
+        self.select_version(None);
+    }
+
+    fn remove_bitrate_limits(&mut self) {
+        self.queries.remove("maxVideoBitrate");
+        self.queries.remove("videoBitrate");
+        self.set_query("autoAdjustQuality", 0);
+        self.set_query("videoQuality", 100);
+    }
+
+    /// Drops the limitations of the profile extra, and fixes the buffer size some clients send wrong.
+    fn remove_client_limitations(&mut self) {
+        if let Some(size) = self.queries.get("mediaBufferSize").cloned() {
+            if let Ok(parsed) = size.parse::<f32>() {
+                self.set_query("mediaBufferSize", parsed as i64);
+            }
+        }
+        if self.queries.contains_key(PROFILE_EXTRA) {
+            let mut extra = self.client_profile().extra;
+            extra.remove_limitations(|_| true);
+            self.set_query(PROFILE_EXTRA, extra);
+        }
+    }
+
+    /// Select the preferred tracks of the selected version with plex, which remembers them per user.
+    /// Only when they differ from the selected ones, as it changes what the user picked before.
+    async fn select_tracks(&mut self, plex_client: &PlexClient, preferences: &TrackPreferences) {
+        let Some(part) = self.selected().and_then(|m| m.parts.first()).cloned() else {
+            return;
+        };
+        let (audio, subtitle) = preferences.changes(&part);
+        if audio.is_none() && subtitle.is_none() {
+            return;
+        }
+        tracing::debug!(part = part.id, audio, subtitle, "Selecting tracks");
+        match plex_client.select_streams(part.id, audio, subtitle).await {
+            Ok(()) => self.set_query("subtitles", "auto"),
+            Err(error) => tracing::warn!(part = part.id, error = %error, "Failed to select tracks"),
+        }
+    }
+
+    fn cap_bitrate(&mut self, max_bitrate: i64) {
+        let bitrate = self.requested_bitrate().map_or(max_bitrate, |b| b.min(max_bitrate));
+        self.set_query("maxVideoBitrate", bitrate);
+        if self.selected().and_then(|m| m.bitrate).is_some_and(|b| b > bitrate) {
+            self.set_query("directPlay", 0);
+            self.set_query("directStream", 0);
+        }
+    }
+}
+
+/// Ranks the versions of an item on resolution fit, bitrate under the client cap,
+/// codec and container support and HDR, from what the client sends.
+/// Support that is not known counts neither for nor against a version.
+pub struct VersionScorer {
+    /// pixels of the screen
+    screen: Option<i64>,
+    /// kbps
+    max_bitrate: Option<i64>,
+    video_codecs: Option<Vec<String>>,
+    containers: Option<Vec<String>>,
+    hdr: Option<bool>,
+    dolby_vision: Option<bool>,
+}
+
+impl VersionScorer {
+    pub fn new(context: &PlexContext, profile: &ClientProfile, max_bitrate: Option<i64>) -> Self {
+        Self {
+            screen: context
+                .screen_resolution
+                .first()
+                .map(|r| r.height * r.width),
+            max_bitrate,
+            video_codecs: profile.video_codecs(),
+            containers: profile.containers(),
+            hdr: profile.supports_hdr(),
+            dolby_vision: profile.supports_dolby_vision(),
+        }
+    }
+
+    fn video_stream(media: &Media) -> Option<&Stream> {
+        media
+            .parts
+            .first()?
+            .streams
+            .iter()
+            .find(|s| s.stream_type == Some(1))
+    }
+
+    fn is_hdr(media: &Media) -> bool {
+        Self::video_stream(media).is_some_and(|s| {
+            s.dovipresent == Some(true)
+                || matches!(s.color_trc.as_deref(), Some("smpte2084") | Some("arib-std-b67"))
+        })
+    }
+
+    /// Dolby Vision without a base layer other players can show, ex profile 5.
+    fn is_dolby_vision_only(media: &Media) -> bool {
+        Self::video_stream(media).is_some_and(|s| {
+            s.dovipresent == Some(true) && s.doviblcompat_id.unwrap_or(0) == 0
+        })
+    }
+
+    pub fn score(&self, media: &Media) -> f64 {
+        let density = (media.height.unwrap_or(0) * media.width.unwrap_or(0)) as f64;
+        let mut score = match self.screen {
+            // as close as possible, versions above the screen lose less than below
+            Some(screen) if screen > 0 => {
+                let ratio = density / screen as f64;
+                match ratio <= 1.0 {
+                    true => 40.0 * ratio,
+                    false => (40.0 - 10.0 * (ratio - 1.0)).max(0.0),
+                }
+            }
+            // highest resolution, 4k is around 8.3 million pixels
+            _ => 40.0 * (density / 8_294_400.0).min(1.0),
+        };
+
+        if let (Some(cap), Some(bitrate)) = (self.max_bitrate, media.bitrate) {
+            score += match bitrate <= cap {
+                true => 30.0 * bitrate as f64 / cap.max(1) as f64,
+                // would be transcoded down
+                false => -50.0,
+            };
+        }
+
+        let supported = |values: &Option<Vec<String>>, value: &Option<String>| {
+            match (values, value) {
+                (Some(values), Some(value)) => Some(values.contains(&value.to_lowercase())),
+                _ => None,
+            }
+        };
+        score += match supported(&self.video_codecs, &media.video_codec) {
+            Some(true) => 20.0,
+            Some(false) => -40.0,
+            None => 0.0,
+        };
+        score += match supported(&self.containers, &media.container) {
+            Some(true) => 5.0,
+            Some(false) => -10.0,
+            None => 0.0,
+        };
+
+        if Self::is_hdr(media) {
+            score += match self.hdr {
+                // needs tone mapping
+                Some(false) => -30.0,
+                _ => 5.0,
+            };
+        }
+        if Self::is_dolby_vision_only(media) && self.dolby_vision != Some(true) {
+            score -= 30.0;
+        }
+        score
+    }
+
+    /// Index of the best version, the first one on a tie.
+    pub fn best(&self, media: &[Media]) -> Option<usize> {
+        media
+            .iter()
+            .map(|m| self.score(m))
+            .enumerate()
+            .fold(None, |best: Option<(usize, f64)>, (index, score)| match best {
+                Some((_, best_score)) if best_score >= score => best,
+                _ => Some((index, score)),
+            })
+            .map(|(index, _)| index)
+    }
+}
+
+/// Apply the matching policies to a decision or start request, rewriting its query.
+pub async fn apply(req: &mut Request) -> Verdict {
+    let config = Config::dynamic(req);
+    let policies = config.playback_policies();
+    if policies.is_empty() {
+        return Verdict::Allow;
+    }
+    let context: PlexContext = req.extract().await.unwrap_or_default();
+    if context.token.is_none() {
+        return Verdict::Allow;
+    }
+    let plex_client = match PlexClient::from_context(&context) {
+        Ok(plex_client) => plex_client,
+        Err(error) => {
+            tracing::warn!(error = %error, "Skipping playback policies");
+            return Verdict::Allow;
+        }
+    };
+    let item = playback_item(&plex_client, req).await;
+    let mut playback = Playback {
+        network: Network::of(&context, req),
+        context,
+        item,
+        queries: req.queries().clone(),
+    };
+
+    let mut forbid_video_transcode: Option<bool> = None;
+    // the last matching preferences win
+    let mut tracks: Option<TrackPreferences> = None;
+    for policy in policies.iter() {
+        if !policy.matches.matches(&playback) {
+            continue;
+        }
+        tracing::debug!(policy = policy.name(), action = ?policy.action, "Applying playback policy");
+        match &policy.action {
+            PolicyAction::ForceDirectPlay => playback.force_direct_play(),
+            PolicyAction::ForceMaximumQuality { direct_play_for } => {
+                playback.force_maximum_quality(direct_play_for.as_deref())
+            }
+            PolicyAction::AutoSelectVersion => playback.auto_select_version(),
+            PolicyAction::CapBitrate { max_bitrate } => playback.cap_bitrate(*max_bitrate),
+            PolicyAction::SelectVersion { resolution } => {
+                playback.select_version(resolution.as_deref())
+            }
+            PolicyAction::ForbidVideoTranscode { deny } => {
+                forbid_video_transcode = Some(forbid_video_transcode.unwrap_or(false) || *deny)
+            }
+            PolicyAction::SelectTracks(preferences) => tracks = Some(preferences.clone()),
+            PolicyAction::Deny { reason } => {
+                let reason = reason
+                    .clone()
+                    .unwrap_or(format!("Playback denied by policy {}", policy.name()));
+                return deny(&playback, reason);
+            }
+        }
+    }
+    // once per playback, start and subtitle requests follow the decision
+    if let Some(preferences) = tracks.filter(|_| req.uri().path().ends_with("/decision")) {
+        playback.select_tracks(&plex_client, &preferences).await;
+    }
+    replace_query(playback.queries.clone(), req);
+
+    // checking needs a decision from plex, start requests follow the decision
+    match forbid_video_transcode {
+        Some(deny_transcode) if req.uri().path().ends_with("/decision") => {
+            forbid_video_transcode_for(req, &plex_client, playback, deny_transcode).await
+        }
+        _ => Verdict::Allow,
+    }
+}
+
+fn deny(playback: &Playback, reason: String) -> Verdict {
+    notify(NotifyEvent::new(
+        NotifyEventKind::PlaybackDenied,
+        &playback.context,
+        serde_json::json!({
+            "path": playback.queries.get("path"),
+            "reason": reason,
+        }),
+    ));
+    Verdict::Deny(reason)
+}
+
+/// Try the versions from best to worst scoring until plex does not transcode the video.
+async fn forbid_video_transcode_for(
+    req: &mut Request,
+    plex_client: &PlexClient,
+    mut playback: Playback,
+    deny_transcode: bool,
+) -> Verdict {
+    let original_queries = playback.queries.clone();
+    let Some(selected) = playback.selected().cloned() else {
+        return Verdict::Allow;
+    };
+    let mut fallbacks: Vec<(usize, Media)> = playback
+        .media()
+        .iter()
+        .cloned()
+        .enumerate()
+        .filter(|(_, m)| m.id != selected.id)
+        .collect();
+    let scorer = playback.scorer();
+    fallbacks.sort_by(|(_, a), (_, b)| scorer.score(b).total_cmp(&scorer.score(a)));
+
+    let mut candidates = vec![None];
+    candidates.extend(fallbacks.into_iter().map(Some));
+    for candidate in candidates {
+        if let Some((index, _)) = &candidate {
+            playback.select(*index);
+            playback.set_query("directStream", 1);
+            replace_query(playback.queries.clone(), req);
+        }
+        match video_transcoding(req, plex_client).await {
+            Ok(true) => {
+                tracing::debug!("Video transcoding, looking for fallback");
+            }
+            Ok(false) => {
+                if let Some((_, media)) = candidate {
+                    tracing::debug!("Video transcode fallback from {} to {}", selected, media);
+                    notify(NotifyEvent::new(
+                        NotifyEventKind::VideoTranscodeFallback,
+                        &playback.context,
+                        serde_json::json!({
+                            "path": playback.context.path,
+                            "from": selected.to_string(),
+                            "to": media.to_string(),
+                        }),
+                    ));
+                }
+                return Verdict::Allow;
+            }
+            Err(error) => {
+                tracing::warn!(error = %error, "Failed to get the transcode decision, skipping fallback check");
+                replace_query(original_queries, req);
+                return Verdict::Allow;
+            }
+        }
+    }
+
+    tracing::debug!("No suitable fallback found");
+    replace_query(original_queries, req);
+    match deny_transcode {
+        true => deny(&playback, "Video transcoding is not allowed".to_string()),
+        false => Verdict::Allow,
+    }
+}
+
+/// Asks plex for the decision of the request as it is now.
+async fn video_transcoding(req: &Request, plex_client: &PlexClient) -> anyhow::Result<bool> {
+    let res = plex_client.request(req).await?;
+    let decision = read_container(res).await?;
+    Ok(decision
+        .media_container
+        .metadata
+        .first()
+        .and_then(|m| m.media.first())
+        .and_then(|m| m.parts.first())
+        .is_some_and(|p| {
+            p.streams
+                .iter()
+                .any(|s| s.stream_type == Some(1) && s.decision.as_deref() == Some("transcode"))
+        }))
+}
+
+/// Item of a playback request `path`. None when it cannot be loaded,
+/// the request then passes through unchanged.
+async fn playback_item(plex_client: &PlexClient, req: &Request) -> Option<MetaData> {
+    let path = req.queries().get("path")?.to_string();
+    match plex_client.clone().get_item_by_key(path.clone()).await {
+        Ok(item) => item.media_container.metadata.into_iter().next(),
+        Err(error) => {
+            tracing::warn!(path = %path, error = %error, "Failed to load playback item");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::providers::{Format, Toml};
+    use figment::Figment;
+
+    #[test]
+    fn test_policies_from_toml() {
+        #[derive(Deserialize)]
+        struct Policies {
+            policies: Vec<PlaybackPolicy>,
+        }
+        let policies: Policies = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [[policies]]
+                name = "remote 4k"
+                action = "cap_bitrate"
+                max_bitrate = 8000
+                [policies.match]
+                networks = ["remote"]
+                resolutions = ["4k"]
+
+                [[policies]]
+                action = "deny"
+                [policies.match]
+                usernames = "kid"
+
+                [[policies]]
+                action = "select_tracks"
+                audio_languages = ["jpn", "eng"]
+                avoid_image_subtitles = true
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        let policies = policies.policies;
+        assert_eq!(policies[0].action, PolicyAction::CapBitrate { max_bitrate: 8000 });
+        assert_eq!(policies[0].matches.networks, Some(vec![Network::Remote]));
+        assert_eq!(policies[0].matches.resolutions, Some(vec!["4k".to_string()]));
+        assert_eq!(policies[1].action, PolicyAction::Deny { reason: None });
+        assert_eq!(policies[1].matches.client.usernames, Some(vec!["kid".to_string()]));
+        assert_eq!(
+            policies[2].action,
+            PolicyAction::SelectTracks(TrackPreferences {
+                audio_languages: Some(vec!["jpn".to_string(), "eng".to_string()]),
+                avoid_image_subtitles: true,
+                ..Default::default()
+            })
+        );
+    }
+
+    fn stream(id: i64, stream_type: i64, language: &str, codec: &str) -> Stream {
+        Stream {
+            id,
+            stream_type: Some(stream_type),
+            language_code: Some(language.to_string()),
+            codec: Some(codec.to_string()),
+            channels: Some(2),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_track_preferences() {
+        let mut commentary = stream(2, 2, "eng", "aac");
+        commentary.title = Some("Director's Commentary".to_string());
+        let mut surround = stream(3, 2, "eng", "eac3");
+        surround.channels = Some(6);
+        let mut pgs = stream(4, 3, "eng", "pgs");
+        pgs.selected = Some(true);
+        let mut forced = stream(6, 3, "eng", "srt");
+        forced.forced = Some(true);
+        let part = MediaPart {
+            streams: vec![
+                stream(1, 2, "jpn", "aac"),
+                commentary,
+                surround,
+                pgs,
+                stream(5, 3, "eng", "srt"),
+                forced,
+            ],
+            ..Default::default()
+        };
+        let id = |s: Option<&Stream>| s.map(|s| s.id);
+
+        let mut preferences = TrackPreferences {
+            audio_languages: Some(vec!["eng".to_string()]),
+            prefer_surround: true,
+            ..Default::default()
+        };
+        assert_eq!(id(preferences.audio_stream(&part)), Some(3));
+        // nothing to change for subtitles
+        assert_eq!(preferences.subtitle_stream(&part), None);
+
+        preferences.avoid_image_subtitles = true;
+        assert_eq!(preferences.subtitle_stream(&part).map(id), Some(Some(5)));
+        preferences.forced_subtitles_only = true;
+        assert_eq!(preferences.subtitle_stream(&part).map(id), Some(Some(6)));
+        preferences.subtitle_languages = Some(vec!["fre".to_string()]);
+        assert_eq!(preferences.subtitle_stream(&part).map(id), Some(None));
+        assert_eq!(preferences.changes(&part), (Some(3), Some(0)));
+    }
+
+    #[test]
+    fn test_track_changes_when_selected() {
+        let mut audio = stream(1, 2, "eng", "eac3");
+        audio.selected = Some(true);
+        let mut subtitle = stream(2, 3, "eng", "srt");
+        subtitle.selected = Some(true);
+        let mut part = MediaPart {
+            streams: vec![audio, stream(3, 2, "fre", "aac"), subtitle],
+            ..Default::default()
+        };
+        let preferences = TrackPreferences {
+            audio_languages: Some(vec!["eng".to_string()]),
+            subtitle_languages: Some(vec!["eng".to_string()]),
+            ..Default::default()
+        };
+        // nothing to put when plex has the preferred tracks selected
+        assert_eq!(preferences.changes(&part), (None, None));
+
+        // subtitles that are off stay off without a subtitle in a preferred language
+        part.streams[2].selected = None;
+        part.streams[2].language_code = Some("ger".to_string());
+        assert_eq!(preferences.changes(&part), (None, None));
+    }
+
+    fn media(id: i64, width: i64, height: i64, codec: &str, bitrate: i64) -> Media {
+        Media {
+            id,
+            width: Some(width),
+            height: Some(height),
+            video_codec: Some(codec.to_string()),
+            bitrate: Some(bitrate),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_version_scorer() {
+        let versions = vec![
+            media(1, 3840, 2160, "hevc", 40000),
+            media(2, 1920, 1080, "h264", 8000),
+        ];
+        let context = PlexContext {
+            screen_resolution: vec![Resolution { width: 3840, height: 2160 }],
+            ..Default::default()
+        };
+        let mut profile = ClientProfile::default();
+
+        // a 4k screen without limits gets the 4k version
+        assert_eq!(VersionScorer::new(&context, &profile, None).best(&versions), Some(0));
+        // but not when the client is capped at 8 Mbps
+        assert_eq!(VersionScorer::new(&context, &profile, Some(8000)).best(&versions), Some(1));
+        // or cannot decode hevc
+        profile.capabilities = ClientCapabilities::parse("protocols=http-live-streaming;videoDecoders=h264{profile:high&resolution:2160}");
+        assert_eq!(VersionScorer::new(&context, &profile, None).best(&versions), Some(1));
+    }
+
+    fn playback(queries: &[(&str, &str)], media: Vec<Media>) -> Playback {
+        Playback {
+            context: PlexContext::default(),
+            network: Network::Local,
+            item: Some(MetaData {
+                media,
+                ..Default::default()
+            }),
+            queries: queries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_builtin_policies() {
+        let versions = || {
+            let mut uhd = media(1, 3840, 2160, "hevc", 40000);
+            uhd.video_resolution = Some("4k".to_string());
+            vec![uhd, media(2, 1920, 1080, "h264", 8000)]
+        };
+        let direct_play_for = vec!["4k".to_string()];
+
+        // clients that do not limit the bitrate are left alone
+        let mut unlimited = playback(&[("videoResolution", "1920x1080")], versions());
+        unlimited.force_maximum_quality(Some(&direct_play_for));
+        assert_eq!(unlimited.queries.get("directPlay"), None);
+        assert_eq!(unlimited.queries.get("videoResolution").map(String::as_str), Some("1920x1080"));
+
+        let mut limited = playback(
+            &[("maxVideoBitrate", "4000"), ("videoResolution", "1920x1080")],
+            versions(),
+        );
+        limited.force_maximum_quality(Some(&direct_play_for));
+        assert_eq!(limited.queries.get("maxVideoBitrate"), None);
+        assert_eq!(limited.queries.get("directPlay").map(String::as_str), Some("1"));
+        assert_eq!(limited.queries.get("videoResolution"), None);
+
+        // without a screen resolution the client picks the version
+        let mut auto = playback(&[], versions());
+        auto.auto_select_version();
+        assert_eq!(auto.queries.get("mediaIndex"), None);
+        auto.context.screen_resolution = vec![Resolution { width: 1920, height: 1080 }];
+        auto.auto_select_version();
+        assert_eq!(auto.queries.get("mediaIndex").map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted = vec!["172.16.0.0/12".to_string(), "10.0.0.2".to_string()];
+        let proxy: IpAddr = "172.18.0.5".parse().unwrap();
+        let client: IpAddr = "8.8.8.8".parse().unwrap();
+
+        // headers of clients that connect directly are ignored
+        assert_eq!(client_ip(Some(client), Some("192.168.1.2"), None, &trusted), Some(client));
+        assert_eq!(client_ip(Some(proxy), Some("8.8.8.8"), None, &trusted), Some(client));
+        // spoofed addresses on the left are skipped
+        assert_eq!(
+            client_ip(Some(proxy), None, Some("192.168.1.2, 8.8.8.8, 10.0.0.2"), &trusted),
+            Some(client)
+        );
+        assert_eq!(client_ip(Some(proxy), None, None, &[]), Some(proxy));
+        assert_eq!(
+            client_ip(Some(proxy), None, Some("10.0.0.2, 172.18.0.6"), &trusted),
+            Some(proxy)
+        );
+        assert!(is_trusted("::ffff:10.0.0.2".parse().unwrap(), &trusted));
+        assert!(!is_trusted("172.32.0.1".parse().unwrap(), &trusted));
+    }
+
+    #[test]
+    fn test_network_of_ip() {
+        assert_eq!(Network::of_ip(Some("192.168.1.10".parse().unwrap())), Network::Local);
+        assert_eq!(Network::of_ip(Some("8.8.8.8".parse().unwrap())), Network::Remote);
+        assert_eq!(Network::of_ip(None), Network::Remote);
+    }
+
+    #[test]
+    fn test_is_local() {
+        assert!(is_local("192.168.1.10".parse().unwrap()));
+        assert!(is_local("127.0.0.1".parse().unwrap()));
+        assert!(is_local("fd00::1".parse().unwrap()));
+        assert!(!is_local("8.8.8.8".parse().unwrap()));
+        assert!(!is_local("2001:4860::8888".parse().unwrap()));
+    }
+}
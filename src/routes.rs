@@ -1,11 +1,17 @@
+use crate::cache::{Cache, RequestIssuer, ResponseStore, GLOBAL_CACHE, RESPONSE_CACHE};
+use crate::client_profile;
 use crate::config::Config;
 use crate::logging::*;
 use crate::models::*;
+use crate::notify::*;
+use crate::policy::{self, Verdict};
 use crate::plex_client::*;
 use crate::timeout::*;
 use crate::transform::*;
+use crate::transform::interleave::InterleaveStrategy;
 use crate::url::*;
 use crate::utils::*;
+use crate::warmer;
 use crate::webhooks;
 use itertools::Itertools;
 use salvo::compression::Compression;
@@ -13,7 +19,8 @@ use salvo::cors::Cors;
 use salvo::http::header::CONTENT_TYPE;
 use salvo::http::{Request, Response, StatusCode};
 use salvo::prelude::*;
-use salvo::routing::PathFilter;
+use salvo::routing::{PathFilter, PathState};
+use salvo::test::ResponseExt;
 use salvo::http::HeaderValue;
 use salvo::http::header;
 use tokio::time::Duration;
@@ -21,8 +28,6 @@ use url::Url;
 use http;
 
 pub fn route() -> Router {
-    let config: Config = Config::figment().extract().unwrap();
-
     // cant use colon in paths. So we do it with an regex
     let guid = regex::Regex::new(":").unwrap();
     PathFilter::register_wisp_regex("colon", guid);
@@ -34,38 +39,40 @@ pub fn route() -> Router {
         .hoop(Compression::new().enable_gzip(CompressionLevel::Fastest));
     // .hoop(affix::insert("script_engine", Arc::new(script_engine)));
 
-    if config.redirect_streams {
-        router = router
-            .push(
-                Router::with_path(
-                    "/video/<colon:colon>/transcode/universal/session/<**rest>",
-                )
-                .goal(redirect_stream),
+    // Optional routes are always registered and enabled per request,
+    // so they follow config reloads and profiles.
+    router = router
+        .push(
+            Router::with_path(
+                "/video/<colon:colon>/transcode/universal/session/<**rest>",
             )
-            .push(
-                Router::with_path(
-                    "/library/parts/<itemid>/<partid>/file.<extension>",
-                )
-                .goal(redirect_stream),
-            );
-    }
+            .filter_fn(enabled(|c| c.redirect_streams))
+            .goal(redirect_stream),
+        )
+        .push(
+            Router::with_path(
+                "/library/parts/<itemid>/<partid>/file.<extension>",
+            )
+            .filter_fn(enabled(|c| c.redirect_streams))
+            .goal(redirect_stream),
+        );
 
     // TODO: We could just make a gobal middleware that checks every request for the includeRelated.
     // Not sure of the performance impact tho
-    if config.disable_related {
-        router = router
-            .push(
-                Router::new()
-                    .path("/library/metadata/<id>/related")
-                    .hoop(Timeout::new(Duration::from_secs(5)))
-                    .goal(proxy_request),
-            )
-            .push(
-                Router::with_path("/playQueues")
-                    .hoop(disable_related_query)
-                    .goal(proxy_request),
-            );
-    }
+    router = router
+        .push(
+            Router::new()
+                .path("/library/metadata/<id>/related")
+                .filter_fn(enabled(|c| c.disable_related))
+                .hoop(Timeout::new(Duration::from_secs(5)))
+                .goal(proxy_request),
+        )
+        .push(
+            Router::with_path("/playQueues")
+                .filter_fn(enabled(|c| c.disable_related))
+                .hoop(disable_related_query)
+                .goal(proxy_request),
+        );
 
     let mut decision_router = Router::new()
         .path("/video/<colon:colon>/transcode/universal/decision")
@@ -79,23 +86,17 @@ pub fn route() -> Router {
         .path("/video/<colon:colon>/transcode/universal/subtitles")
         .goal(proxy_request);
 
-    // should go before force_maximum_quality and video_transcode_fallback
-    if config.auto_select_version {
-        decision_router = decision_router.hoop(auto_select_version);
-        start_router = start_router.hoop(auto_select_version);
-        subtitles_router = subtitles_router.hoop(auto_select_version);
-    }
+    // hoops run in the order they are added. Outermost, so it sees the
+    // response the client gets
+    decision_router = decision_router.hoop(observe_decision);
 
-    if config.force_maximum_quality || config.disable_transcode {
-        decision_router = decision_router.hoop(force_maximum_quality);
-        start_router = start_router.hoop(force_maximum_quality);
-        subtitles_router = subtitles_router.hoop(force_maximum_quality);
-    }
-
-    if config.video_transcode_fallback_for.is_some() {
-        decision_router = decision_router.hoop(video_transcode_fallback);
-        //subtitles_router = subtitles_router.hoop(video_transcode_fallback);
-    }
+    // always hooked as profiles and reloads can change the policies per request,
+    // overrides first so policies see what the client really plays
+    decision_router = decision_router.hoop(client_overrides);
+    start_router = start_router.hoop(client_overrides);
+    decision_router = decision_router.hoop(playback_policy);
+    start_router = start_router.hoop(playback_policy);
+    subtitles_router = subtitles_router.hoop(playback_policy);
 
     decision_router = decision_router.hoop(direct_stream_fallback);
 
@@ -104,29 +105,28 @@ pub fn route() -> Router {
         .push(start_router)
         .push(subtitles_router);
 
-    if config.disable_continue_watching {
-        router = router.push(
-            Router::new()
-                .path(PLEX_CONTINUE_WATCHING)
-                .get(empty_handler),
-        );
-    }
-    
-    if config.ntf_watchlist_force {
-        router = router.push(
-            Router::new()
-                .hoop(ntf_watchlist_force)
-                //.get(ping)
-                //.hoop(debug)
-                .goal(proxy_request)
-                .path("/media/providers"),
-        );
-    }
+    router = router.push(
+        Router::new()
+            .path(PLEX_CONTINUE_WATCHING)
+            .filter_fn(enabled(|c| c.disable_continue_watching))
+            .get(empty_handler),
+    );
+
+    router = router.push(
+        Router::new()
+            .filter_fn(enabled(|c| c.ntf_watchlist_force))
+            .hoop(ntf_watchlist_force)
+            //.get(ping)
+            //.hoop(debug)
+            .goal(proxy_request)
+            .path("/media/providers"),
+    );
 
     router = router
         .push(
             Router::new()
                 .path(PLEX_HUBS_PROMOTED)
+                .hoop(response_cache())
                 .hoop(transform_req_content_directory)
                 .hoop(transform_req_include_guids)
                 .hoop(transform_req_android)
@@ -146,29 +146,59 @@ pub fn route() -> Router {
         .push(
             Router::new()
                 .path(format!("{}/<id>", PLEX_HUBS_SECTIONS))
+                .hoop(response_cache())
                 .hoop(transform_req_include_guids)
                 .hoop(transform_req_android)
                 .hoop(proxy_for_transform)
                 .get(transform_hubs_response)
         )
+        .push(
+            Router::new()
+                .path("/library/metadata/<id>")
+                .filter_fn(enabled(|c| {
+                    c.scripts.metadata.is_some() || c.pipelines.metadata.is_some()
+                }))
+                .get(get_library_item_metadata),
+        )
         .push(
             Router::new()
                 .path("/replex/webhooks")
                 .post(webhook_plex),
         )
+        .push(
+            Router::new()
+                .path("/replex/admin/cache")
+                .hoop(admin_auth)
+                .get(admin_cache_stats)
+                .delete(admin_cache_purge),
+        )
         .push(
             Router::new()
                 .path("/ping")
                 .get(ping),
         )
+        .push(
+            Router::new()
+                .path("/metrics")
+                .filter_fn(enabled(|c| c.enable_metrics))
+                .get(metrics),
+        )
         .push(
             Router::new()
                 .path("/replex/<style>/library/collections/<ids>/children")
+                .hoop(response_cache())
+                .get(get_collections_children),
+        )
+        .push(
+            Router::new()
+                .path("/replex/<style>/library/collections/<ids>/children/<strategy>")
+                .hoop(response_cache())
                 .get(get_collections_children),
         )
         .push(
             Router::new()
                 .path("/replex/<style>/<**rest>")
+                .hoop(response_cache())
                 .get(default_transform),
         )
         .push(
@@ -182,6 +212,24 @@ pub fn route() -> Router {
     router
 }
 
+/// Caches transformed responses per user and profile when `cache_responses` is enabled.
+fn response_cache() -> Cache<ResponseStore, RequestIssuer> {
+    let fresh = || Duration::from_secs(Config::current().cache_responses_ttl);
+    Cache::new(RESPONSE_CACHE.clone(), RequestIssuer::with_plex_defaults())
+        .skipper(|req: &mut Request, _: &Depot| {
+            req.method() != salvo::http::Method::GET
+                || !Config::dynamic(req).cache_responses
+        })
+        .stale_while_revalidate(fresh)
+}
+
+/// Route filter matching when the given setting is enabled for the request.
+fn enabled(
+    setting: fn(&Config) -> bool,
+) -> impl Fn(&mut Request, &mut PathState) -> bool + Send + Sync + 'static {
+    move |req, _| setting(&Config::dynamic(req))
+}
+
 #[handler]
 async fn proxy_request(
     req: &mut Request,
@@ -191,6 +239,14 @@ async fn proxy_request(
 ) {
     let proxy = default_proxy();
     proxy.handle(req, depot, res, ctrl).await;
+    record_proxy_status(res);
+}
+
+/// The status of a proxied response is the one plex responded with.
+fn record_proxy_status(res: &Response) {
+    if let Some(status) = res.status_code {
+        crate::access_log::record_upstream(status.as_u16());
+    }
 }
 
 #[handler]
@@ -215,6 +271,7 @@ async fn proxy_for_transform(
     let headers_ori = req.headers().clone();
     req.headers_mut().insert(http::header::ACCEPT, header::HeaderValue::from_static("application/json"));
     proxy.handle(req, depot, res, ctrl).await;
+    record_proxy_status(res);
     *req.headers_mut() = headers_ori;
     Ok(())
 }
@@ -227,7 +284,7 @@ async fn should_skip(
     depot: &mut Depot,
     ctrl: &mut FlowCtrl,
 ) {
-    let context: PlexContext = req.extract().await.unwrap();
+    let context: PlexContext = req.extract().await.unwrap_or_default();
     
     let is_livetv = match context.path.clone() {
         Some(v) => v.contains("livetv"),
@@ -240,10 +297,11 @@ async fn should_skip(
     };
 
     if is_livetv || is_plexamp {
-        let config: Config = Config::dynamic(req).extract().unwrap();
+        let config = Config::dynamic(req);
         let proxy = default_proxy();
 
         proxy.handle(req, depot, res, ctrl).await;
+        record_proxy_status(res);
         ctrl.skip_rest();
     }
 }
@@ -254,7 +312,7 @@ async fn redirect_stream(
     _depot: &mut Depot,
     res: &mut Response,
 ) {
-    let config: Config = Config::dynamic(req).extract().unwrap();
+    let config = Config::dynamic(req);
     let redirect_url = if config.redirect_streams_host.clone().is_some() {
         format!(
             "{}{}",
@@ -264,7 +322,7 @@ async fn redirect_stream(
     } else {
         format!(
             "{}{}",
-            config.host.unwrap(),
+            config.host.clone().unwrap(),
             req.uri_mut().path_and_query().unwrap()
         )
     };
@@ -281,50 +339,69 @@ async fn fix_photo_transcode_request(
     _depot: &mut Depot,
     res: &mut Response,
 ) {
-    let context: PlexContext = req.extract().await.unwrap();
-    if context.size.is_some() && context.clone().size.unwrap().contains('-')
+    let context: PlexContext = match req.extract().await {
+        Ok(context) => context,
+        Err(error) => {
+            tracing::debug!(error = %error, "Cannot read photo transcode context");
+            return;
+        }
+    };
     // (catched things like (medlium-240, large-500),i dont think size paramater orks at all, but who knows
     // && context.platform.is_some()
     // && context.clone().platform.unwrap().to_lowercase() == "android"
-    {
-        let size: String = context
-            .clone()
-            .size
-            .unwrap()
-            .split('-')
-            .last()
-            .unwrap()
-            .parse()
-            .unwrap();
+    if let Some((_, size)) = context.size.as_deref().and_then(|size| size.rsplit_once('-')) {
+        let size = size.to_string();
         add_query_param_salvo(req, "height".to_string(), size.clone());
         add_query_param_salvo(req, "width".to_string(), size.clone());
         //add_query_param_salvo(req, "quality".to_string(), "80".to_string());
     }
 }
 
+/// Context and plex client of the request. Fails without a token or plex host,
+/// see `PlexClient::from_context`.
+async fn request_client(req: &mut Request) -> anyhow::Result<(PlexContext, PlexClient)> {
+    let context: PlexContext = req.extract().await?;
+    let plex_client = PlexClient::from_context(&context)?;
+    Ok((context, plex_client))
+}
+
+/// Status to respond with when a request to plex failed.
+fn error_status(error: &anyhow::Error) -> StatusCode {
+    UpstreamError::from_error(error).map_or(StatusCode::BAD_GATEWAY, |e| e.status())
+}
+
 // resolve a local media path to full url
 #[handler]
 async fn resolve_local_media_path(
     req: &mut Request,
     res: &mut Response,
 ) {
-    let mut context: PlexContext = req.extract().await.unwrap();
-    let url = req.query::<String>("url");
-    if url.is_some() && url.clone().unwrap().contains("/replex/image/hero")
-    {
-        let uri: url::Url = url::Url::parse(url.unwrap().as_str()).unwrap();
-        let segments = uri.path_segments().unwrap().collect::<Vec<&str>>();
-        
-        let uuid = segments.last().unwrap().replace(".jpg", "");
-        //if context.token.is_none() {
-        //    context.token = Some(segments.last().unwrap().to_string());
-        //}
-
-        let plex_client = PlexClient::from_context(&context);
-        let rurl = plex_client.get_hero_art(uuid.to_string()).await;
-        if rurl.is_some() {
-          add_query_param_salvo(req, "url".to_string(), rurl.unwrap());
+    let Some(url) = req.query::<String>("url") else {
+        return;
+    };
+    if !url.contains("/replex/image/hero") {
+        return;
+    }
+    // on failures the url is passed on as it is
+    let uuid = match url::Url::parse(&url) {
+        Ok(uri) => match uri.path_segments().and_then(|mut s| s.next_back()) {
+            Some(segment) => segment.replace(".jpg", ""),
+            None => return,
+        },
+        Err(error) => {
+            tracing::debug!(url, error = %error, "Invalid hero image url");
+            return;
         }
+    };
+    let plex_client = match request_client(req).await {
+        Ok((_, plex_client)) => plex_client,
+        Err(error) => {
+            tracing::debug!(error = %error, "Cannot resolve hero image url");
+            return;
+        }
+    };
+    if let Some(rurl) = plex_client.get_hero_art(uuid).await {
+        add_query_param_salvo(req, "url".to_string(), rurl);
     }
 }
 
@@ -466,15 +543,114 @@ pub async fn webhook_plex(
     req: &mut Request,
     res: &mut Response,
 ) -> Result<(), anyhow::Error> {
-    dbg!("YOOO");
-    let raw = req.form::<String>("payload").await;
-    let payload: webhooks::Payload = serde_json::from_str(&raw.unwrap())?;
-    dbg!(&req);
-    dbg!(payload);
+    let config = Config::current();
+    let Some(webhook_secret) = config.webhook_secret.as_ref() else {
+        res.status_code(StatusCode::FORBIDDEN);
+        res.render("Webhooks need REPLEX_WEBHOOK_SECRET to be set");
+        return Ok(());
+    };
+    let secret = req.query::<String>("secret").unwrap_or_default();
+    if !secret_matches(&secret, webhook_secret) {
+        res.status_code(StatusCode::UNAUTHORIZED);
+        return Ok(());
+    }
+
+    let Some(raw) = req.form::<String>("payload").await else {
+        tracing::warn!("Webhook without payload");
+        res.status_code(StatusCode::BAD_REQUEST);
+        return Ok(());
+    };
+    let payload: webhooks::Payload = match serde_json::from_str(&raw) {
+        Ok(payload) => payload,
+        Err(error) => {
+            tracing::warn!(error = %error, "Failed to parse webhook payload");
+            res.status_code(StatusCode::BAD_REQUEST);
+            return Ok(());
+        }
+    };
 
-    // watchlist();
+    webhooks::handle(payload);
     res.render(());
-    return Ok(());
+    Ok(())
+}
+
+#[handler]
+async fn metrics(res: &mut Response) {
+    match crate::metrics::render().await {
+        Ok(body) => {
+            res.add_header(CONTENT_TYPE, "text/plain; version=0.0.4", true).ok();
+            res.render(body);
+        }
+        Err(error) => {
+            tracing::error!(error = %error, "Failed to render metrics");
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+}
+
+/// Constant time comparison, so the time taken does not leak the secret.
+fn secret_matches(given: &str, secret: &str) -> bool {
+    given.len() == secret.len() && openssl::memcmp::eq(given.as_bytes(), secret.as_bytes())
+}
+
+/// Only allows requests with the admin token of the `token` setting.
+#[handler]
+async fn admin_auth(req: &mut Request, res: &mut Response, ctrl: &mut FlowCtrl) {
+    let config = Config::current();
+    let Some(admin_token) = config.token.as_ref() else {
+        res.status_code(StatusCode::FORBIDDEN);
+        res.render("Admin api needs REPLEX_TOKEN to be set");
+        ctrl.skip_rest();
+        return;
+    };
+    let token = req
+        .header::<String>("X-Plex-Token")
+        .or_else(|| req.query::<String>("X-Plex-Token"))
+        .unwrap_or_default();
+    if !secret_matches(&token, admin_token) {
+        res.status_code(StatusCode::UNAUTHORIZED);
+        ctrl.skip_rest();
+    }
+}
+
+#[handler]
+async fn admin_cache_stats(res: &mut Response) {
+    res.render(Json(serde_json::json!({
+        "global": GLOBAL_CACHE.stats().await,
+        "plex_client": crate::plex_client::cache_stats().await,
+        "responses": RESPONSE_CACHE.stats().await,
+    })));
+}
+
+/// Purges the entries matching the `prefix` params, or everything without them.
+#[handler]
+async fn admin_cache_purge(req: &mut Request, res: &mut Response) {
+    let prefixes: Vec<String> = req
+        .queries()
+        .get_vec("prefix")
+        .cloned()
+        .unwrap_or_default();
+    let result = match prefixes.is_empty() {
+        true => GLOBAL_CACHE.clear().await.and_then(|_| {
+            crate::plex_client::invalidate_cache_prefixes(vec![String::new()])
+        }),
+        false => GLOBAL_CACHE.invalidate_prefixes(prefixes.clone()).and_then(|_| {
+            crate::plex_client::invalidate_cache_prefixes(prefixes.clone())
+        }),
+    };
+    // responses are built from the purged entries
+    RESPONSE_CACHE.clear();
+
+    match result {
+        Ok(_) => {
+            tracing::info!(prefixes = ?prefixes, "Purged caches");
+            res.render(Json(serde_json::json!({ "purged": prefixes })));
+        }
+        Err(error) => {
+            tracing::error!(error = %error, "Failed to purge caches");
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
 }
 
 #[handler]
@@ -484,22 +660,66 @@ pub async fn hero_image(
     ctrl: &mut FlowCtrl,
     depot: &mut Depot,
 ) {
-    let context: PlexContext = req.extract().await.unwrap();
-    let t = req.param::<String>("type").unwrap();
-    let uuid = req.param::<String>("uuid").unwrap();
-
-    let plex_client = PlexClient::from_context(&context);
-    let url = plex_client.get_hero_art(uuid).await;
-    if url.is_none() {
+    let Some(uuid) = req.param::<String>("uuid") else {
         res.status_code(StatusCode::NOT_FOUND);
-        return
-    }
+        return;
+    };
+    let plex_client = match request_client(req).await {
+        Ok((_, plex_client)) => plex_client,
+        Err(error) => {
+            tracing::warn!(error = %error, "Cannot load hero image");
+            res.status_code(error_status(&error));
+            return;
+        }
+    };
+    let Some(url) = plex_client.get_hero_art(uuid).await else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
     // let uri = url.unwrap().parse::<http::Uri>().unwrap();;
     // req.set_uri(uri);
     // let proxy = proxy("https://metadata-static.plex.tv".to_string());
     // proxy.handle(req, depot, res, ctrl).await;
 
-    res.render(Redirect::found(url.unwrap()));
+    res.render(Redirect::found(url));
+}
+
+/// Counts and notifies the decision the client gets, see `metrics::observe_playback_decision`.
+#[handler]
+async fn observe_decision(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+    ctrl: &mut FlowCtrl,
+) {
+    let context: Option<PlexContext> = req.extract().await.ok();
+    ctrl.call_next(req, depot, res).await;
+    if !res.status_code.map_or(true, |s| s.is_success()) {
+        return;
+    }
+    let bytes = match res.take_bytes(None).await {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            tracing::warn!(error = %error, "Failed to read decision response");
+            return;
+        }
+    };
+    let container = match get_content_type_from_headers(res.headers_mut()) {
+        ContentType::Json => from_bytes(bytes.clone()).ok().map(|c| c.media_container),
+        ContentType::Xml => std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|xml| yaserde::de::from_str::<MediaContainer>(xml).ok()),
+    };
+    match container {
+        Some(container) => {
+            crate::metrics::observe_playback_decision(&container);
+            if let Some(context) = &context {
+                notify(NotifyEvent::playback_decision(context, &container));
+            }
+        }
+        None => tracing::debug!("Cannot read decision response for metrics"),
+    }
+    res.body(bytes);
 }
 
 // if directplay fails we remove it.
@@ -510,9 +730,15 @@ pub async fn direct_stream_fallback(
     ctrl: &mut FlowCtrl,
     depot: &mut Depot,
 ) -> Result<(), anyhow::Error> {
-    let config: Config = Config::dynamic(req).extract().unwrap();
-    let context: PlexContext = req.extract().await.unwrap();
-    let plex_client = PlexClient::from_context(&context);
+    let config = Config::dynamic(req);
+    // without a context the request goes to plex as it is
+    let context: PlexContext = match req.extract().await {
+        Ok(context) => context,
+        Err(error) => {
+            tracing::debug!(error = %error, "Cannot read playback context");
+            return Ok(());
+        }
+    };
     let queries = req.queries().clone();
 
     let direct_play = queries
@@ -527,18 +753,31 @@ pub async fn direct_stream_fallback(
     let mut res_upstream = &mut Response::new();
     proxy_for_transform.handle(req, depot, res_upstream, ctrl).await;
 
-    match res_upstream.status_code.unwrap() {
+    match res_upstream.status_code.unwrap_or(StatusCode::OK) {
         http::StatusCode::OK => {
             let container: MediaContainerWrapper<MediaContainer> =
-            //from_reqwest_response(upstream_res).await?;
-            from_salvo_response(res_upstream).await?;
-    
+                match from_salvo_response(res_upstream).await {
+                    Ok(container) => container,
+                    Err(error) => {
+                        tracing::warn!(error = %error, "Cannot read direct play decision, passing the request through");
+                        return Ok(());
+                    }
+                };
+
             if container.media_container.general_decision_code.is_some()
                 && container.media_container.general_decision_code.unwrap() == 2000
             {
                 tracing::debug!(
                     "Direct play not avaiable, falling back to direct stream"
                 );
+                notify(NotifyEvent::new(
+                    NotifyEventKind::DirectStreamFallback,
+                    &context,
+                    serde_json::json!({
+                        "path": context.path,
+                        "reason": container.media_container.general_decision_text,
+                    }),
+                ));
                 add_query_param_salvo(req, "directPlay".to_string(), "0".to_string());
                 add_query_param_salvo(req, "directStream".to_string(), "1".to_string());
             };
@@ -548,15 +787,22 @@ pub async fn direct_stream_fallback(
             tracing::debug!(
                 "Got 400 bad request, falling back to direct stream"
             );
+            notify(NotifyEvent::new(
+                NotifyEventKind::DirectStreamFallback,
+                &context,
+                serde_json::json!({
+                    "path": context.path,
+                    "reason": "bad request",
+                }),
+            ));
             add_query_param_salvo(req, "directPlay".to_string(), "0".to_string());
             add_query_param_salvo(req, "directStream".to_string(), "1".to_string());   
             //return Ok(());   
         },
         status => {
-            tracing::error!(status = ?status, res = ?res_upstream, "Failed to get plex response");
-            return Err(
-                salvo::http::StatusError::internal_server_error().into()
-            );
+            // plex answers the unchanged request itself
+            tracing::warn!(status = ?status, res = ?res_upstream, "Unexpected direct play decision, passing the request through");
+            return Ok(());
         }
     };
     //res = &mut Response::new();
@@ -568,21 +814,51 @@ pub async fn transform_hubs_response(
     req: &mut Request,
     res: &mut Response,
 ) -> Result<(), anyhow::Error> {
-    let context: PlexContext = req.extract().await.unwrap();
-    let plex_client = PlexClient::from_context(&context);
     let content_type = get_content_type_from_headers(req.headers_mut());
+    let pipeline = if req.uri().path().starts_with(PLEX_HUBS_PROMOTED) {
+        Pipeline::HubsPromoted
+    } else {
+        Pipeline::HubsSections
+    };
 
+    // pass the upstream response through when it is not a media container, ex plex errors
+    let bytes = res.take_bytes(None).await?;
     let mut container: MediaContainerWrapper<MediaContainer> =
-        from_salvo_response(res).await?;
+        match from_bytes(bytes.clone()) {
+            Ok(container) => container,
+            Err(error) => {
+                tracing::warn!(error = %error, status = ?res.status_code, "Cannot transform hubs, passing the response through");
+                res.body(bytes);
+                return Ok(());
+            }
+        };
+    let (context, plex_client) = match request_client(req).await {
+        Ok(client) => client,
+        Err(error) => {
+            tracing::warn!(error = %error, "Cannot transform hubs, passing the response through");
+            res.body(bytes);
+            return Ok(());
+        }
+    };
     container.content_type = content_type;
 
+    let params = PipelineParams {
+        section_id: req.param::<i64>("id"),
+        ..Default::default()
+    };
+    let sections: Vec<i64> = match params.section_id {
+        Some(id) => vec![id],
+        None => context
+            .content_directory_id
+            .iter()
+            .flatten()
+            .filter_map(|id| id.parse().ok())
+            .collect(),
+    };
+    warmer::record(&context, sections);
+
     TransformBuilder::new(plex_client, context.clone())
-        .with_transform(HubRestrictionTransform)
-        .with_transform(HubStyleTransform { is_home: true })
-        .with_transform(HubWatchedTransform)
-        .with_transform(HubInterleaveTransform)
-        .with_transform(UserStateTransform)
-        .with_transform(HubKeyTransform)
+        .with_pipeline(pipeline, params)
         .apply_to(&mut container)
         .await;
 
@@ -596,14 +872,20 @@ pub async fn transform_req_content_directory(
     res: &mut Response,
     ctrl: &mut FlowCtrl
 ) {
-    let config: Config = Config::dynamic(req).extract().unwrap();
-    let context: PlexContext = req.extract().await.unwrap();
-    let plex_client = PlexClient::from_context(&context);
+    let config = Config::dynamic(req);
+    let context: PlexContext = match req.extract().await {
+        Ok(context) => context,
+        Err(error) => {
+            tracing::debug!(error = %error, "Cannot read content directory context");
+            return;
+        }
+    };
     let content_type = get_content_type_from_headers(req.headers_mut());
 
-    if context.clone().pinned_content_directory_id.is_some()
-        && context.clone().content_directory_id.unwrap()[0]
-            != context.clone().pinned_content_directory_id.unwrap()[0]
+    let first = |ids: &Option<Vec<String>>| ids.as_ref().and_then(|ids| ids.first().cloned());
+    if context.pinned_content_directory_id.is_some()
+        && first(&context.content_directory_id)
+            != first(&context.pinned_content_directory_id)
     {
         // We only fill the first one.
         let mut container: MediaContainerWrapper<MediaContainer> =
@@ -649,16 +931,16 @@ pub async fn transform_req_android(
     req: &mut Request,
     res: &mut Response,
 ) {
-    let config: Config = Config::dynamic(req).extract().unwrap();
-    let context: PlexContext = req.extract().await.unwrap();
-    
-    let mut count = context.clone().count.unwrap_or(25);
-    match context.platform.unwrap() {
-        Platform::Android => count = 50,
-        _ => (),
-    }
-    // Hack, as the list could be smaller when removing watched items. So we request more.
-    if config.exclude_watched && count < 50 {
+    let context: PlexContext = match req.extract().await {
+        Ok(context) => context,
+        Err(error) => {
+            tracing::debug!(error = %error, "Cannot read android context");
+            return;
+        }
+    };
+
+    let mut count = context.count.unwrap_or(25);
+    if matches!(context.platform, Some(Platform::Android)) {
         count = 50;
     }
 
@@ -673,52 +955,52 @@ pub async fn get_collections_children(
     _depot: &mut Depot,
     res: &mut Response,
 ) -> Result<(), anyhow::Error> {
-    let config: Config = Config::dynamic(req).extract().unwrap();
-    let context: PlexContext = req.extract().await.unwrap();
-    let collection_ids = req.param::<String>("ids").unwrap();
-    let collection_ids: Vec<u32> = collection_ids
+    let config = Config::dynamic(req);
+    let (context, plex_client) = match request_client(req).await {
+        Ok(client) => client,
+        Err(error) => {
+            tracing::warn!(error = %error, "Cannot load collection children");
+            res.status_code(error_status(&error));
+            return Ok(());
+        }
+    };
+    let collection_ids: Vec<u32> = req
+        .param::<String>("ids")
+        .unwrap_or_default()
         .split(',')
-        .filter(|&v| !v.parse::<u32>().is_err())
-        .map(|v| v.parse().unwrap())
+        .filter_map(|v| v.parse().ok())
         .collect();
-    let plex_client = PlexClient::from_context(&context);
     let content_type = get_content_type_from_headers(req.headers_mut());
 
-    // We dont listen to pagination. We have a hard max of 250 per collection
-    let mut limit: i32 = 250;
-    let mut offset: i32 = 0;
-
-    // in we dont remove watched then we dont need to limit
-    if !config.exclude_watched {
-        limit = context.container_size.unwrap_or(50);
-        offset = context.container_start.unwrap_or(0);
-    }
+    // the window is filled with unwatched items when excluding watched
+    let limit = context.container_size.unwrap_or(50);
+    let offset = context.container_start.unwrap_or(0);
 
     // create a stub
     let mut container: MediaContainerWrapper<MediaContainer> =
         MediaContainerWrapper::default();
     container.content_type = content_type;
-    let size = container.media_container.children().len();
-    container.media_container.size = Some(size.try_into().unwrap());
+    container.media_container.size = Some(container.media_container.children().len() as i64);
     container.media_container.offset = Some(offset);
 
     // filtering of watched happens in the transform
+    // keys without a strategy are single collections or round robin
+    let strategy = req
+        .param::<InterleaveStrategy>("strategy")
+        .unwrap_or_default();
+    let params = PipelineParams {
+        collection_ids,
+        offset,
+        limit,
+        strategy,
+        hub: context.content_directory_id.is_some() // its a guessing game
+            && !context.include_collections
+            && !context.include_advanced
+            && !context.exclude_all_leaves,
+        ..Default::default()
+    };
     TransformBuilder::new(plex_client, context.clone())
-        .with_transform(LibraryInterleaveTransform {
-            collection_ids: collection_ids.clone(),
-            offset,
-            limit,
-        })
-        .with_transform(HubRestrictionTransform)
-        .with_transform(CollectionStyleTransform {
-            collection_ids: collection_ids.clone(),
-            hub: context.content_directory_id.is_some() // its a guessing game
-                && !context.include_collections
-                && !context.include_advanced
-                && !context.exclude_all_leaves,
-        })
-        .with_transform(UserStateTransform)
-        //.with_transform(MediaContainerScriptingTransform)
+        .with_pipeline(Pipeline::CollectionChildren, params)
         .apply_to(&mut container)
         .await;
 
@@ -732,26 +1014,37 @@ pub async fn default_transform(
     _depot: &mut Depot,
     res: &mut Response,
 ) -> Result<(), anyhow::Error> {
-    let config: Config = Config::dynamic(req).extract().unwrap();
-    let context: PlexContext = req.extract().await.unwrap();
-    let plex_client = PlexClient::from_context(&context);
+    let config = Config::dynamic(req);
+    let (context, plex_client) = match request_client(req).await {
+        Ok(client) => client,
+        Err(error) => {
+            tracing::warn!(error = %error, "Cannot load replex path");
+            res.status_code(error_status(&error));
+            return Ok(());
+        }
+    };
     let content_type = get_content_type_from_headers(req.headers_mut());
-    let style = req.param::<Style>("style").unwrap();
-    let rest_path = req.param::<String>("**rest").unwrap();
-
-    // We dont listen to pagination. We have a hard max of 250 per collection
-    let mut limit: i32 = 250;
-    let mut offset: i32 = 0;
+    let (Some(style), Some(rest_path)) = (
+        req.param::<Style>("style"),
+        req.param::<String>("**rest"),
+    ) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(());
+    };
 
-    // in we dont remove watched then we dont need to limit
-    if !config.exclude_watched {
-        limit = context.container_size.unwrap_or(50);
-        offset = context.container_start.unwrap_or(0);
+    let limit = context.container_size.unwrap_or(50);
+    let offset = context.container_start.unwrap_or(0);
+
+    // virtual hubs page through their configured plex path
+    let virtual_hub = rest_path
+        .strip_prefix("virtual/")
+        .and_then(|id| config.virtual_hubs.iter().find(|h| h.id == id));
+    let mut url = Url::parse(req.uri_mut().to_string().as_str())?;
+    match virtual_hub {
+        Some(virtual_hub) => url = virtual_hub_url(virtual_hub, &url)?,
+        None => url.set_path(&rest_path),
     }
-
-    let mut url = Url::parse(req.uri_mut().to_string().as_str()).unwrap();
-    url.set_path(&rest_path);
-    req.set_uri(hyper::Uri::try_from(url.as_str()).unwrap());
+    req.set_uri(hyper::Uri::try_from(url.as_str())?);
     
     
     // patch, plex seems to pass wrong contentdirid, probaply cause we all load it inti the first
@@ -760,26 +1053,24 @@ pub async fn default_transform(
     replace_query(queries, req);
 
     let upstream_res = plex_client.request(req).await?;
-    match upstream_res.status() {
-        reqwest::StatusCode::OK => (),
-        status => {
-            tracing::error!(status = ?status, res = ?upstream_res, req = ?req, "Failed to get plex response");
-            return Err(
-                salvo::http::StatusError::internal_server_error().into()
-            );
-        }
-    };
+    if let Some(error) = UpstreamError::from_status(upstream_res.status()) {
+        tracing::error!(error = %error, req = ?req, "Failed to get plex response");
+        res.status_code(error.status());
+        return Ok(());
+    }
 
     let mut container: MediaContainerWrapper<MediaContainer> =
         from_reqwest_response(upstream_res).await?;
     container.content_type = content_type;
 
+    let params = PipelineParams {
+        offset,
+        limit,
+        style: Some(style),
+        ..Default::default()
+    };
     TransformBuilder::new(plex_client, context.clone())
-        .with_transform(HubRestrictionTransform)
-        .with_transform(MediaStyleTransform { style: style })
-        .with_transform(UserStateTransform)
-        .with_transform(HubWatchedTransform)
-        .with_transform(HubKeyTransform)
+        .with_pipeline(Pipeline::Default, params)
         .apply_to(&mut container)
         .await;
 
@@ -789,9 +1080,15 @@ pub async fn default_transform(
 
 #[handler]
 pub async fn get_library_item_metadata(req: &mut Request, res: &mut Response) {
-    let config: Config = Config::dynamic(req).extract().unwrap();
-    let context: PlexContext = req.extract().await.unwrap();
-    let plex_client = PlexClient::from_context(&context);
+    let config = Config::dynamic(req);
+    let (context, plex_client) = match request_client(req).await {
+        Ok(client) => client,
+        Err(error) => {
+            tracing::warn!(error = %error, uri = ?req.uri(), "Cannot load library metadata");
+            res.status_code(error_status(&error));
+            return;
+        }
+    };
     let content_type = get_content_type_from_headers(req.headers_mut());
 
     if config.disable_related {
@@ -802,7 +1099,19 @@ pub async fn get_library_item_metadata(req: &mut Request, res: &mut Response) {
         );
     }
 
-    let upstream_res = plex_client.request(req).await.unwrap();
+    let upstream_res = match plex_client.request(req).await {
+        Ok(r) => r,
+        Err(error) => {
+            tracing::error!(error = ?error, uri = ?req.uri(), "Failed to get plex response");
+            res.status_code(StatusCode::BAD_GATEWAY);
+            return;
+        }
+    };
+    if let Some(error) = UpstreamError::from_status(upstream_res.status()) {
+        tracing::error!(error = %error, uri = ?req.uri(), "Failed to get plex response");
+        res.status_code(error.status());
+        return;
+    }
     let mut container: MediaContainerWrapper<MediaContainer> =
         match from_reqwest_response(upstream_res).await {
             Ok(r) => r,
@@ -815,499 +1124,26 @@ pub async fn get_library_item_metadata(req: &mut Request, res: &mut Response) {
     container.content_type = content_type;
 
     TransformBuilder::new(plex_client, context.clone())
-        //.with_transform(MediaContainerScriptingTransform)
+        .with_pipeline(Pipeline::Metadata, PipelineParams::default())
         .apply_to(&mut container)
         .await;
     // dbg!(container.media_container.count);
     res.render(container);
 }
 
-// const RESOLUTIONS: HashMap<&'static str, &'static str> =
-//     HashMap::from([("1080p", "1920x1080"), ("4k", "4096x2160")]);
-
+/// Rewrites decision and start requests with the matching playback policies, see `policy`.
 #[handler]
-async fn force_maximum_quality(req: &mut Request) -> Result<(), anyhow::Error> {
-    let context: PlexContext = req.extract().await.unwrap();
-    let plex_client = PlexClient::from_context(&context);
-    let config: Config = Config::dynamic(req).extract().unwrap();
-    let mut queries = req.queries().clone();
-
-    if queries.get("maxVideoBitrate").is_none() && queries.get("videoBitrate").is_none() {
-        return Ok(())
-    }
-
-    queries.remove("maxVideoBitrate");
-    queries.remove("videoBitrate");
-    queries.remove("autoAdjustQuality");
-    queries.insert("autoAdjustQuality".to_string(), "0".to_string());
-    queries.remove("directStream");
-    queries.insert("directStream".to_string(), "1".to_string());
-    queries.remove("directPlay");
-    queries.insert("directPlay".to_string(), "1".to_string());
-    queries.remove("videoQuality");
-    queries.insert("videoQuality".to_string(), "100".to_string());
-    // queries.insert("directStreamAudio".to_string(), "0".to_string());
-    //queries.remove("videoResolution");
-    //queries.insert("videoResolution".to_string(), "4096x2160".to_string());
-
-    // some clients send wrong buffer format
-    if let Some(size) = queries.remove("mediaBufferSize") {
-        queries.insert(
-            "mediaBufferSize".to_string(),
-            (size[0].parse::<f32>().unwrap() as i64).to_string(),
-        );
-    }
-    // if let Some(i) = req.queries().get("protocol") {
-    //     if i == "http" {
-    //         queries.remove("copyts");
-    //         queries.insert("copyts".to_string(), "0".to_string());
-    //         queries.remove("hasMDE");
-    //         queries.insert("hasMDE".to_string(), "0".to_string());
-    //     }
-    // }
-
-    let query_key = "X-Plex-Client-Profile-Extra".to_string();
-    if queries.contains_key(&query_key) {
-        let extra = &queries.remove(&query_key.clone()).unwrap()[0];
-
-        let filtered_extra = extra
-            .split("+")
-            .filter(|s| {
-                !s.contains("add-limitation")
-                    && !s.to_lowercase().contains("name=video.bitrate")
-            })
-            .join("+");
-
-        queries.insert(query_key, filtered_extra);
-    };
-
-    if config.force_direct_play_for.is_some() && queries.get("path").is_some() {
-        let resos = config.force_direct_play_for.unwrap();
-        let item = plex_client
-            .clone()
-            .get_item_by_key(req.queries().get("path").unwrap().to_string())
-            .await
-            .unwrap();
-
-        let media_index: usize = if req.queries().get("mediaIndex").is_none()
-            || req.queries().get("mediaIndex").unwrap() == "-1"
-        {
-            0
-        } else {
-            req.queries()
-                .get("mediaIndex")
-                .unwrap()
-                .parse::<usize>()
-                .unwrap()
-        };
-
-        let media_item =
-            item.media_container.metadata[0].media[media_index].clone();
-
-        for reso in resos {
-            if let Some(video_resolution) = media_item.video_resolution.clone()
-            {
-                if video_resolution.to_lowercase() == reso.to_lowercase() {
-                    queries.remove("directPlay");
-                    queries.insert("directPlay".to_string(), "1".to_string());
-                    queries.remove("videoResolution");
-                    // queries.insert(
-                    //     "videoResolution".to_string(),
-                    //     RESOLUTIONS.get(&reso.to_lowercase()),
-                    // );
-                }
-            }
-        }
-    }
-
-    replace_query(queries, req);
-    Ok(())
-}
-
-// async fn execute_video_transcode_fallback(
-//     req: &mut Request,
-//     item: MediaContainerWrapper<MediaContainer>,
-//     media_index: usize,
-// ) -> Result<(), anyhow::Error> {
-//     let context: PlexContext = req.extract().await.unwrap();
-//     let plex_client = PlexClient::from_context(&context);
-//     let mut queries = req.queries().clone();
-//     let mut original_queries = req.queries().clone();
-
-//     let response = plex_client.request(req).await?;
-//     let mut transcode: MediaContainerWrapper<MediaContainer> =
-//         from_reqwest_response(response).await?;
-
-//     let streams =
-//         &transcode.media_container.metadata[0].media[media_index].parts[0].streams;
-//     let selected_media = transcode.media_container.metadata[0].media[media_index].clone();
-//     let mut fallback_selected = false;
-//     for stream in streams {
-//         if stream.stream_type.clone().unwrap() == 1
-//             && stream.decision.clone().unwrap_or("unknown".to_string())
-//                 == "transcode"
-//         {
-//             tracing::trace!(
-//                 "{} is transcoding, looking for fallback",
-//                 selected_media
-//             );
-//             // for now just select a random fallback
-//             for (index, media) in
-//                 item.media_container.metadata[0].media.iter().enumerate()
-//             {
-//                 if transcode.media_container.metadata[0].media[media_index].id != media.id
-//                 {
-//                     tracing::debug!(
-//                         "Video transcode fallback from {} to {}",
-//                         selected_media,
-//                         media,
-//                     );
-//                     queries.remove("mediaIndex");
-//                     queries.insert("mediaIndex".to_string(), index.to_string());
-//                     queries.remove("directPlay");
-//                     queries.insert("directPlay".to_string(), "0".to_string());
-//                     queries.remove("directStream");
-//                     queries.insert("directStream".to_string(), "1".to_string());
-//                     fallback_selected = true;
-//                     break;
-//                 }
-//             }
-//         }
-//     }
-//     if !fallback_selected {
-//         replace_query(original_queries, req);
-//     }
-//     Ok(())
-// }
-
-pub struct TranscodingStatus {
-    pub is_transcoding: bool,
-    pub decision_result: MediaContainerWrapper<MediaContainer>,
-}
-
-async fn get_transcoding_for_request(
-    req: &mut Request,
-) -> Result<TranscodingStatus, anyhow::Error> {
-    let context: PlexContext = req.extract().await.unwrap();
-    let plex_client = PlexClient::from_context(&context);
-    
-    let mut res = &mut Response::new();
-    let mut depot = &mut Depot::new();
-    let mut ctrl = &mut FlowCtrl::new(vec![]);
-    proxy_for_transform.handle(req, depot, res, ctrl).await;
-    dbg!(&res);
-    
-    let ress = plex_client.proxy_request(&req).await?;
-    dbg!(&ress);
-    //dbg!(&req);
-
-    let transcode: MediaContainerWrapper<MediaContainer> =
-        from_salvo_response(res).await?;
-    let mut is_transcoding = false;
-
-    if transcode.media_container.size.is_some()
-        && transcode.media_container.size.unwrap() == 0
-    {
-        return Ok(TranscodingStatus {
-            is_transcoding,
-            decision_result: transcode,
-        });
-    }
-
-    let streams =
-        &transcode.media_container.metadata[0].media[0].parts[0].streams;
-    // let selected_media = transcode.media_container.metadata[0].media[0].clone();
-    for stream in streams {
-        if stream.stream_type.clone().unwrap() == 1
-            && stream.decision.clone().unwrap_or("unknown".to_string())
-                == "transcode"
-        {
-            is_transcoding = true;
-            break;
-        }
-    }
-
-    Ok(TranscodingStatus {
-        is_transcoding,
-        decision_result: transcode,
-    })
-}
-
-// TODO: Fallback to a version close to the requested bitrate
-#[handler]
-async fn video_transcode_fallback(
-    req: &mut salvo::Request,
-    depot: &mut Depot,
-    res: &mut salvo::Response,
-    ctrl: &mut FlowCtrl,
-) -> Result<(), anyhow::Error> {
-    let context: PlexContext = req.extract().await.unwrap();
-    let plex_client = PlexClient::from_context(&context);
-    let config: Config = Config::dynamic(req).extract().unwrap();
-    let mut queries = req.queries().clone();
-    let original_queries = req.queries().clone();
-    let media_index: usize = if req.queries().get("mediaIndex").is_none()
-        || req.queries().get("mediaIndex").unwrap() == "-1"
-    {
-        0
-    } else {
-        req.queries()
-            .get("mediaIndex")
-            .unwrap()
-            .parse::<usize>()
-            .unwrap()
-    };
-
-    let fallback_for =
-        config.video_transcode_fallback_for.unwrap()[0].to_lowercase();
-
-    let item = plex_client
-        .clone()
-        .get_item_by_key(req.queries().get("path").unwrap().to_string())
-        .await
-        .unwrap();
-
-    if item.media_container.metadata[0].media[media_index]
-        .video_resolution
-        .clone()
-        .unwrap()
-        .to_lowercase()
-        != fallback_for
-    {
-        tracing::debug!("Media item not marked for fallback, continue playing");
-        return Ok(());
-    }
-
-    if item.media_container.metadata[0].media.len() <= 1 {
-        tracing::debug!("Nothing to fallback on, skipping fallback check");
-    } else {
-        // execute_video_transcode_fallback(req, item, media_index).await?;
-        // let response = plex_client.request(req).await?;
-        // let mut transcode: MediaContainerWrapper<MediaContainer> =
-        //     from_reqwest_response(response).await?;
-        // let streams =
-        //     &transcode.media_container.metadata[0].media[0].parts[0].streams;
-        // let selected_media =
-        //     transcode.media_container.metadata[0].media[0].clone();
-        let mut requested_bitrate: Option<i64> = None;
-        if queries.get("videoBitrate").is_some() {
-            requested_bitrate =
-                Some(queries.get("videoBitrate").unwrap().parse().unwrap());
-        } else if queries.get("maxVideoBitrate").is_some() {
-            requested_bitrate =
-                Some(queries.get("maxVideoBitrate").unwrap().parse().unwrap());
-        }
-
-        let mut fallback_selected = false;
-        // this could fail.
-        let status: TranscodingStatus =
-            get_transcoding_for_request(req).await?;
-        let selected_media = item.media_container.metadata[0].media[0].clone();
-        let mut available_media_ids: Vec<i64> = item.media_container.metadata
-            [0]
-        .media
-        .iter()
-        .map(|x| x.id)
-        .collect();
-        available_media_ids.retain(|x| *x != selected_media.id);
-        // available_media_ids.remove(selected_media.id);
-        if status.is_transcoding {
-            tracing::debug!(
-                "{} transcoding, looking for fallback",
-                selected_media
-            );
-
-            let mut media_items =
-                item.media_container.metadata[0].media.clone();
-            media_items.sort_by(|x, y| {
-                let current_density = x.height.unwrap() * x.width.unwrap();
-                let next_density = y.height.unwrap() * y.width.unwrap();
-
-                if current_density < next_density {
-                    return std::cmp::Ordering::Greater;
-                } else {
-                    return std::cmp::Ordering::Less;
-                }
-            });
-            // dbg!(&media_items.iter().map(|x| x.video_resolution.clone()));
-            // for now just select a random fallback
-            for (index, media) in media_items.iter().enumerate() {
-                if available_media_ids.contains(&media.id) {
-                    if queries.get("maxVideoBitrate").is_some()
-                        || queries.get("videoBitrate").is_some()
-                    {
-                        // tracing::trace!(
-                        //     "Video has max bitrate which always forces transcode. Forcing max quality for fallback {}",
-                        //     media,
-                        // );
-
-                        // if same resolution we can assume it will transcode again. Fallback to another resolution
-                        let resolution = media
-                            .video_resolution
-                            .clone()
-                            .unwrap()
-                            .to_lowercase();
-                        if resolution == fallback_for {
-                            continue;
-                        }
-
-                        // check if requested falls into a resolution range. Either we remove the max bitrate or allow it
-                        //let requested_bitrate: i64 = queries
-                        //    .get("videoBitrate")
-                        //    .unwrap_or_else(|| queries.get("maxVideoBitrate").unwrap()).parse().unwrap();
-
-                        //if (resolution == "1080" && requested_bitrate >= 8000)
-                        //    || (resolution == "720"
-                        //        && requested_bitrate >= 2000)
-                        //{
-                        //    force_maximum_quality
-                        //        .handle(req, depot, res, ctrl)
-                        //        .await;
-                        //    queries = req.queries().clone();
-                        //}
-                    }
-
-                    // force_maximum_quality
-                    tracing::debug!(
-                        "Video transcode fallback from {} to {}",
-                        selected_media,
-                        media,
-                    );
-                    // let mut media_queries = req.queries().clone();
-                    queries.remove("mediaIndex");
-                    queries.insert("mediaIndex".to_string(), index.to_string());
-                    queries.remove("directStream");
-                    queries.insert("directStream".to_string(), "1".to_string());
-
-                    if requested_bitrate.is_none() {
-                        queries.remove("directPlay");
-                        queries
-                            .insert("directPlay".to_string(), "1".to_string());
-                    }
-
-                    queries.remove("subtitles");
-                    queries.insert("subtitles".to_string(), "auto".to_string());
-
-                    replace_query(queries.clone(), req);
-                    // processed_media_indexes.append(selected_media.id);
-                    // available_media_ids.remove(selected_media.id);
-
-                    if media.video_resolution.clone().unwrap().to_lowercase()
-                        != fallback_for
-                    {
-                        fallback_selected = true;
-                        break;
-                    }
-
-                    let status: TranscodingStatus =
-                        get_transcoding_for_request(req).await?;
-                    available_media_ids.retain(|x| *x != media.id);
-                    if status.is_transcoding && available_media_ids.len() != 0 {
-                        tracing::debug!(
-                            "Fallback is transcoding, getting another fallback",
-                        );
-                        continue;
-                    }
-                    // let mut transcode: MediaContainerWrapper<MediaContainer> =
-                    //     from_reqwest_response(response).await?;
-                    fallback_selected = true;
-                    break;
-                }
-            }
-            if !fallback_selected {
-                tracing::debug!("No suitable fallback found");
-                replace_query(original_queries, req);
-            }
-        }
+async fn playback_policy(req: &mut Request, res: &mut Response, ctrl: &mut FlowCtrl) {
+    if let Verdict::Deny(reason) = policy::apply(req).await {
+        tracing::info!(reason = %reason, "Playback denied");
+        res.render(StatusError::forbidden().brief(reason));
+        ctrl.skip_rest();
     }
-
-    // replace_query(queries, req);
-    Ok(())
 }
 
-/// When multiple qualities are avaiable, select the most relevant one.
-/// Does not work for every client as some client decides themselfs which version to use.
 #[handler]
-async fn auto_select_version(req: &mut Request) {
-    let context: PlexContext = req.extract().await.unwrap();
-    let plex_client = PlexClient::from_context(&context);
-    let mut queries = req.queries().clone();
-    let media_index = queries.get("mediaIndex");
-
-    if media_index.is_some() && media_index.unwrap() != "-1" {
-        tracing::debug!(
-            "Skipping auto selected as client specified a media index"
-        );
-        return;
-    }
-
-    if context.screen_resolution.len() == 0 {
-        tracing::debug!(
-            "Skipping auto selected as no screen resolution has been specified"
-        );
-        return;
-    }
-
-    if queries.get("path").is_some() {
-        let item = plex_client
-            .get_item_by_key(req.queries().get("path").unwrap().to_string())
-            .await
-            .unwrap();
-
-        if item.media_container.metadata[0].media.len() <= 1 {
-            tracing::debug!(
-                "Only one media version available, skipping auto select"
-            );
-            return;
-        }
-
-        let mut requested_bitrate: Option<i64> = None;
-        if queries.get("videoBitrate").is_some() {
-            requested_bitrate =
-                Some(queries.get("videoBitrate").unwrap().parse().unwrap());
-        } else if queries.get("maxVideoBitrate").is_some() {
-            requested_bitrate =
-                Some(queries.get("maxVideoBitrate").unwrap().parse().unwrap());
-        }
-
-        let mut media = item.media_container.metadata[0].media.clone();
-        let device_density = context.screen_resolution[0].height
-            * context.screen_resolution[0].width;
-        if media.len() > 1 {
-            media.sort_by(|x, y| {
-                let current_density = x.height.unwrap() * x.width.unwrap();
-                let next_density = y.height.unwrap() * y.width.unwrap();
-                let q = current_density - device_density;
-                let qq = next_density - device_density;
-
-                if q > qq {
-                    return std::cmp::Ordering::Greater;
-                } else {
-                    return std::cmp::Ordering::Less;
-                }
-            })
-        }
-
-        for (index, m) in
-            item.media_container.metadata[0].media.iter().enumerate()
-        {
-            if m.id == media[0].id {
-                tracing::debug!("Auto selected {}", m);
-                queries.remove("mediaIndex");
-                queries.insert("mediaIndex".to_string(), index.to_string());
-                // directPlay is meant for the first media item
-                if requested_bitrate.is_none() {
-                    queries.remove("directPlay");
-                    queries.insert("directPlay".to_string(), "1".to_string());
-                }
-
-                queries.remove("subtitles");
-                queries.insert("subtitles".to_string(), "auto".to_string());
-
-            }
-        }
-    }
-    replace_query(queries, req);
+async fn client_overrides(req: &mut Request) {
+    client_profile::apply_overrides(req).await;
 }
 
 #[handler]
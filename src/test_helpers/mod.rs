@@ -1,4 +1,18 @@
+use crate::{models::PlexContext, plex_client::PlexClient};
 use httpmock::prelude::*;
+use moka::future::Cache;
+
+/// Client for a plex host that refuses connections, so every lookup fails.
+pub(crate) fn offline_client(context: &PlexContext) -> PlexClient {
+    PlexClient {
+        http_client: reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build(),
+        context: context.clone(),
+        host: "http://127.0.0.1:9".to_string(),
+        cache: Cache::new(10),
+        default_headers: reqwest::header::HeaderMap::new(),
+    }
+}
+
 pub(crate) fn get_mock_server() -> MockServer {
     // let config: Config = Config::figment().extract().unwrap();
     // dbg!(config);
@@ -0,0 +1,153 @@
+use crate::{
+    config::Config,
+    models::*,
+    plex_client::{PlexClient},
+};
+
+use super::interleave::DedupeMode;
+use super::Transform;
+use async_trait::async_trait;
+use regex::{Regex, RegexBuilder};
+use std::collections::HashSet;
+
+/// Shows an item at most once across the hubs of a screen,
+/// when `dedupe` is set to `screen`.
+#[derive(Default, Debug)]
+pub struct DedupeTransform;
+
+#[async_trait]
+impl Transform for DedupeTransform {
+    async fn transform_mediacontainer(
+        &self,
+        mut item: MediaContainer,
+        plex_client: PlexClient,
+        options: PlexContext,
+    ) -> MediaContainer {
+        let config = Config::for_context(&options);
+        if config.dedupe != DedupeMode::Screen || !item.is_hub() {
+            return item;
+        }
+
+        let priority: Vec<Regex> = config
+            .dedupe_priority
+            .iter()
+            .flatten()
+            .filter_map(|p| {
+                // patterns are validated when loading the config
+                RegexBuilder::new(p).case_insensitive(true).build().ok()
+            })
+            .collect();
+        dedupe_hubs(&mut item, &priority);
+        item
+    }
+}
+
+/// Remove items shown by an earlier hub, hubs matching earlier `priority`
+/// patterns claim their items first. Hubs left empty are removed.
+fn dedupe_hubs(item: &mut MediaContainer, priority: &[Regex]) {
+    let rank = |hub: &MetaData| {
+        priority
+            .iter()
+            .position(|p| {
+                p.is_match(&hub.title)
+                    || hub.hub_identifier.as_ref().is_some_and(|id| p.is_match(id))
+            })
+            .unwrap_or(priority.len())
+    };
+
+    // hubs keep their position, only the order of claiming items changes
+    let mut order: Vec<usize> = (0..item.hub.len()).collect();
+    order.sort_by_cached_key(|i| rank(&item.hub[*i]));
+
+    let mut seen = HashSet::new();
+    let mut emptied = HashSet::new();
+    for i in order {
+        let hub = &mut item.hub[i];
+        let children = hub.children();
+        if children.is_empty() {
+            continue;
+        }
+        let children: Vec<MetaData> = children
+            .into_iter()
+            .filter(|c| c.dedupe_key().map_or(true, |k| seen.insert(k)))
+            .collect();
+        if children.is_empty() {
+            emptied.insert(i);
+        }
+        hub.set_children(children);
+    }
+
+    // remove hubs that only had duplicates
+    let mut i = 0;
+    item.hub.retain(|_| {
+        i += 1;
+        !emptied.contains(&(i - 1))
+    });
+    item.size = Some(item.hub.len() as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hub(title: &str, guids: &[&str]) -> MetaData {
+        MetaData {
+            title: title.to_string(),
+            hub_identifier: Some(format!("custom.{}", title.to_lowercase())),
+            metadata: guids
+                .iter()
+                .map(|guid| MetaData {
+                    guid: Some(guid.to_string()),
+                    ..MetaData::default()
+                })
+                .collect(),
+            ..MetaData::default()
+        }
+    }
+
+    fn guids(hub: &mut MetaData) -> Vec<String> {
+        hub.children().into_iter().filter_map(|c| c.guid).collect()
+    }
+
+    #[test]
+    fn test_dedupe_hubs() {
+        let mut item = MediaContainer {
+            hub: vec![
+                hub("Continue Watching", &["a", "b"]),
+                hub("Recently Added", &["b", "c"]),
+                hub("Popular", &["a", "c"]),
+            ],
+            ..MediaContainer::default()
+        };
+        dedupe_hubs(&mut item, &[]);
+
+        // the last hub only had duplicates
+        assert_eq!(item.hub.len(), 2);
+        assert_eq!(item.size, Some(2));
+        assert_eq!(guids(&mut item.hub[0]), vec!["a", "b"]);
+        assert_eq!(guids(&mut item.hub[1]), vec!["c"]);
+    }
+
+    #[test]
+    fn test_dedupe_hubs_priority() {
+        let mut item = MediaContainer {
+            hub: vec![
+                hub("Continue Watching", &["a", "b", "e"]),
+                hub("Recently Added", &["b", "c"]),
+                hub("Popular", &["a", "d"]),
+            ],
+            ..MediaContainer::default()
+        };
+        let priority = vec![
+            RegexBuilder::new("popular").case_insensitive(true).build().unwrap(),
+            RegexBuilder::new("^custom.recently").build().unwrap(),
+        ];
+        dedupe_hubs(&mut item, &priority);
+
+        // popular claims first, then recently added by identifier, then the rest
+        assert_eq!(item.hub.len(), 3);
+        assert_eq!(guids(&mut item.hub[0]), vec!["e"]);
+        assert_eq!(guids(&mut item.hub[1]), vec!["b", "c"]);
+        assert_eq!(guids(&mut item.hub[2]), vec!["a", "d"]);
+    }
+}
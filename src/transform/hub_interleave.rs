@@ -5,9 +5,11 @@ use crate::{
     utils::*,
 };
 
+use super::interleave::{
+    dedupe, group_key, interleave, section_size, DedupeMode, InterleaveStrategy,
+};
 use super::Transform;
 use async_trait::async_trait;
-use itertools::Itertools;
 
 #[derive(Default, Debug)]
 pub struct HubInterleaveTransform;
@@ -20,21 +22,28 @@ impl Transform for HubInterleaveTransform {
         plex_client: PlexClient,
         options: PlexContext,
     ) -> MediaContainer {
-        let config: Config = Config::figment().extract().unwrap();
+        let config = Config::for_context(&options);
         let mut new_hubs: Vec<MetaData> = vec![];
         
         if !config.interleave {
             return item;
         }
 
+        // children of the hubs merged into new_hubs[i], with their weight
+        let mut group_children: Vec<Vec<(Vec<MetaData>, u64)>> = vec![];
+        let mut group_keys: Vec<Option<String>> = vec![];
+        let strategy = config.interleave_strategy;
+
         for hub in item.children_mut() {
-            if hub.size.unwrap() == 0 {
+            if hub.size.unwrap_or(0) == 0 {
                 continue;
             }
 
             // we only process collection hubs
             if !hub.is_collection_hub() {
                 new_hubs.push(hub.to_owned());
+                group_children.push(vec![]);
+                group_keys.push(None);
                 continue;
             }
 
@@ -43,27 +52,57 @@ impl Transform for HubInterleaveTransform {
             // hub.placeholder = Some(SpecialBool::new(true));
             //hub.placeholder = Some(true);
 
-            let p = new_hubs.iter().position(|v| v.title == hub.title);
+            let group_key = group_key(hub, &config, plex_client.clone()).await;
+            let weight = match strategy {
+                InterleaveStrategy::Weighted => {
+                    section_size(hub_section_id(hub), plex_client.clone()).await
+                }
+                _ => 1,
+            };
+            let p = group_keys
+                .iter()
+                .position(|k| k.as_ref() == Some(&group_key));
             // if hub.r#type != "clip" {
             //     hub.r#type = "mixed".to_string();
             // }
             match p {
                 Some(v) => {
-                    new_hubs[v].key = Some(merge_children_keys(
-                        new_hubs[v].key.clone().unwrap(),
-                        hub.key.clone().unwrap(),
-                    ));
-                    let c = new_hubs[v].children();
-                    new_hubs[v].set_children(
-                        c.into_iter()
-                            .interleave(hub.children())
-                            .collect::<Vec<MetaData>>(),
-                    );
+                    if let (Some(key), Some(hub_key)) = (new_hubs[v].key.clone(), hub.key.clone()) {
+                        new_hubs[v].key = Some(merge_children_keys(key, hub_key, strategy));
+                    }
+                    group_children[v].push((hub.children(), weight));
+                }
+                None => {
+                    new_hubs.push(hub.to_owned());
+                    group_children.push(vec![(hub.children(), weight)]);
+                    group_keys.push(Some(group_key));
                 }
-                None => new_hubs.push(hub.to_owned()),
+            }
+        }
+
+        for (i, children) in group_children.into_iter().enumerate() {
+            if children.len() > 1 {
+                let seed = new_hubs[i].key.clone().unwrap_or_default();
+                let mut merged = interleave(children, strategy, &seed);
+                if config.dedupe != DedupeMode::Off {
+                    merged = dedupe(merged);
+                }
+                new_hubs[i].set_children(merged);
             }
         }
         item.set_children_mut(&mut new_hubs);
         item
     }
-}
\ No newline at end of file
+}
+
+/// Section of a collection hub, ex `custom.collection.2.1234`
+fn hub_section_id(hub: &MetaData) -> Option<i64> {
+    hub.library_section_id.or_else(|| {
+        hub.hub_identifier
+            .as_ref()?
+            .split('.')
+            .nth(2)?
+            .parse()
+            .ok()
+    })
+}
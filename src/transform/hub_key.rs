@@ -19,13 +19,12 @@ impl Transform for HubKeyTransform {
         options: PlexContext,
     ) {
 
-        if item.is_hub()
-            && item.key.is_some()
-            && !item.key.clone().unwrap().starts_with("/replex")
-        {
-            // might already been set by the mixings
+        if !item.is_hub() {
+            return;
+        }
+        // might already been set by the mixings
+        if let Some(old_key) = item.key.clone().filter(|k| !k.starts_with("/replex")) {
             // setting an url argument crashes client. So we use the path
-            let old_key = item.key.clone().unwrap();
             item.key = Some(format!(
                 "/replex/{}{}",
                 item.style
@@ -0,0 +1,71 @@
+use crate::{
+    config::{Config, HubOrderConfig},
+    models::*,
+    plex_client::{PlexClient},
+};
+
+use super::Transform;
+use async_trait::async_trait;
+use regex::{Regex, RegexBuilder};
+
+/// Pins, reorders and hides hubs based on the `hub_order` setting.
+#[derive(Default, Debug)]
+pub struct HubOrderTransform;
+
+#[async_trait]
+impl Transform for HubOrderTransform {
+    async fn transform_mediacontainer(
+        &self,
+        mut item: MediaContainer,
+        plex_client: PlexClient,
+        options: PlexContext,
+    ) -> MediaContainer {
+        let config = Config::for_context(&options);
+        if !item.is_hub() {
+            return item;
+        }
+
+        let hubs = std::mem::take(&mut item.hub);
+        item.hub = order_hubs(hubs, &config.hub_order);
+        item.size = Some(item.hub.len() as i64);
+        item
+    }
+}
+
+fn compile(patterns: &Option<Vec<String>>) -> Vec<Regex> {
+    patterns
+        .iter()
+        .flatten()
+        .filter_map(|p| {
+            // patterns are validated when loading the config
+            RegexBuilder::new(p).case_insensitive(true).build().ok()
+        })
+        .collect()
+}
+
+/// Index of the first pattern matching the hub.
+fn position(patterns: &[Regex], hub: &MetaData) -> Option<usize> {
+    patterns.iter().position(|p| {
+        p.is_match(&hub.title)
+            || hub.hub_identifier.as_ref().is_some_and(|id| p.is_match(id))
+    })
+}
+
+/// Hidden hubs are removed, then pinned hubs go first, ordered hubs second
+/// and the rest keeps the plex order.
+pub fn order_hubs(mut hubs: Vec<MetaData>, config: &HubOrderConfig) -> Vec<MetaData> {
+    let hide = compile(&config.hide);
+    let pin = compile(&config.pin);
+    let order = compile(&config.order);
+
+    hubs.retain(|hub| position(&hide, hub).is_none());
+    // stable, so hubs with the same key keep their order
+    hubs.sort_by_cached_key(|hub| {
+        match (position(&pin, hub), position(&order, hub)) {
+            (Some(i), _) => (0, i),
+            (None, Some(i)) => (1, i),
+            (None, None) => (2, 0),
+        }
+    });
+    hubs
+}
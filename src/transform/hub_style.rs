@@ -185,7 +185,7 @@ impl Transform for HubStyleTransform {
         plex_client: PlexClient,
         options: PlexContext,
     ) {
-        let config: Config = Config::figment().extract().unwrap();
+        let config = Config::for_context(&options);
         let style = item.style.clone().unwrap_or("".to_string()).to_owned();
 
         if item.is_hub() {
@@ -208,8 +208,8 @@ impl Transform for HubStyleTransform {
                 // let now = Instant::now();
 
                 for mut child in item.children() {
-                    if style.child_type.clone().is_some() {
-                        child.r#type = style.child_type.clone().unwrap();
+                    if let Some(child_type) = style.child_type.clone() {
+                        child.r#type = child_type;
                     }
 
                     let client = plex_client.clone();
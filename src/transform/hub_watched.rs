@@ -2,6 +2,7 @@ use crate::{
     config::Config,
     models::*,
     plex_client::{PlexClient},
+    utils::*,
 };
 use super::Transform;
 use async_trait::async_trait;
@@ -17,7 +18,7 @@ impl Transform for HubWatchedTransform {
         plex_client: PlexClient,
         options: PlexContext,
     ) {
-        let config: Config = Config::figment().extract().unwrap();
+        let config = Config::for_context(&options);
 
         if item.is_hub() {
             let exclude_watched = item
@@ -25,9 +26,30 @@ impl Transform for HubWatchedTransform {
                 .await
                 .unwrap_or(false);
 
-            if exclude_watched {
-                item.children_mut().retain(|x| !x.is_watched());
+            if !exclude_watched {
+                return;
             }
+
+            // top up collection hubs so they keep the requested size
+            if let Some(collection_id) =
+                get_collection_id_from_hub(item).filter(|_| item.is_collection_hub())
+            {
+                let count = item.children().len() as i32;
+                let collection_id = collection_id as i64;
+                match plex_client
+                    .load_collection_children(collection_id, 0, count, true)
+                    .await
+                {
+                    Ok(window) => {
+                        item.set_children(window.children);
+                        return;
+                    }
+                    Err(error) => {
+                        tracing::warn!(collection = collection_id, error = %error, "Failed to top up hub");
+                    }
+                }
+            }
+            item.children_mut().retain(|x| !x.is_watched());
         }
     }
 }
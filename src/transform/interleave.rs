@@ -0,0 +1,453 @@
+use crate::{
+    config::Config,
+    models::*,
+    plex_client::{PlexClient},
+    utils::*,
+};
+use regex::RegexBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+use strum_macros::Display as EnumDisplay;
+use strum_macros::EnumString;
+
+/// Label prefix to group collection hubs by, ex `REPLEX_GROUP:Marvel`
+pub const GROUP_LABEL_PREFIX: &str = "REPLEX_GROUP:";
+
+/// How the children of interleaved hubs are merged.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, EnumString, EnumDisplay, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum InterleaveStrategy {
+    /// one item of each hub in turn
+    #[default]
+    RoundRobin,
+    /// items in proportion to the library size
+    Weighted,
+    /// newest first
+    AddedAt,
+    /// newest release first
+    OriginallyAvailableAt,
+    /// shuffled, stable for a day
+    Random,
+}
+
+/// Removing items that are in multiple hubs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupeMode {
+    #[default]
+    Off,
+    /// within interleaved hubs
+    Interleave,
+    /// within interleaved hubs and across all hubs of a screen
+    Screen,
+}
+
+/// How hubs are grouped for interleaving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterleaveGroup {
+    /// hubs with the same title
+    #[default]
+    Title,
+    /// collections with the same `REPLEX_GROUP:<name>` label, others by title
+    Label,
+    /// hubs with a title matching the same `interleave_patterns` pattern, others by title
+    Regex,
+}
+
+/// Items shuffled together by `Random`. Windows are rounded up to this
+/// size, see `prefix_size`.
+const SHUFFLE_CHUNK: i32 = 50;
+
+/// Merge the lists with the given strategy. Weights are only used by `Weighted`,
+/// `seed` makes `Random` differ per hub.
+///
+/// Merging the first n items of each list gives the same first n merged items
+/// as merging the full lists, so windows can be loaded a page at the time.
+pub fn interleave(
+    lists: Vec<(Vec<MetaData>, u64)>,
+    strategy: InterleaveStrategy,
+    seed: &str,
+) -> Vec<MetaData> {
+    match strategy {
+        InterleaveStrategy::RoundRobin => round_robin(lists),
+        InterleaveStrategy::Weighted => weighted(lists),
+        InterleaveStrategy::AddedAt => merge_by(lists, |i| i.added_at),
+        InterleaveStrategy::OriginallyAvailableAt => {
+            // dates are yyyy-mm-dd so compare as strings
+            merge_by(lists, |i| i.originally_available_at.clone())
+        }
+        InterleaveStrategy::Random => {
+            let mut items = round_robin(lists);
+            shuffle_chunks(&mut items, daily_seed(seed));
+            items
+        }
+    }
+}
+
+/// Shuffle every `SHUFFLE_CHUNK` items on its own, so a chunk only depends on
+/// the items in it.
+fn shuffle_chunks(items: &mut [MetaData], seed: u64) {
+    for (i, chunk) in items.chunks_mut(SHUFFLE_CHUNK as usize).enumerate() {
+        shuffle(chunk, seed.wrapping_add(i as u64));
+    }
+}
+
+/// Remove items seen before, by guid or rating key.
+pub fn dedupe(items: Vec<MetaData>) -> Vec<MetaData> {
+    let mut seen = std::collections::HashSet::new();
+    items
+        .into_iter()
+        .filter(|i| i.dedupe_key().map_or(true, |k| seen.insert(k)))
+        .collect()
+}
+
+/// Items to load of each list to fill the first `count` merged items.
+pub fn prefix_size(strategy: InterleaveStrategy, count: i32) -> i32 {
+    match strategy {
+        // whole chunks, otherwise the last chunk shuffles different per page
+        InterleaveStrategy::Random => {
+            (count + SHUFFLE_CHUNK - 1) / SHUFFLE_CHUNK * SHUFFLE_CHUNK
+        }
+        _ => count,
+    }
+}
+
+/// Repeatedly take the newest head of the lists. Gives a sorted result
+/// when the lists are sorted, like smart collections sorted by date.
+fn merge_by<K: Ord>(
+    lists: Vec<(Vec<MetaData>, u64)>,
+    key: impl Fn(&MetaData) -> K,
+) -> Vec<MetaData> {
+    let mut iters: Vec<_> = lists
+        .into_iter()
+        .map(|(l, _)| l.into_iter().peekable())
+        .collect();
+    let mut items = vec![];
+    loop {
+        // first list wins on equal keys
+        let pick = (0..iters.len())
+            .filter_map(|i| iters[i].peek().map(|item| (key(item), std::cmp::Reverse(i))))
+            .max()
+            .map(|(_, std::cmp::Reverse(i))| i);
+        match pick {
+            Some(i) => items.push(iters[i].next().unwrap()),
+            None => return items,
+        }
+    }
+}
+
+fn round_robin(lists: Vec<(Vec<MetaData>, u64)>) -> Vec<MetaData> {
+    let mut iters: Vec<_> = lists.into_iter().map(|(l, _)| l.into_iter()).collect();
+    let mut items = vec![];
+    loop {
+        let before = items.len();
+        for iter in iters.iter_mut() {
+            if let Some(item) = iter.next() {
+                items.push(item);
+            }
+        }
+        if items.len() == before {
+            return items;
+        }
+    }
+}
+
+/// Smooth weighted round robin, bigger lists are picked more often.
+fn weighted(lists: Vec<(Vec<MetaData>, u64)>) -> Vec<MetaData> {
+    let weights: Vec<i64> = lists.iter().map(|(_, w)| (*w).max(1) as i64).collect();
+    let mut iters: Vec<_> = lists
+        .into_iter()
+        .map(|(l, _)| l.into_iter().peekable())
+        .collect();
+    let mut credits = vec![0i64; iters.len()];
+    let mut items = vec![];
+    loop {
+        let active: Vec<usize> = (0..iters.len())
+            .filter(|i| iters[*i].peek().is_some())
+            .collect();
+        if active.is_empty() {
+            return items;
+        }
+        let total: i64 = active.iter().map(|i| weights[*i]).sum();
+        for i in &active {
+            credits[*i] += weights[*i];
+        }
+        let pick = *active.iter().max_by_key(|i| (credits[**i], std::cmp::Reverse(**i))).unwrap();
+        credits[pick] -= total;
+        items.push(iters[pick].next().unwrap());
+    }
+}
+
+/// Same seed for the whole day, so paging sees the same order.
+fn daily_seed(seed: &str) -> u64 {
+    let day = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    (seed, day).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Fisher-Yates with splitmix64, no need for a rand crate.
+fn shuffle(items: &mut [MetaData], mut state: u64) {
+    let mut next = || {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    };
+    for i in (1..items.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// Key hubs with the same key are interleaved on.
+pub async fn group_key(
+    hub: &MetaData,
+    config: &Config,
+    plex_client: PlexClient,
+) -> String {
+    let label = match config.interleave_group {
+        InterleaveGroup::Label => group_label(hub, plex_client).await,
+        _ => None,
+    };
+    hub_group_key(hub, config, label)
+}
+
+/// `group_key` with the group label of the collection already loaded.
+fn hub_group_key(hub: &MetaData, config: &Config, label: Option<String>) -> String {
+    match config.interleave_group {
+        InterleaveGroup::Title => {}
+        InterleaveGroup::Label => {
+            if let Some(label) = label {
+                return format!("label:{}", label.to_lowercase());
+            }
+        }
+        InterleaveGroup::Regex => {
+            let matched = config.interleave_patterns.iter().flatten().position(|p| {
+                // patterns are validated when loading the config
+                RegexBuilder::new(p)
+                    .case_insensitive(true)
+                    .build()
+                    .is_ok_and(|r| r.is_match(&hub.title))
+            });
+            if let Some(i) = matched {
+                return format!("pattern:{}", i);
+            }
+        }
+    }
+    format!("title:{}", hub.title)
+}
+
+async fn group_label(hub: &MetaData, plex_client: PlexClient) -> Option<String> {
+    if !hub.is_collection_hub() {
+        return None;
+    }
+    let collection_id = get_collection_id_from_hub(hub)?;
+    let mut collection = plex_client
+        .clone()
+        .get_cached(
+            plex_client.get_collection(collection_id),
+            format!("collection:{}", collection_id),
+        )
+        .await
+        .ok()?;
+    let children = collection.media_container.children();
+    label_group(&children.first()?.labels)
+}
+
+/// Name of the `REPLEX_GROUP:<name>` label.
+fn label_group(labels: &[Label]) -> Option<String> {
+    labels.iter().find_map(|l| {
+        l.tag
+            .strip_prefix(GROUP_LABEL_PREFIX)
+            .map(|name| name.trim().to_string())
+    })
+}
+
+/// Amount of items in the section, used as weight.
+pub async fn section_size(section_id: Option<i64>, plex_client: PlexClient) -> u64 {
+    let Some(section_id) = section_id else {
+        return 1;
+    };
+    plex_client
+        .clone()
+        .get_cached(
+            plex_client.get_section_size(section_id),
+            format!("sectionsize:{}", section_id),
+        )
+        .await
+        .ok()
+        .and_then(|c| c.media_container.total_size)
+        .unwrap_or(1) as u64
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::providers::{Format, Toml};
+    use figment::Figment;
+
+    fn item(key: &str) -> MetaData {
+        MetaData {
+            rating_key: Some(key.to_string()),
+            ..MetaData::default()
+        }
+    }
+
+    fn list(keys: &[&str], weight: u64) -> (Vec<MetaData>, u64) {
+        (keys.iter().map(|k| item(k)).collect(), weight)
+    }
+
+    fn keys(items: &[MetaData]) -> Vec<String> {
+        items.iter().filter_map(|i| i.rating_key.clone()).collect()
+    }
+
+    fn config(toml: &str) -> Config {
+        Figment::from(Toml::string(toml)).extract().unwrap()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let merged = round_robin(vec![list(&["a1", "a2", "a3"], 1), list(&["b1"], 1), list(&["c1", "c2"], 1)]);
+        assert_eq!(keys(&merged), vec!["a1", "b1", "c1", "a2", "c2", "a3"]);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let merged = weighted(vec![list(&["a1", "a2", "a3", "a4", "a5"], 3), list(&["b1", "b2", "b3"], 1)]);
+        assert_eq!(keys(&merged), vec!["a1", "a2", "b1", "a3", "a4", "a5", "b2", "b3"]);
+    }
+
+    #[test]
+    fn test_merge_by() {
+        let dated = |key: &str, added_at: i64, released: &str| MetaData {
+            added_at: Some(added_at),
+            originally_available_at: Some(released.to_string()),
+            ..item(key)
+        };
+        let lists = vec![
+            (vec![dated("a1", 30, "2020-01-01"), dated("a2", 10, "2024-01-01")], 1),
+            (vec![dated("b1", 20, "2023-01-01"), dated("b2", 10, "2019-01-01")], 1),
+        ];
+
+        let merged = interleave(lists.clone(), InterleaveStrategy::AddedAt, "");
+        // first list wins on equal keys
+        assert_eq!(keys(&merged), vec!["a1", "b1", "a2", "b2"]);
+        // only the heads are compared, lists keep their own order
+        let merged = interleave(lists, InterleaveStrategy::OriginallyAvailableAt, "");
+        assert_eq!(keys(&merged), vec!["b1", "a1", "a2", "b2"]);
+    }
+
+    #[test]
+    fn test_shuffle_fixed_seed() {
+        let items: Vec<MetaData> = (0..120).map(|i| item(&i.to_string())).collect();
+        let mut first = items.clone();
+        let mut second = items.clone();
+        shuffle_chunks(&mut first, 42);
+        shuffle_chunks(&mut second, 42);
+        assert_eq!(keys(&first), keys(&second));
+        assert_ne!(keys(&first), keys(&items));
+
+        // chunks keep their own items
+        for (shuffled, original) in first
+            .chunks(SHUFFLE_CHUNK as usize)
+            .zip(items.chunks(SHUFFLE_CHUNK as usize))
+        {
+            let mut shuffled = keys(shuffled);
+            let mut original = keys(original);
+            shuffled.sort();
+            original.sort();
+            assert_eq!(shuffled, original);
+        }
+
+        let mut other = items.clone();
+        shuffle_chunks(&mut other, 43);
+        assert_ne!(keys(&first), keys(&other));
+    }
+
+    #[test]
+    fn test_prefix_invariant() {
+        let full: Vec<(Vec<MetaData>, u64)> = (0..3)
+            .map(|l| ((0..120).map(|i| item(&format!("{}-{}", l, i))).collect(), l + 1))
+            .collect();
+        for strategy in [
+            InterleaveStrategy::RoundRobin,
+            InterleaveStrategy::Weighted,
+            InterleaveStrategy::Random,
+        ] {
+            let merged = interleave(full.clone(), strategy, "seed");
+            for count in [1, 10, 60] {
+                let size = prefix_size(strategy, count) as usize;
+                let prefixes = full
+                    .iter()
+                    .map(|(l, w)| (l[..size].to_vec(), *w))
+                    .collect();
+                let window = interleave(prefixes, strategy, "seed");
+                assert_eq!(
+                    keys(&window[..count as usize]),
+                    keys(&merged[..count as usize]),
+                    "{} {}",
+                    strategy,
+                    count
+                );
+            }
+        }
+        assert_eq!(prefix_size(InterleaveStrategy::Random, 60), 100);
+    }
+
+    #[test]
+    fn test_group_key_label() {
+        let config = config("interleave_group = 'label'");
+        let hub = MetaData {
+            title: "Marvel Movies".to_string(),
+            ..MetaData::default()
+        };
+        assert_eq!(
+            hub_group_key(&hub, &config, Some("Marvel".to_string())),
+            "label:marvel"
+        );
+        // without the label hubs are grouped by title
+        assert_eq!(hub_group_key(&hub, &config, None), "title:Marvel Movies");
+
+        let labels: Vec<Label> = serde_json::from_str(
+            r#"[{"id": 1, "tag": "Favorites", "filter": ""}, {"id": 2, "tag": "REPLEX_GROUP: Marvel ", "filter": ""}]"#,
+        )
+        .unwrap();
+        assert_eq!(label_group(&labels), Some("Marvel".to_string()));
+        assert_eq!(label_group(&labels[..1]), None);
+    }
+
+    #[test]
+    fn test_group_key_regex() {
+        let config = config(
+            r#"
+            interleave_group = "regex"
+            interleave_patterns = ["^Trending", "^Top (10|rated)"]
+            "#,
+        );
+        let hub = |title: &str| MetaData {
+            title: title.to_string(),
+            ..MetaData::default()
+        };
+        assert_eq!(hub_group_key(&hub("Trending Movies"), &config, None), "pattern:0");
+        assert_eq!(hub_group_key(&hub("top rated shows"), &config, None), "pattern:1");
+        assert_eq!(hub_group_key(&hub("Top 10 Movies"), &config, None), "pattern:1");
+        assert_eq!(hub_group_key(&hub("Recently Added"), &config, None), "title:Recently Added");
+        // labels are only used in label mode
+        assert_eq!(
+            hub_group_key(&hub("Recently Added"), &config, Some("x".to_string())),
+            "title:Recently Added"
+        );
+    }
+}
@@ -1,17 +1,20 @@
 use crate::{
     config::Config,
     models::*,
-    plex_client::{PlexClient},
+    plex_client::{CollectionWindow, PlexClient},
+};
+use super::interleave::{
+    dedupe, interleave, prefix_size, section_size, DedupeMode, InterleaveStrategy,
 };
 use super::Transform;
 use async_trait::async_trait;
-use itertools::Itertools;
 
 #[derive(Default, Debug, Clone)]
 pub struct LibraryInterleaveTransform {
     pub collection_ids: Vec<u32>,
     pub offset: i32,
     pub limit: i32,
+    pub strategy: InterleaveStrategy,
 }
 
 #[async_trait]
@@ -22,70 +25,133 @@ impl Transform for LibraryInterleaveTransform {
         plex_client: PlexClient,
         options: PlexContext,
     ) -> MediaContainer {
-        let config: Config = Config::figment().extract().unwrap();
-        if !config.interleave {
-            return item;
-        }
-        let mut children: Vec<MetaData> = vec![];
+        let mut sources: Vec<(u32, bool, u64)> = vec![];
         let mut total_size = 0;
 
         for id in self.collection_ids.clone() {
-            let collection = plex_client
+            // should have proper errors but lets assume not found so no access
+            let collection = match plex_client
                 .clone()
                 .get_cached(
                     plex_client.get_collection(id as i32),
-                    format!("collection:{}", id.to_string()),
+                    format!("collection:{}", id),
                 )
                 .await
-                .unwrap();
-        
-            //match c {
-            //    Ok(v) =>,
-            //    Err(err) =>
-            //}
-        
-            let mut c = plex_client
-                .clone()
-                .get_cached(
-                    plex_client.get_collection_children(
-                        id as i64,
-                        Some(self.offset),
-                        Some(self.limit),
-                    ),
-                    format!(
-                        "get_collection_children:{}:{}:{}",
-                        id, self.offset, self.limit
-                    ),
-                )
-                .await
-                .unwrap();
-            
-            // should have proper errors but lets assume not found so no access
-            //match c {
-            //    Ok(v) =>,
-            //    Err(err) =>
-            //}
+            {
+                Ok(collection) => collection,
+                Err(error) => {
+                    tracing::warn!(collection = id, error = %error, "Failed to load collection");
+                    continue;
+                }
+            };
 
+            let exclude_watched =
+                collection.media_container.exclude_watched(&options);
+            let metadata = collection.media_container.metadata.first();
+            total_size += match metadata {
+                Some(metadata) => plex_client
+                    .get_collection_total_size(
+                        metadata,
+                        collection.media_container.library_section_id,
+                        exclude_watched,
+                    )
+                    .await
+                    .unwrap_or(0),
+                None => 0,
+            };
 
-            if collection.media_container.exclude_watched() {
-                c.media_container.children_mut().retain(|x| !x.is_watched());
-            }
+            let weight = match self.strategy {
+                InterleaveStrategy::Weighted => {
+                    section_size(
+                        collection.media_container.library_section_id,
+                        plex_client.clone(),
+                    )
+                    .await
+                }
+                _ => 1,
+            };
+            sources.push((id, exclude_watched, weight));
+        }
 
-            total_size += c.media_container.children().len() as i32;
+        // a single collection pages through its own children
+        if let [(id, exclude_watched, _)] = sources[..] {
+            if self.strategy != InterleaveStrategy::Random {
+                let window = match plex_client
+                    .load_collection_children(id as i64, self.offset, self.limit, exclude_watched)
+                    .await
+                {
+                    Ok(window) => window,
+                    Err(error) => {
+                        tracing::warn!(collection = id, error = %error, "Failed to load collection children");
+                        CollectionWindow::default()
+                    }
+                };
+                item.total_size = Some(window.total_size.unwrap_or(total_size).max(
+                    self.offset.max(0) + window.children.len() as i32,
+                ));
+                item.metadata = window.children;
+                item.offset = Some(self.offset);
+                item.size = Some(item.metadata.len() as i64);
+                return item;
+            }
+        }
 
-            match children.is_empty() {
-                false => {
-                    children = children
-                        .into_iter()
-                        .interleave(c.media_container.children())
-                        .collect::<Vec<MetaData>>();
+        // seed without the offset, so all pages use the same order
+        let seed = format!("{:?}", self.collection_ids);
+        let dedupe_items = Config::for_context(&options).dedupe != DedupeMode::Off;
+        let end = self.offset.max(0) + self.limit.max(0);
+        // every list can fill the whole window, so load the window end of each.
+        // duplicates shrink the window, then load more until it is full again.
+        let mut count = end.max(1);
+        let (merged, removed, complete) = loop {
+            let load = prefix_size(self.strategy, count);
+            let mut lists: Vec<(Vec<MetaData>, u64)> = vec![];
+            let mut complete = true;
+            for (id, exclude_watched, weight) in sources.iter() {
+                let children = match plex_client
+                    .load_collection_children(*id as i64, 0, load, *exclude_watched)
+                    .await
+                {
+                    Ok(window) => window.children,
+                    Err(error) => {
+                        tracing::warn!(collection = id, error = %error, "Failed to load collection children");
+                        vec![]
+                    }
+                };
+                if children.len() as i32 >= load {
+                    complete = false;
                 }
-                true => children.append(&mut c.media_container.children()),
+                lists.push((children, *weight));
             }
-        }
-        item.total_size = Some(total_size);
-        // always metadata
-        item.metadata = children;
+
+            let mut merged = interleave(lists, self.strategy, &seed);
+            if !complete {
+                // only this prefix is the same as merging the full lists
+                merged.truncate(load as usize);
+            }
+            let size = merged.len();
+            if dedupe_items {
+                merged = dedupe(merged);
+            }
+            if complete || merged.len() as i32 >= end {
+                let removed = (size - merged.len()) as i32;
+                break (merged, removed, complete);
+            }
+            count *= 2;
+        };
+
+        item.total_size = Some(match complete {
+            true => merged.len() as i32,
+            // duplicates further on are not known yet
+            false => (total_size - removed).max(merged.len() as i32),
+        });
+        item.metadata = merged
+            .into_iter()
+            .skip(self.offset.max(0) as usize)
+            .take(self.limit.max(0) as usize)
+            .collect();
+        item.offset = Some(self.offset);
+        item.size = Some(item.metadata.len() as i64);
         item
     }
-}
\ No newline at end of file
+}
@@ -33,46 +33,47 @@ impl Transform for MediaStyleTransform {
     ) {
         if self.style == Style::Hero {
             let style_def = ClientHeroStyle::from_context(options.clone());
-            if style_def.child_type.clone().is_some() {
-                item.r#type = style_def.child_type.clone().unwrap();
+            if let Some(child_type) = style_def.child_type.clone() {
+                item.r#type = child_type;
             }
 
-            let mut guid = item.guid.clone().unwrap();
-            if guid.starts_with("plex://episode") && item.parent_guid.is_some() {    
-                guid = item.parent_guid.clone().unwrap();
+            let Some(mut guid) = item.guid.clone() else {
+                return;
+            };
+            if guid.starts_with("plex://episode") {
+                if let Some(parent_guid) = item.parent_guid.clone() {
+                    guid = parent_guid;
+                }
             }
             guid = guid.replace("plex://", "");
 
+            let (Some(host), Some(token)) = (
+                options.forwarded_host.clone().or(options.host.clone()),
+                options.token.clone(),
+            ) else {
+                return;
+            };
             //let cover_art = Some(format!("https://metadata-static.plex.tv/7/gracenote/779d16f22ad2f3a002937133f8744e5d.jpg"));
             // let cover_art = Some(format!("/replex/image/hero/{}?X-Plex-Token={}", 
-            let cover_art = Some(format!("{}://{}/replex/image/hero/{}?X-Plex-Token={}", 
-                match options.forwarded_proto {
-                    Some(v) => v,
-                    None => "http".to_string()
-                },
-                match options.forwarded_host {
-                    Some(v) => v,
-                    None => options.host.clone().unwrap()
-                },
+            let cover_art = format!("{}://{}/replex/image/hero/{}?X-Plex-Token={}", 
+                options.forwarded_proto.clone().unwrap_or("http".to_string()),
+                host,
                 guid,
-                options.token.clone().unwrap()
-            ));
-            //dbg!(&cover_art);
-            if cover_art.is_some() {
-                // c.art = art.clone();
-                item.images = vec![Image {
-                    r#type: "coverArt".to_string(),
-                    url: cover_art.clone().unwrap(),
-                    alt: Some(item.title.clone()),
-                }];
-                // lots of clients dont listen to the above
-                if style_def.cover_art_as_art {
-                    item.art = cover_art.clone();
-                }
+                token
+            );
+            // c.art = art.clone();
+            item.images = vec![Image {
+                r#type: "coverArt".to_string(),
+                url: cover_art.clone(),
+                alt: Some(item.title.clone()),
+            }];
+            // lots of clients dont listen to the above
+            if style_def.cover_art_as_art {
+                item.art = Some(cover_art.clone());
+            }
 
-                if style_def.cover_art_as_thumb {
-                    item.thumb = cover_art.clone();
-                }
+            if style_def.cover_art_as_thumb {
+                item.thumb = Some(cover_art);
             }
         }
         // item
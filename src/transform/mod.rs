@@ -1,26 +1,37 @@
 pub mod collection_style;
+pub mod dedupe;
 pub mod hub_interleave;
+pub mod interleave;
 pub mod hub_watched;
 pub mod user_state;
 pub mod hub_key;
 pub mod hub_limit;
+pub mod hub_order;
 pub mod media_style;
 pub mod hub_section_directory;
 pub mod hub_style;
 pub mod library_interleave;
+pub mod pipeline;
 pub mod restrictions;
+pub mod scripting;
+pub mod virtual_hubs;
 
 pub use collection_style::CollectionStyleTransform;
+pub use dedupe::DedupeTransform;
 pub use hub_interleave::HubInterleaveTransform;
 pub use user_state::UserStateTransform;
 pub use hub_watched::HubWatchedTransform;
 pub use hub_key::HubKeyTransform;
 pub use hub_limit::HubChildrenLimitTransform;
+pub use hub_order::HubOrderTransform;
 pub use media_style::MediaStyleTransform;
 pub use hub_section_directory::HubSectionDirectoryTransform;
 pub use hub_style::{ClientHeroStyle, HubStyleTransform};
 pub use library_interleave::LibraryInterleaveTransform;
+pub use pipeline::{Pipeline, PipelineParams};
 pub use restrictions::HubRestrictionTransform;
+pub use scripting::{ScriptEndpoint, ScriptingTransform};
+pub use virtual_hubs::{virtual_hub_url, VirtualHubTransform};
 
 use crate::{
     models::*,
@@ -34,9 +45,16 @@ use futures_util::{
     StreamExt,
 };
 use std::sync::Arc;
+use tracing::Instrument;
 
 #[async_trait]
 pub trait Transform: Send + Sync + 'static {
+    /// Name used in traces, the type name by default.
+    fn name(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
     async fn transform_metadata(
         &self,
         item: &mut MetaData,
@@ -101,6 +119,18 @@ impl TransformBuilder {
         self
     }
 
+    /// Add the transforms of the route pipeline, see `pipeline::Pipeline`.
+    pub fn with_pipeline(
+        mut self,
+        pipeline: Pipeline,
+        params: PipelineParams,
+    ) -> Self {
+        let config = crate::config::Config::for_context(&self.options);
+        self.transforms
+            .extend(pipeline.transforms(&config, &params));
+        self
+    }
+
     pub async fn apply_to_old(
         self,
         container: &mut MediaContainerWrapper<MediaContainer>,
@@ -137,14 +167,8 @@ impl TransformBuilder {
         }
 
         if container.media_container.size.is_some() {
-            container.media_container.size = Some(
-                container
-                    .media_container
-                    .children_mut()
-                    .len()
-                    .try_into()
-                    .unwrap(),
-            );
+            container.media_container.size =
+                Some(container.media_container.children_mut().len() as i64);
         }
     }
     
@@ -156,59 +180,67 @@ impl TransformBuilder {
         let mut idx = 0 as usize;
         let mut filter_childs = vec![];
         for t in self.transforms.clone() {
-            //dbg!(&filter_childs);
-            for child in container.media_container.children_mut() {
-                 //if filter_childs.contains(child.key.clone().unwrap()) {
-                 //  continue;
-                 //} 
-                 //dbg!(&child.rating_key);
-                 //dbg!(&child.key);
-                 if !t.filter_metadata(
-                        child.clone(),
+            let span = tracing::trace_span!(
+                "transform",
+                otel.name = t.name(),
+                transform = t.name(),
+            );
+            crate::access_log::record_transform(t.name());
+            async {
+                //dbg!(&filter_childs);
+                for child in container.media_container.children_mut() {
+                     //if filter_childs.contains(child.key.clone().unwrap()) {
+                     //  continue;
+                     //} 
+                     //dbg!(&child.rating_key);
+                     //dbg!(&child.key);
+                     if !t.filter_metadata(
+                            child.clone(),
+                            self.plex_client.clone(),
+                            self.options.clone(),
+                        )
+                        .await
+                     {
+                        //childs.remove(idx);
+                        if let Some(key) = child.key.clone() {
+                            filter_childs.push(key);
+                        }
+                        continue
+                     }
+                     t.transform_metadata(
+                                child,
+                                self.plex_client.clone(),
+                                self.options.clone(),
+                            ).await;
+                    //if 
+                    //idx = idx + 1;
+                }
+                container.media_container.children_mut().retain(|x| {
+                    x.key.as_ref().map_or(true, |key| !filter_childs.contains(key))
+                });
+                //item.children_mut().retain(|x| !x.is_watched());
+                //future::join_all(futures).await;
+
+                // dont use join as it needs ti be executed in order
+                // let l = std::cell::RefCell::new(&mut container.media_container);
+                container.media_container = t
+                    .transform_mediacontainer(
+                        container.media_container.clone(),
                         self.plex_client.clone(),
                         self.options.clone(),
                     )
-                    .await
-                 {
-                    //childs.remove(idx);
-                    filter_childs.push(child.key.clone().unwrap());
-                    continue
-                 }
-                 t.transform_metadata(
-                            child,
-                            self.plex_client.clone(),
-                            self.options.clone(),
-                        ).await;
-                //if 
-                //idx = idx + 1;
+                    .await;
+                // dbg!(container.media_container.size);
             }
-            container.media_container.children_mut().retain(|x| !x.key.is_some() || !filter_childs.contains(&x.key.clone().unwrap()));
-            //item.children_mut().retain(|x| !x.is_watched());
-            //future::join_all(futures).await;
-
-            // dont use join as it needs ti be executed in order
-            // let l = std::cell::RefCell::new(&mut container.media_container);
-            container.media_container = t
-                .transform_mediacontainer(
-                    container.media_container.clone(),
-                    self.plex_client.clone(),
-                    self.options.clone(),
-                )
-                .await;
-            // dbg!(container.media_container.size);
+            .instrument(span)
+            .await;
         }
 
         //container.media_container.set_children(childs);
 
         if container.media_container.size.is_some() {
-            container.media_container.size = Some(
-                container
-                    .media_container
-                    .children_mut()
-                    .len()
-                    .try_into()
-                    .unwrap(),
-            );
+            container.media_container.size =
+                Some(container.media_container.children_mut().len() as i64);
         }
     }
 }
@@ -277,3 +309,73 @@ pub fn hero_meta() -> Meta {
         ],
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::offline_client;
+
+    async fn apply(
+        pipeline: Pipeline,
+        params: PipelineParams,
+        media_container: MediaContainer,
+    ) -> MediaContainer {
+        let context = PlexContext {
+            token: Some("token".to_string()),
+            ..PlexContext::default()
+        };
+        let mut container = MediaContainerWrapper {
+            media_container,
+            ..MediaContainerWrapper::default()
+        };
+        TransformBuilder::new(offline_client(&context), context)
+            .with_pipeline(pipeline, params)
+            .apply_to(&mut container)
+            .await;
+        container.media_container
+    }
+
+    #[tokio::test]
+    async fn test_pipelines_pass_through_incomplete_items() {
+        let hubs = MediaContainer {
+            hub: vec![
+                MetaData {
+                    title: "No identifier".to_string(),
+                    key: Some("/hubs/1".to_string()),
+                    ..MetaData::default()
+                },
+                MetaData {
+                    title: "Broken collection".to_string(),
+                    context: Some("hub.custom.collection".to_string()),
+                    hub_identifier: Some("custom.collection".to_string()),
+                    key: Some("/library/collections/children".to_string()),
+                    size: Some(1),
+                    metadata: vec![MetaData::default()],
+                    ..MetaData::default()
+                },
+            ],
+            ..MediaContainer::default()
+        };
+        let titles = |container: MediaContainer| -> Vec<String> {
+            container.hub.into_iter().map(|h| h.title).collect()
+        };
+        // interleaving drops hubs without children
+        for pipeline in [Pipeline::HubsPromoted, Pipeline::HubsSections] {
+            let container = apply(pipeline, PipelineParams::default(), hubs.clone()).await;
+            assert_eq!(titles(container), vec!["Broken collection"]);
+        }
+        let container = apply(Pipeline::Default, PipelineParams::default(), hubs.clone()).await;
+        assert_eq!(titles(container), vec!["No identifier", "Broken collection"]);
+
+        for collection_ids in [vec![], vec![1]] {
+            let params = PipelineParams {
+                collection_ids,
+                limit: 10,
+                ..PipelineParams::default()
+            };
+            let container =
+                apply(Pipeline::CollectionChildren, params, MediaContainer::default()).await;
+            assert!(container.metadata.is_empty());
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{
+    config::{Config, PipelinesConfig},
+    models::*,
+};
+
+use super::*;
+use super::interleave::InterleaveStrategy;
+use std::sync::Arc;
+
+/// Names of the transforms that can be used in a pipeline.
+pub const TRANSFORMS: [&str; 15] = [
+    "virtual_hubs",
+    "hub_restriction",
+    "hub_style",
+    "hub_watched",
+    "hub_interleave",
+    "hub_order",
+    "dedupe",
+    "hub_children_limit",
+    "hub_section_directory",
+    "hub_key",
+    "user_state",
+    "library_interleave",
+    "collection_style",
+    "media_style",
+    "script",
+];
+
+pub const HUBS_PIPELINE: [&str; 10] = [
+    "virtual_hubs",
+    "hub_restriction",
+    "hub_style",
+    "hub_watched",
+    "hub_interleave",
+    "hub_order",
+    "dedupe",
+    "user_state",
+    "hub_key",
+    "script",
+];
+pub const COLLECTION_CHILDREN_PIPELINE: [&str; 5] = [
+    "library_interleave",
+    "hub_restriction",
+    "collection_style",
+    "user_state",
+    "script",
+];
+pub const DEFAULT_PIPELINE: [&str; 5] = [
+    "hub_restriction",
+    "media_style",
+    "user_state",
+    "hub_watched",
+    "hub_key",
+];
+pub const METADATA_PIPELINE: [&str; 1] = ["script"];
+
+/// Default children limit of `hub_children_limit` when not configured.
+const HUB_CHILDREN_LIMIT: i32 = 50;
+
+/// The routes with a configurable pipeline.
+#[derive(Debug, Clone, Copy)]
+pub enum Pipeline {
+    HubsPromoted,
+    HubsSections,
+    CollectionChildren,
+    Default,
+    Metadata,
+}
+
+/// Request specific values some transforms need.
+#[derive(Debug, Clone, Default)]
+pub struct PipelineParams {
+    pub collection_ids: Vec<u32>,
+    pub offset: i32,
+    pub limit: i32,
+    /// if collections are loaded for a hub
+    pub hub: bool,
+    pub style: Option<Style>,
+    pub strategy: InterleaveStrategy,
+    /// section of the hubs, None for home
+    pub section_id: Option<i64>,
+}
+
+impl Pipeline {
+    /// The transform names for this route, configured or default.
+    pub fn names(self, pipelines: &PipelinesConfig) -> Vec<String> {
+        let (configured, default): (&Option<Vec<String>>, &[&str]) = match self {
+            Self::HubsPromoted => (&pipelines.hubs_promoted, &HUBS_PIPELINE),
+            Self::HubsSections => (&pipelines.hubs_sections, &HUBS_PIPELINE),
+            Self::CollectionChildren => (
+                &pipelines.collection_children,
+                &COLLECTION_CHILDREN_PIPELINE,
+            ),
+            Self::Default => (&pipelines.default, &DEFAULT_PIPELINE),
+            Self::Metadata => (&pipelines.metadata, &METADATA_PIPELINE),
+        };
+        match configured {
+            Some(names) => names.clone(),
+            None => default.iter().map(|n| n.to_string()).collect(),
+        }
+    }
+
+    pub fn script_endpoint(self) -> Option<ScriptEndpoint> {
+        match self {
+            Self::HubsPromoted => Some(ScriptEndpoint::HubsPromoted),
+            Self::HubsSections => Some(ScriptEndpoint::HubsSections),
+            Self::CollectionChildren => Some(ScriptEndpoint::CollectionChildren),
+            Self::Metadata => Some(ScriptEndpoint::Metadata),
+            Self::Default => None,
+        }
+    }
+
+    pub fn transforms(
+        self,
+        config: &Config,
+        params: &PipelineParams,
+    ) -> Vec<Arc<dyn Transform>> {
+        self.names(&config.pipelines)
+            .iter()
+            .filter_map(|name| self.transform(name, config, params))
+            .collect()
+    }
+
+    /// Transform registry. Returns None for unknown names or
+    /// when the transform does not apply to this route.
+    fn transform(
+        self,
+        name: &str,
+        config: &Config,
+        params: &PipelineParams,
+    ) -> Option<Arc<dyn Transform>> {
+        let transform: Arc<dyn Transform> = match name {
+            "virtual_hubs" => Arc::new(VirtualHubTransform {
+                section_id: params.section_id,
+            }),
+            "hub_restriction" => Arc::new(HubRestrictionTransform),
+            "hub_style" => Arc::new(HubStyleTransform { is_home: true }),
+            "hub_watched" => Arc::new(HubWatchedTransform),
+            "hub_interleave" => Arc::new(HubInterleaveTransform),
+            "hub_order" => Arc::new(HubOrderTransform),
+            "dedupe" => Arc::new(DedupeTransform),
+            "hub_children_limit" => Arc::new(HubChildrenLimitTransform {
+                limit: config.hub_children_limit.unwrap_or(HUB_CHILDREN_LIMIT),
+            }),
+            "hub_section_directory" => Arc::new(HubSectionDirectoryTransform),
+            "hub_key" => Arc::new(HubKeyTransform),
+            "user_state" => Arc::new(UserStateTransform),
+            "library_interleave" => Arc::new(LibraryInterleaveTransform {
+                collection_ids: params.collection_ids.clone(),
+                offset: params.offset,
+                limit: params.limit,
+                strategy: params.strategy,
+            }),
+            "collection_style" => Arc::new(CollectionStyleTransform {
+                collection_ids: params.collection_ids.clone(),
+                hub: params.hub,
+            }),
+            "media_style" => Arc::new(MediaStyleTransform {
+                style: params.style.clone()?,
+            }),
+            "script" => Arc::new(ScriptingTransform::new(self.script_endpoint()?, config)?),
+            _ => {
+                tracing::warn!(transform = %name, "Unknown transform in pipeline");
+                return None;
+            }
+        };
+        Some(transform)
+    }
+}
@@ -17,7 +17,7 @@ impl Transform for HubRestrictionTransform {
         plex_client: PlexClient,
         options: PlexContext,
     ) -> bool {
-        let config: Config = Config::figment().extract().unwrap();
+        let config = Config::for_context(&options);
         
         if !config.hub_restrictions {
             return true;
@@ -31,41 +31,48 @@ impl Transform for HubRestrictionTransform {
             return true;
         }
         
-        if item.size.unwrap() == 0 {
+        if item.size == Some(0) {
             return false;
         }
 
-        let section_id: i64 = item.library_section_id.unwrap_or_else(|| {
-            item.hub_identifier.clone().unwrap().split('.').collect::<Vec<&str>>()[2].parse().unwrap()
-        });
+        // hub identifiers look like custom.collection.{section}.{collection}
+        let Some(hub_identifier) = item.hub_identifier.clone() else {
+            return true;
+        };
+        let Some(section_id) = item.library_section_id.or_else(|| {
+            hub_identifier.split('.').nth(2)?.parse().ok()
+        }) else {
+            return true;
+        };
 
         //let start = Instant::now();
-        let mut custom_collections = plex_client
+        let mut custom_collections = match plex_client
             .clone()
             .get_cached(
                 plex_client.get_section_collections(section_id),
                 format!("sectioncollections:{}", section_id).to_string(),
             )
             .await
-            .unwrap();
+        {
+            Ok(collections) => collections,
+            Err(error) => {
+                // keep the hub as plex returned it
+                tracing::warn!(section = section_id, error = %error, "Failed to load section collections");
+                return true;
+            }
+        };
 
         //println!("Elapsed time: {:.2?}", start.elapsed());
         let custom_collections_ids: Vec<String> = custom_collections
             .media_container
             .children()
             .iter()
-            .map(|c| c.rating_key.clone().unwrap())
+            .filter_map(|c| c.rating_key.clone())
             .collect();
 
-        custom_collections_ids.contains(
-            &item
-                .hub_identifier
-                .clone()
-                .unwrap()
-                .split('.')
-                .last()
-                .unwrap()
-                .to_owned(),
-        )
+        hub_identifier
+            .rsplit('.')
+            .next()
+            .is_some_and(|id| custom_collections_ids.iter().any(|c| c == id))
     }
 }
\ No newline at end of file
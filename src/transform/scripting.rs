@@ -1,51 +1,266 @@
-pub struct ScriptingMediaContainer {}
+use async_trait::async_trait;
+use crate::{
+    config::{Config, ScriptsConfig},
+    models::*,
+    plex_client::{PlexClient},
+};
+use once_cell::sync::Lazy;
+use rhai::{
+    serde::{from_dynamic, to_dynamic},
+    CallFnOptions, Dynamic, Engine, Scope, AST,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-impl MediaContainer {
-    pub fn get_size(&mut self) -> i64 {
-        // rhai::Dynamic::from(self.size.unwrap_or(()))
-        // to_dynamic(self.size).unwrap()
-        self.size.unwrap_or(0)
+use super::Transform;
+
+/// Limits keep a looping script from blocking a worker.
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+    let mut engine = Engine::new();
+    engine.set_max_operations(1_000_000);
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine
+});
+
+/// Compiled scripts by path, recompiled when the file changes.
+/// Scripts that failed to compile are kept as None until they change.
+static SCRIPTS: Lazy<Mutex<HashMap<String, (Option<SystemTime>, Option<Arc<AST>>)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The endpoints a script can be configured for.
+#[derive(Debug, Clone, Copy)]
+pub enum ScriptEndpoint {
+    HubsPromoted,
+    HubsSections,
+    CollectionChildren,
+    Metadata,
+}
+
+impl ScriptEndpoint {
+    pub fn script(self, scripts: &ScriptsConfig) -> Option<&String> {
+        match self {
+            Self::HubsPromoted => scripts.hubs_promoted.as_ref(),
+            Self::HubsSections => scripts.hubs_sections.as_ref(),
+            Self::CollectionChildren => scripts.collection_children.as_ref(),
+            Self::Metadata => scripts.metadata.as_ref(),
+        }
     }
-    pub fn set_size(&mut self, value: i64) {
-        self.size = Some(value);
+}
+
+/// Runs a user script on the response.
+///
+/// A script can define any of these functions:
+///
+/// - `filter_metadata(item, context)` returning false to remove the item
+/// - `transform_metadata(item, context)` returning the changed item
+/// - `transform_mediacontainer(media_container, context)` returning the changed container
+///
+/// Scripts without any of these run top level on every container,
+/// with `media_container` and `context` in scope.
+/// On errors the original is kept.
+#[derive(Debug)]
+pub struct ScriptingTransform {
+    pub path: String,
+    pub ast: Arc<AST>,
+}
+
+impl ScriptingTransform {
+    /// The script configured for the endpoint, loaded once per response.
+    /// None without a script or when it does not compile.
+    pub fn new(endpoint: ScriptEndpoint, config: &Config) -> Option<Self> {
+        let path = endpoint.script(&config.scripts)?.clone();
+        let ast = compiled(&path)?;
+        Some(Self { path, ast })
+    }
+}
+
+fn compiled(path: &str) -> Option<Arc<AST>> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut scripts = SCRIPTS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((compiled_at, ast)) = scripts.get(path) {
+        if *compiled_at == modified {
+            return ast.clone();
+        }
     }
+
+    let ast = match ENGINE.compile_file(PathBuf::from(path)) {
+        Ok(ast) => Some(Arc::new(ast)),
+        Err(error) => {
+            tracing::error!(script = %path, error = %error, "Failed to compile script");
+            None
+        }
+    };
+    scripts.insert(path.to_string(), (modified, ast.clone()));
+    ast
+}
+
+fn has_fn(ast: &AST, name: &str) -> bool {
+    ast.iter_functions().any(|f| f.name == name && f.params.len() == 2)
 }
 
-#[derive(Default, Debug)]
-pub struct MediaContainerScriptingTransform;
+/// Call a script function, converting from and to rust values.
+fn call<I: serde::Serialize, O: serde::de::DeserializeOwned>(
+    ast: &AST,
+    name: &str,
+    item: &I,
+    options: &PlexContext,
+) -> Result<O, Box<rhai::EvalAltResult>> {
+    let result: Dynamic = ENGINE.call_fn_with_options(
+        CallFnOptions::new().eval_ast(false),
+        &mut Scope::new(),
+        ast,
+        name,
+        (to_dynamic(item)?, to_dynamic(options)?),
+    )?;
+    from_dynamic(&result)
+}
+
+fn run(
+    ast: &AST,
+    item: &MediaContainer,
+    options: &PlexContext,
+) -> Result<MediaContainer, Box<rhai::EvalAltResult>> {
+    let mut scope = Scope::new();
+    scope.push("media_container", to_dynamic(item)?);
+    scope.push("context", to_dynamic(options)?);
+    ENGINE.run_ast_with_scope(&mut scope, ast)?;
+    match scope.get_value::<Dynamic>("media_container") {
+        Some(result) => from_dynamic(&result),
+        None => Err("media_container was removed from scope".into()),
+    }
+}
 
 #[async_trait]
-impl Transform for MediaContainerScriptingTransform {
+impl Transform for ScriptingTransform {
+    async fn filter_metadata(
+        &self,
+        item: MetaData,
+        plex_client: PlexClient,
+        options: PlexContext,
+    ) -> bool {
+        let (path, ast) = (&self.path, &self.ast);
+        if !has_fn(ast, "filter_metadata") {
+            return true;
+        }
+
+        call(ast, "filter_metadata", &item, &options).unwrap_or_else(|error| {
+            tracing::error!(script = %path, error = %error, "Script filter_metadata failed");
+            true
+        })
+    }
+
+    async fn transform_metadata(
+        &self,
+        item: &mut MetaData,
+        plex_client: PlexClient,
+        options: PlexContext,
+    ) {
+        let (path, ast) = (&self.path, &self.ast);
+        if !has_fn(ast, "transform_metadata") {
+            return;
+        }
+
+        match call(ast, "transform_metadata", &*item, &options) {
+            Ok(result) => *item = result,
+            Err(error) => {
+                tracing::error!(script = %path, error = %error, "Script transform_metadata failed");
+            }
+        }
+    }
+
     async fn transform_mediacontainer(
         &self,
         item: MediaContainer,
         plex_client: PlexClient,
         options: PlexContext,
     ) -> MediaContainer {
-        let config: Config = Config::figment().extract().unwrap();
-        if config.test_script.is_none() {
+        let (path, ast) = (&self.path, &self.ast);
+        let result = if has_fn(ast, "transform_mediacontainer") {
+            call(ast, "transform_mediacontainer", &item, &options)
+        } else if !has_fn(ast, "filter_metadata")
+            && !has_fn(ast, "transform_metadata")
+        {
+            run(ast, &item, &options)
+        } else {
             return item;
-        }
+        };
+
+        result.unwrap_or_else(|error| {
+            tracing::error!(script = %path, error = %error, "Script transform_mediacontainer failed");
+            item
+        })
+    }
+}
 
-        let mut media_container: Dynamic = to_dynamic(item).unwrap();
-        let mut context: Dynamic = to_dynamic(options).unwrap();
-        let mut engine = Engine::new();
 
-        engine
-            .register_type_with_name::<Dynamic>("MediaContainer")
-            .register_type_with_name::<Dynamic>("PlexContext");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::offline_client;
+    use crate::transform::TransformBuilder;
 
-        let mut scope = Scope::new();
-        scope.push("media_container", media_container);
-        scope.push("context", context);
+    async fn apply_script(name: &str, script: &str) -> Vec<String> {
+        let dir = std::env::temp_dir()
+            .join(format!("replex-scripts-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, script).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let transform = ScriptingTransform {
+            ast: compiled(&path).unwrap(),
+            path,
+        };
+        std::fs::remove_dir_all(dir).unwrap();
 
-        engine
-            .run_file_with_scope(&mut scope, config.test_script.unwrap().into())
-            .unwrap();
-        let result = from_dynamic::<MediaContainer>(
-            &scope.get_value::<Dynamic>("media_container").unwrap(),
+        let context = PlexContext::default();
+        let mut container = MediaContainerWrapper {
+            media_container: MediaContainer {
+                metadata: vec![
+                    MetaData {
+                        title: "a".to_string(),
+                        key: Some("/library/metadata/a".to_string()),
+                        ..MetaData::default()
+                    },
+                    MetaData {
+                        title: "b".to_string(),
+                        key: Some("/library/metadata/b".to_string()),
+                        ..MetaData::default()
+                    },
+                ],
+                ..MediaContainer::default()
+            },
+            ..MediaContainerWrapper::default()
+        };
+        TransformBuilder::new(offline_client(&context), context)
+            .with_transform(transform)
+            .apply_to(&mut container)
+            .await;
+        container.media_container.metadata.into_iter().map(|m| m.title).collect()
+    }
+
+    #[tokio::test]
+    async fn test_script_hooks() {
+        let titles = apply_script(
+            "hooks.rhai",
+            r#"
+            fn filter_metadata(item, context) { item.title != "b" }
+            fn transform_metadata(item, context) { item.title += "!"; item }
+            "#,
         )
-        .unwrap();
-        result
+        .await;
+        assert_eq!(titles, vec!["a!"]);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_script_operation_limit() {
+        let titles = apply_script(
+            "loop.rhai",
+            "fn transform_metadata(item, context) { loop {} }",
+        )
+        .await;
+        assert_eq!(titles, vec!["a", "b"]);
+    }
+}
@@ -17,7 +17,7 @@ impl Transform for UserStateTransform {
         plex_client: PlexClient,
         options: PlexContext,
     ) {
-        let config: Config = Config::figment().extract().unwrap();
+        let config = Config::for_context(&options);
         if !config.disable_user_state && !config.disable_leaf_count {
             return;
         }
@@ -0,0 +1,213 @@
+use crate::{
+    config::{Config, VirtualHub, VIRTUAL_HUB_PREFIX},
+    models::*,
+    plex_client::{PlexClient},
+};
+
+use super::Transform;
+use async_trait::async_trait;
+use url::Url;
+
+/// Injects the configured virtual hubs into the home or section hubs.
+#[derive(Default, Debug)]
+pub struct VirtualHubTransform {
+    /// None for the home screen
+    pub section_id: Option<i64>,
+}
+
+impl VirtualHubTransform {
+    fn applies_to(&self, hub: &VirtualHub) -> bool {
+        match self.section_id {
+            Some(id) => hub.sections.contains(&id),
+            None => hub.home,
+        }
+    }
+}
+
+#[async_trait]
+impl Transform for VirtualHubTransform {
+    async fn transform_mediacontainer(
+        &self,
+        mut item: MediaContainer,
+        plex_client: PlexClient,
+        options: PlexContext,
+    ) -> MediaContainer {
+        let config = Config::for_context(&options);
+
+        for virtual_hub in config.virtual_hubs.iter().filter(|h| self.applies_to(h)) {
+            let hub = match load_virtual_hub(virtual_hub, plex_client.clone()).await {
+                Ok(hub) => hub,
+                Err(error) => {
+                    tracing::error!(hub = %virtual_hub.id, error = %error, "Failed to load virtual hub");
+                    continue;
+                }
+            };
+            if hub.metadata.is_empty() {
+                continue;
+            }
+
+            insert_at(&mut item.hub, virtual_hub.position, hub);
+        }
+        item.size = Some(item.hub.len() as i64);
+        item
+    }
+}
+
+/// Insert at the configured index, or at the end when it is not set or beyond the hubs.
+fn insert_at(hubs: &mut Vec<MetaData>, position: Option<usize>, hub: MetaData) {
+    let position = position.unwrap_or(hubs.len()).min(hubs.len());
+    hubs.insert(position, hub);
+}
+
+/// Plex url of a page of the virtual hub: the configured path and query,
+/// with the remaining query of the client request, ex the container window.
+pub fn virtual_hub_url(virtual_hub: &VirtualHub, url: &Url) -> anyhow::Result<Url> {
+    let hub_url = url.join(&virtual_hub.path)?;
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !hub_url.query_pairs().any(|(k, _)| k == *key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    query.extend(hub_url.query_pairs().map(|(k, v)| (k.into_owned(), v.into_owned())));
+
+    let mut page_url = url.clone();
+    page_url.set_path(hub_url.path());
+    page_url.query_pairs_mut().clear().extend_pairs(&query);
+    Ok(page_url)
+}
+
+/// Section of the hub path, 0 when it spans sections. Part of the cache key
+/// so webhooks only invalidate the hubs of the changed section.
+fn section_of(path: &str) -> i64 {
+    path.strip_prefix("/library/sections/")
+        .and_then(|rest| rest.split(['/', '?']).next())
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(0)
+}
+
+async fn load_virtual_hub(
+    virtual_hub: &VirtualHub,
+    plex_client: PlexClient,
+) -> anyhow::Result<MetaData> {
+    let path = with_container_window(&virtual_hub.path, 0, virtual_hub.limit);
+    let mut container = plex_client
+        .clone()
+        .get_cached(
+            plex_client.get_item_by_key(path),
+            format!("virtualhub:{}:{}", section_of(&virtual_hub.path), virtual_hub.id),
+        )
+        .await?;
+
+    let children = container.media_container.children();
+    let size = children.len() as i32;
+    let total_size = container.media_container.total_size.unwrap_or(size);
+    let style = virtual_hub
+        .style
+        .clone()
+        .unwrap_or(Style::Shelf)
+        .to_string()
+        .to_lowercase();
+
+    Ok(MetaData {
+        title: virtual_hub.title.clone(),
+        r#type: children
+            .first()
+            .map(|c| c.r#type.clone())
+            .unwrap_or("mixed".to_string()),
+        hub_identifier: Some(format!("{}{}", VIRTUAL_HUB_PREFIX, virtual_hub.id)),
+        key: Some(format!("/replex/{}/virtual/{}", style, virtual_hub.id)),
+        context: Some("hub.custom.virtual".to_string()),
+        size: Some(size),
+        more: Some(SpecialBool::new(total_size > size)),
+        style: Some(style),
+        promoted: Some(SpecialBool::new(true)),
+        metadata: children,
+        ..Default::default()
+    })
+}
+
+/// Add the container window, plex ignores the size when start is missing.
+fn with_container_window(path: &str, start: i32, size: i32) -> String {
+    let separator = if path.contains('?') { '&' } else { '?' };
+    format!(
+        "{}{}X-Plex-Container-Start={}&X-Plex-Container-Size={}",
+        path, separator, start, size
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn virtual_hub(id: &str, home: bool, sections: Vec<i64>) -> VirtualHub {
+        VirtualHub {
+            id: id.to_string(),
+            title: id.to_string(),
+            path: "/library/sections/1/all?type=1&sort=addedAt:desc".to_string(),
+            home,
+            sections,
+            style: None,
+            limit: 20,
+            position: None,
+        }
+    }
+
+    fn hub(title: &str) -> MetaData {
+        MetaData {
+            title: title.to_string(),
+            ..MetaData::default()
+        }
+    }
+
+    fn titles(hubs: &[MetaData]) -> Vec<&str> {
+        hubs.iter().map(|h| h.title.as_str()).collect()
+    }
+
+    #[test]
+    fn test_applies_to() {
+        let home = VirtualHubTransform { section_id: None };
+        let section = VirtualHubTransform { section_id: Some(2) };
+
+        assert!(home.applies_to(&virtual_hub("a", true, vec![])));
+        assert!(!home.applies_to(&virtual_hub("b", false, vec![2])));
+        assert!(section.applies_to(&virtual_hub("b", false, vec![1, 2])));
+        assert!(!section.applies_to(&virtual_hub("c", true, vec![1])));
+    }
+
+    #[test]
+    fn test_insert_at() {
+        let mut hubs = vec![hub("a"), hub("b")];
+        insert_at(&mut hubs, Some(0), hub("first"));
+        insert_at(&mut hubs, Some(2), hub("middle"));
+        insert_at(&mut hubs, Some(10), hub("beyond"));
+        insert_at(&mut hubs, None, hub("last"));
+        assert_eq!(titles(&hubs), vec!["first", "a", "middle", "b", "beyond", "last"]);
+    }
+
+    #[test]
+    fn test_virtual_hub_url() {
+        let url = Url::parse(
+            "http://localhost/replex/shelf/virtual/new?X-Plex-Container-Start=20&X-Plex-Container-Size=10&sort=titleSort",
+        )
+        .unwrap();
+        let page = virtual_hub_url(&virtual_hub("new", true, vec![]), &url).unwrap();
+        assert_eq!(page.path(), "/library/sections/1/all");
+        let query: Vec<(String, String)> = page.query_pairs().into_owned().collect();
+        assert_eq!(
+            query,
+            vec![
+                ("X-Plex-Container-Start".to_string(), "20".to_string()),
+                ("X-Plex-Container-Size".to_string(), "10".to_string()),
+                ("type".to_string(), "1".to_string()),
+                ("sort".to_string(), "addedAt:desc".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_section_of() {
+        assert_eq!(section_of("/library/sections/3/all?type=1"), 3);
+        assert_eq!(section_of("/library/sections/3"), 3);
+        assert_eq!(section_of("/hubs/promoted"), 0);
+    }
+}
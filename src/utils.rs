@@ -6,6 +6,7 @@ use itertools::Itertools;
 use mime::Mime;
 use multimap::MultiMap;
 use crate::config::Config;
+use crate::transform::interleave::InterleaveStrategy;
 use salvo::prelude::*;
 use salvo::proxy::*;
 use salvo::Error;
@@ -59,7 +60,7 @@ pub fn salvo_url_query_getter(
 
 // Proxy to plex instance
 pub fn default_proxy() -> Proxy<String, ReqwestClient> {
-  let config: Config = Config::figment().extract().unwrap();
+  let config = Config::current();
   let mut proxy = Proxy::new(
     config.host.clone().unwrap(),
     ReqwestClient::new(reqwest::Client::builder()
@@ -113,15 +114,8 @@ pub fn get_collection_id_from_child_path(path: String) -> i32 {
     path.parse().unwrap()
 }
 
-pub fn get_collection_id_from_hub(hub: &MetaData) -> i32 {
-    hub.hub_identifier
-        .clone()
-        .unwrap()
-        .split('.')
-        .last()
-        .unwrap()
-        .parse()
-        .unwrap()
+pub fn get_collection_id_from_hub(hub: &MetaData) -> Option<i32> {
+    hub.hub_identifier.as_ref()?.rsplit('.').next()?.parse().ok()
 }
 
 pub fn replace_query(query: MultiMap<String, String>, req: &mut SalvoRequest) {
@@ -242,7 +236,7 @@ pub fn from_string(
 pub async fn from_reqwest_response(
     res: reqwest::Response,
 ) -> Result<MediaContainerWrapper<MediaContainer>, Error> {
-    let bytes = res.bytes().await.unwrap();
+    let bytes = res.bytes().await.map_err(Error::other)?;
     //dbg!(&bytes);
     from_bytes(bytes)
 }
@@ -250,7 +244,7 @@ pub async fn from_reqwest_response(
 pub async fn from_reqwest_response_mut(
     mut res: reqwest::Response,
 ) -> Result<MediaContainerWrapper<MediaContainer>, Error> {
-    let bytes = res.bytes().await.unwrap();
+    let bytes = res.bytes().await.map_err(Error::other)?;
     from_bytes(bytes)
 }
 
@@ -265,7 +259,7 @@ pub async fn from_salvo_response(
     res: &mut SalvoResponse,
 ) -> Result<MediaContainerWrapper<MediaContainer>, Error> {
     //let bytes = res.take_body().to_bytes();
-    let bytes = res.take_bytes(None).await.unwrap();
+    let bytes = res.take_bytes(None).await.map_err(Error::other)?;
     from_bytes(bytes)
 }
 
@@ -330,20 +324,25 @@ pub async fn to_string(
 }
 
 // TODO: Merge hub keys when mixed
+/// The strategy is added to the path so paging uses the same strategy as the first page.
 pub fn merge_children_keys(
-    mut key_left: String,
-    mut key_right: String,
+    key_left: String,
+    key_right: String,
+    strategy: InterleaveStrategy,
 ) -> String {
-    key_left = key_left.replace("/hubs/library/collections/", "");
-    key_left = key_left.replace("/library/collections/", "");
-    key_left = key_left.replace("/children", "");
-    key_right = key_right.replace("/hubs/library/collections/", "");
-    key_right = key_right.replace("/library/collections/", "");
-    key_right = key_right.replace("/children", "");
-
-    format!(
+    let collection_ids = |key: String| {
+        let key = key.replace("/hubs/library/collections/", "");
+        let key = key.replace("/library/collections/", "");
+        key.split("/children").next().unwrap_or_default().to_string()
+    };
+
+    let mut key = format!(
         "/library/collections/{},{}/children",
-        key_left,
-        key_right // order is important. As thhis order is used to generated the library collections
-    )
+        collection_ids(key_left),
+        collection_ids(key_right) // order is important. As thhis order is used to generated the library collections
+    );
+    if strategy != InterleaveStrategy::RoundRobin {
+        key = format!("{}/{}", key, strategy);
+    }
+    key
 }
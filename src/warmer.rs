@@ -0,0 +1,215 @@
+use crate::config::Config;
+use crate::models::*;
+use crate::plex_client::{PlexClient, COLLECTION_PAGE_SIZE};
+use futures_util::stream::{self, StreamExt};
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Users not seen for this long are no longer warmed.
+const SEEN_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Most recently seen users warmed per cycle.
+const MAX_USERS: usize = 20;
+
+/// Collections warmed per user and cycle.
+const MAX_COLLECTIONS: usize = 100;
+
+/// Collections warmed at the same time.
+const CONCURRENCY: usize = 4;
+
+struct SeenUser {
+    context: PlexContext,
+    sections: HashSet<i64>,
+    last_seen: Instant,
+}
+
+/// Recently seen users by token. The plex client caches are keyed by token,
+/// so warming with the admin token would not refresh the entries users read.
+#[derive(Default)]
+struct SeenUsers(HashMap<String, SeenUser>);
+
+impl SeenUsers {
+    fn record(&mut self, context: &PlexContext, sections: Vec<i64>) {
+        let Some(token) = context.token.clone() else {
+            return;
+        };
+        let user = self.0.entry(token).or_insert_with(|| SeenUser {
+            context: context.clone(),
+            sections: HashSet::new(),
+            last_seen: Instant::now(),
+        });
+        user.context = context.clone();
+        user.sections.extend(sections);
+        user.last_seen = Instant::now();
+    }
+
+    /// The most recently seen users within the window, forgets the others.
+    fn recent(&mut self, window: Duration, max: usize) -> Vec<(PlexContext, Vec<i64>)> {
+        self.0.retain(|_, user| user.last_seen.elapsed() < window);
+        self.0
+            .values()
+            .sorted_by_key(|user| std::cmp::Reverse(user.last_seen))
+            .take(max)
+            .map(|user| (user.context.clone(), user.sections.iter().copied().sorted().collect()))
+            .collect()
+    }
+}
+
+static SEEN: Lazy<Mutex<SeenUsers>> = Lazy::new(|| Mutex::new(SeenUsers::default()));
+
+/// Remember the user and sections of a hub request, so the warmer refreshes them.
+pub fn record(context: &PlexContext, sections: Vec<i64>) {
+    SEEN.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .record(context, sections);
+}
+
+/// Refresh the collection caches before they expire, every 4/5 of `cache_ttl`,
+/// when `cache_warm` is enabled.
+pub fn spawn() {
+    tokio::spawn(async {
+        loop {
+            let interval = Duration::from_secs((Config::current().cache_ttl * 4 / 5).max(60));
+            tokio::time::sleep(interval).await;
+            if Config::current().cache_warm {
+                warm().await;
+            }
+        }
+    });
+}
+
+async fn warm() {
+    let users = SEEN.lock().unwrap_or_else(|e| e.into_inner()).recent(SEEN_WINDOW, MAX_USERS);
+    tracing::debug!(users = users.len(), "Warming caches");
+    let start = Instant::now();
+    for (context, sections) in users {
+        // plex client calls can panic on upstream errors, contain them per user
+        let result = tokio::spawn(warm_user(context, sections)).await;
+        if let Err(error) = result {
+            tracing::error!(error = %error, "Failed to warm caches");
+        }
+    }
+    tracing::debug!(elapsed = ?start.elapsed(), "Warmed caches");
+}
+
+async fn warm_user(context: PlexContext, sections: Vec<i64>) {
+    let plex_client = match PlexClient::from_context(&context) {
+        Ok(plex_client) => plex_client,
+        Err(error) => {
+            tracing::warn!(error = %error, "Failed to warm user caches");
+            return;
+        }
+    };
+    let mut budget = MAX_COLLECTIONS;
+    for section_id in sections {
+        if budget == 0 {
+            tracing::debug!("Reached the collections to warm per user");
+            break;
+        }
+        let mut collections = match plex_client
+            .clone()
+            .refresh_cached(
+                plex_client.get_section_collections(section_id),
+                format!("sectioncollections:{}", section_id),
+            )
+            .await
+        {
+            Ok(collections) => collections,
+            Err(error) => {
+                tracing::warn!(section = section_id, error = %error, "Failed to warm section collections");
+                continue;
+            }
+        };
+
+        let ids: Vec<i64> = collections
+            .media_container
+            .children()
+            .iter()
+            .filter_map(|c| c.rating_key.as_ref()?.parse().ok())
+            .take(budget)
+            .collect();
+        budget -= ids.len();
+        stream::iter(ids)
+            .for_each_concurrent(CONCURRENCY, |id| warm_collection(plex_client.clone(), id))
+            .await;
+    }
+}
+
+async fn warm_collection(plex_client: PlexClient, id: i64) {
+    let collection = plex_client
+        .clone()
+        .refresh_cached(
+            plex_client.get_collection(id as i32),
+            format!("collection:{}", id),
+        )
+        .await;
+    let children = plex_client
+        .clone()
+        .refresh_cached(
+            plex_client.get_collection_children(id, Some(0), Some(COLLECTION_PAGE_SIZE)),
+            format!("get_collection_children:{}:{}:{}", id, 0, COLLECTION_PAGE_SIZE),
+        )
+        .await;
+    let mut children = match (collection, children) {
+        (Ok(_), Ok(children)) => children,
+        (Err(error), _) | (_, Err(error)) => {
+            tracing::warn!(collection = id, error = %error, "Failed to warm collection");
+            return;
+        }
+    };
+    crate::webhooks::record_collection(id, &children.media_container.children());
+
+    // hero art is loaded with the admin token and cached for a month,
+    // this only loads the missing ones
+    let config = Config::for_context(&plex_client.context);
+    if config.token.is_none() || config.hero_rows.is_none() {
+        return;
+    }
+    for child in children.media_container.children() {
+        let guid = match (&child.guid, &child.parent_guid) {
+            (Some(guid), Some(parent)) if guid.starts_with("plex://episode") => parent,
+            (Some(guid), _) => guid,
+            _ => continue,
+        };
+        if let Some(uuid) = guid.rsplit('/').next() {
+            plex_client.clone().get_hero_art(uuid.to_string()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(token: &str) -> PlexContext {
+        PlexContext {
+            token: Some(token.to_string()),
+            ..PlexContext::default()
+        }
+    }
+
+    #[test]
+    fn test_seen_users() {
+        let mut seen = SeenUsers::default();
+        seen.record(&PlexContext::default(), vec![1]);
+        seen.record(&context("a"), vec![2, 1]);
+        seen.record(&context("b"), vec![3]);
+        seen.record(&context("a"), vec![2]);
+
+        let users = seen.recent(SEEN_WINDOW, 10);
+        let tokens: Vec<_> = users.iter().map(|(c, _)| c.token.clone().unwrap()).collect();
+        assert_eq!(tokens, vec!["a", "b"]);
+        assert_eq!(users[0].1, vec![1, 2]);
+        assert_eq!(seen.recent(SEEN_WINDOW, 1).len(), 1);
+
+        let window = Duration::from_millis(50);
+        std::thread::sleep(window);
+        seen.record(&context("b"), vec![]);
+        let users = seen.recent(window, 10);
+        assert_eq!(users.len(), 1);
+        assert!(!seen.0.contains_key("a"));
+    }
+}
@@ -1,13 +1,161 @@
 // use serde_derive::Deserialize;
 // use serde_derive::Serialize;
+use crate::cache::{GLOBAL_CACHE, RESPONSE_CACHE};
+use crate::models::MetaData;
+use crate::plex_client::invalidate_cache_prefixes;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 
 pub fn watchlist(payload: Payload) {
 
 }
 
+/// Collections seen while loading collection children, so events only
+/// invalidate the collections holding the item.
+static COLLECTIONS: Lazy<CollectionIndex> = Lazy::new(CollectionIndex::default);
+
+#[derive(Default, Debug)]
+pub struct CollectionIndex {
+    /// rating key of a child to the collections holding it
+    items: Mutex<HashMap<String, HashSet<i64>>>,
+    /// section id to its collections
+    sections: Mutex<HashMap<i64, HashSet<i64>>>,
+}
+
+impl CollectionIndex {
+    pub fn record(&self, collection_id: i64, children: &[MetaData]) {
+        let mut items = self.items.lock().unwrap();
+        let mut sections = self.sections.lock().unwrap();
+        for child in children {
+            if let Some(rating_key) = child.rating_key.clone() {
+                items.entry(rating_key).or_default().insert(collection_id);
+            }
+            if let Some(section_id) = child.library_section_id {
+                sections.entry(section_id).or_default().insert(collection_id);
+            }
+        }
+    }
+
+    /// Collections holding one of the rating keys.
+    pub fn collections_of(&self, rating_keys: &[&String]) -> HashSet<i64> {
+        let items = self.items.lock().unwrap();
+        rating_keys
+            .iter()
+            .filter_map(|key| items.get(*key))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    pub fn collections_in(&self, section_id: i64) -> HashSet<i64> {
+        self.sections
+            .lock()
+            .unwrap()
+            .get(&section_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Remember which items and sections belong to the collection.
+pub fn record_collection(collection_id: i64, children: &[MetaData]) {
+    COLLECTIONS.record(collection_id, children);
+}
+
+/// Invalidate the caches affected by the event, so changes show up
+/// without waiting for the cache ttl.
+pub fn handle(payload: Payload) {
+    let prefixes = invalidated_prefixes(&payload, &COLLECTIONS);
+    if prefixes.is_empty() {
+        tracing::debug!(event = %payload.event, "Ignoring webhook event");
+        return;
+    }
+
+    tracing::debug!(event = %payload.event, prefixes = ?prefixes, "Invalidating caches for webhook event");
+    if let Err(error) = invalidate_cache_prefixes(prefixes.clone()) {
+        tracing::error!(error = %error, "Failed to invalidate plex client cache");
+    }
+    if let Err(error) = GLOBAL_CACHE.invalidate_prefixes(prefixes) {
+        tracing::error!(error = %error, "Failed to invalidate global cache");
+    }
+    // responses are cached per url, so no telling which ones hold the new item.
+    // watched state is left to the response ttl.
+    if payload.event == "library.new" {
+        RESPONSE_CACHE.clear();
+    }
+}
+
+/// Cache key prefixes changed by the event. Keys end with the token,
+/// and webhooks dont have one, so entries of all users are invalidated.
+pub fn invalidated_prefixes(payload: &Payload, index: &CollectionIndex) -> Vec<String> {
+    let metadata = &payload.metadata;
+    // episodes and tracks are shown by their show, season, artist or album
+    let rating_keys: Vec<&String> = [
+        &metadata.rating_key,
+        &metadata.parent_rating_key,
+        &metadata.grandparent_rating_key,
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let virtual_hubs = match metadata.library_section_id {
+        // hubs without a section can show items of any section
+        Some(id) => vec![format!("virtualhub:{}:", id), "virtualhub:0:".to_string()],
+        None => vec!["virtualhub:".to_string()],
+    };
+
+    let mut prefixes: Vec<String> = match payload.event.as_str() {
+        "library.new" => {
+            let Some(section_id) = metadata.library_section_id else {
+                return [
+                    "get_collection_children:",
+                    "collectiontotalunwatched:",
+                    "collection:",
+                    "sectioncollections:",
+                    "sectionsize:",
+                    "virtualhub:",
+                ]
+                .iter()
+                .map(|p| p.to_string())
+                .collect();
+            };
+            let mut collections = index.collections_in(section_id);
+            collections.extend(index.collections_of(&rating_keys));
+            let mut prefixes = vec![
+                format!("sectioncollections:{}:", section_id),
+                format!("sectionsize:{}:", section_id),
+            ];
+            for id in sorted(collections) {
+                prefixes.push(format!("collection:{}:", id));
+                prefixes.push(format!("get_collection_children:{}:", id));
+                prefixes.push(format!("collectiontotalunwatched:{}:", id));
+            }
+            prefixes
+        }
+        "media.scrobble" | "media.play" | "media.stop" | "media.rate" => {
+            let mut prefixes = vec![];
+            for id in sorted(index.collections_of(&rating_keys)) {
+                prefixes.push(format!("get_collection_children:{}:", id));
+                prefixes.push(format!("collectiontotalunwatched:{}:", id));
+            }
+            prefixes
+        }
+        _ => return vec![],
+    };
+    prefixes.extend(virtual_hubs);
+    prefixes
+}
+
+fn sorted(ids: HashSet<i64>) -> Vec<i64> {
+    let mut ids: Vec<i64> = ids.into_iter().collect();
+    ids.sort();
+    ids
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Payload {
     pub event: String,
     pub user: bool,
@@ -23,7 +171,7 @@ pub struct Payload {
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Account {
     pub id: i64,
     pub thumb: String,
@@ -31,14 +179,14 @@ pub struct Account {
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Server {
     pub title: String,
     pub uuid: String,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Player {
     pub local: bool,
     pub public_address: String,
@@ -47,32 +195,120 @@ pub struct Player {
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Metadata {
-    pub library_section_type: String,
-    pub rating_key: String,
-    pub key: String,
-    pub parent_rating_key: String,
-    pub grandparent_rating_key: String,
-    pub guid: String,
+    pub library_section_type: Option<String>,
+    pub rating_key: Option<String>,
+    pub key: Option<String>,
+    pub parent_rating_key: Option<String>,
+    pub grandparent_rating_key: Option<String>,
+    pub guid: Option<String>,
     #[serde(rename = "librarySectionID")]
-    pub library_section_id: i64,
+    pub library_section_id: Option<i64>,
     #[serde(rename = "type")]
-    pub type_field: String,
-    pub title: String,
-    pub grandparent_key: String,
-    pub parent_key: String,
+    pub type_field: Option<String>,
+    pub title: Option<String>,
+    pub grandparent_key: Option<String>,
+    pub parent_key: Option<String>,
     pub grandparent_title: Option<String>,
     pub parent_title: Option<String>,
-    pub summary: String,
-    pub index: i64,
-    pub parent_index: i64,
-    pub rating_count: i64,
-    pub thumb: String,
-    pub art: String,
-    pub parent_thumb: String,
-    pub grandparent_thumb: String,
-    pub grandparent_art: String,
-    pub added_at: i64,
-    pub updated_at: i64,
+    pub summary: Option<String>,
+    pub index: Option<i64>,
+    pub parent_index: Option<i64>,
+    pub rating_count: Option<i64>,
+    pub thumb: Option<String>,
+    pub art: Option<String>,
+    pub parent_thumb: Option<String>,
+    pub grandparent_thumb: Option<String>,
+    pub grandparent_art: Option<String>,
+    pub added_at: Option<i64>,
+    pub updated_at: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPISODE: &str = r#"{"event":"media.scrobble","user":true,"owner":true,"Account":{"id":1,"thumb":"","title":"user"},"Server":{"title":"server","uuid":"abc"},"Player":{"local":true,"publicAddress":"1.2.3.4","title":"tv","uuid":"def"},"Metadata":{"librarySectionType":"show","ratingKey":"1003","key":"/library/metadata/1003","parentRatingKey":"1002","grandparentRatingKey":"1001","guid":"plex://episode/1","librarySectionID":2,"type":"episode","title":"Pilot","grandparentTitle":"Show","parentTitle":"Season 1","index":1,"parentIndex":1,"addedAt":1700000000}}"#;
+    const TRACK: &str = r#"{"event":"library.new","user":false,"owner":true,"Account":{"id":1,"title":"user"},"Server":{"title":"server","uuid":"abc"},"Player":{},"Metadata":{"librarySectionType":"artist","ratingKey":"2003","parentRatingKey":"2002","grandparentRatingKey":"2001","librarySectionID":3,"type":"track","title":"Song","grandparentTitle":"Artist","parentTitle":"Album"}}"#;
+
+    fn child(rating_key: &str, section_id: i64) -> MetaData {
+        MetaData {
+            rating_key: Some(rating_key.to_string()),
+            library_section_id: Some(section_id),
+            ..MetaData::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_payloads() {
+        let episode: Payload = serde_json::from_str(EPISODE).unwrap();
+        assert_eq!(episode.event, "media.scrobble");
+        assert_eq!(episode.metadata.type_field.as_deref(), Some("episode"));
+        assert_eq!(episode.metadata.rating_key.as_deref(), Some("1003"));
+        assert_eq!(episode.metadata.grandparent_rating_key.as_deref(), Some("1001"));
+        assert_eq!(episode.metadata.library_section_id, Some(2));
+        assert_eq!(episode.player.public_address, "1.2.3.4");
+
+        let track: Payload = serde_json::from_str(TRACK).unwrap();
+        assert_eq!(track.metadata.type_field.as_deref(), Some("track"));
+        assert_eq!(track.metadata.parent_rating_key.as_deref(), Some("2002"));
+        assert_eq!(track.metadata.grandparent_rating_key.as_deref(), Some("2001"));
+        assert_eq!(track.metadata.library_section_id, Some(3));
+    }
+
+    #[test]
+    fn test_invalidated_prefixes_user_state() {
+        let index = CollectionIndex::default();
+        // the show is in collection 10, collection 11 is unrelated
+        index.record(10, &[child("1001", 2)]);
+        index.record(11, &[child("5000", 2)]);
+
+        let payload: Payload = serde_json::from_str(EPISODE).unwrap();
+        assert_eq!(
+            invalidated_prefixes(&payload, &index),
+            vec![
+                "get_collection_children:10:",
+                "collectiontotalunwatched:10:",
+                "virtualhub:2:",
+                "virtualhub:0:",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalidated_prefixes_library_new() {
+        let index = CollectionIndex::default();
+        index.record(20, &[child("2001", 3)]);
+        index.record(21, &[child("6000", 4)]);
+
+        let payload: Payload = serde_json::from_str(TRACK).unwrap();
+        assert_eq!(
+            invalidated_prefixes(&payload, &index),
+            vec![
+                "sectioncollections:3:",
+                "sectionsize:3:",
+                "collection:20:",
+                "get_collection_children:20:",
+                "collectiontotalunwatched:20:",
+                "virtualhub:3:",
+                "virtualhub:0:",
+            ]
+        );
+
+        // without a section everything could have changed
+        let mut payload = payload;
+        payload.metadata.library_section_id = None;
+        let prefixes = invalidated_prefixes(&payload, &index);
+        assert!(prefixes.contains(&"sectioncollections:".to_string()));
+        assert!(prefixes.contains(&"virtualhub:".to_string()));
+    }
+
+    #[test]
+    fn test_invalidated_prefixes_ignored_events() {
+        let index = CollectionIndex::default();
+        let mut payload: Payload = serde_json::from_str(EPISODE).unwrap();
+        payload.event = "media.pause".to_string();
+        assert!(invalidated_prefixes(&payload, &index).is_empty());
+    }
 }